@@ -3,12 +3,13 @@ use std::net::SocketAddr;
 
 extern crate piston_window;
 
-use laminar::ErrorKind;
+use laminar::{ErrorKind, Socket};
 use piston_window::*;
 use shipyard::*;
 
-use demo::{ClientState, Color, NetworkPacket, Position, Rectangle};
-use netcarrier::{CarrierPacket, transport::{self, update_client, JitBuffer}};
+use demo::{ClientState, Color, NetworkPacket, PlayerPrediction, Position, Rectangle};
+use netcarrier::transport::conditioner::{LinkConditioner, LinkConditions, LinkProfile};
+use netcarrier::transport::{self, apply_prediction, interpolate_client_state, predict_client, ClientConfig};
 
 const SERVER: &str = "127.0.0.1:12351";
 
@@ -16,15 +17,19 @@ const SERVER: &str = "127.0.0.1:12351";
 pub fn init(addr: &str) -> Result<(), ErrorKind> {
     println!("Connected on {}", addr);
     let mut world = World::default();
-    transport::init_client_network::<NetworkPacket>(&mut world, addr, SERVER)?;
     let server: SocketAddr = SERVER.parse().unwrap();
+    // Keys 1 to 4 switch the simulated network
+    let conditioner = LinkConditioner::new(Socket::bind(addr)?, LinkProfile::perfect());
+    world.add_unique(conditioner.conditions());
+    transport::init_client_transport::<NetworkPacket, _>(&mut world, conditioner, server, ClientConfig::default());
+    transport::init_client_prediction::<PlayerPrediction>(&mut world, 64);
     let mut client_state = ClientState::default();
 
     let mut window: PistonWindow = WindowSettings::new("Hello Piston!", [640, 480])
         .exit_on_esc(true)
         .build()
         .unwrap();
-    window.set_max_fps(20);
+    window.set_max_fps(60);
     window.set_ups(20);
     while let Some(event) = window.next() {
         window.draw_2d(&event, |context, graphics, _device| {
@@ -57,6 +62,10 @@ pub fn init(addr: &str) -> Result<(), ErrorKind> {
                 Key::D => client_state.right = true,
                 Key::W => client_state.up = true,
                 Key::S => client_state.down = true,
+                Key::D1 => set_profile(&world, LinkProfile::perfect()),
+                Key::D2 => set_profile(&world, LinkProfile::good()),
+                Key::D3 => set_profile(&world, LinkProfile::average()),
+                Key::D4 => set_profile(&world, LinkProfile::bad()),
                 _ => (),
             }
         };
@@ -69,13 +78,23 @@ pub fn init(addr: &str) -> Result<(), ErrorKind> {
                 _ => (),
             }
         };
-        update_client::<ClientState>(&mut world, client_state.clone(), server.clone());
-        process_events(&world);
+        // Inputs are predicted at the server frame rate
+        if event.update_args().is_some() {
+            predict_client::<NetworkPacket, PlayerPrediction>(&mut world, client_state.clone(), server);
+        }
+        transport::client_keepalive::<NetworkPacket>(&world, server);
+        interpolate_client_state::<NetworkPacket>(&world);
+        apply_prediction::<PlayerPrediction>(&world);
     }
 
     Ok(())
 }
 
+fn set_profile(world: &World, profile: LinkProfile) {
+    println!("Network profile: {:?}", profile);
+    world.run(|conditions: UniqueView<LinkConditions>| conditions.set_profile(profile));
+}
+
 fn main() -> Result<(), laminar::ErrorKind> {
     println!("Starting client..");
 
@@ -90,22 +109,3 @@ fn main() -> Result<(), laminar::ErrorKind> {
 
     init(&addr)
 }
-
-// TODO: this should be on our API side
-fn process_events(world: &World) {
-    let net_state: Option<NetworkPacket>;
-    {
-        let jit_buffer = world.borrow::<UniqueViewMut<JitBuffer<NetworkPacket>>>();
-        let mut jit_buffer = jit_buffer.0.lock().unwrap();
-        println!("JitBuffer: {:?}", jit_buffer.len());
-        // TODO: review how to define the size of the jit buffer (config, dynamic...)
-        if jit_buffer.len() < 3 {
-            return;
-        }
-        net_state = jit_buffer.pop();
-    }
-    if let Some(state) = net_state {
-        println!("NetState: {:?}", state);
-        state.apply_state(&world);
-    }
-}
@@ -6,7 +6,9 @@ use laminar::ErrorKind;
 use shipyard::*;
 
 use demo::{ClientState, Color, NetworkPacket, Position, Rectangle, Velocity};
-use netcarrier::transport::{self, update_server, EventList, NetworkEvent, init_network};
+use netcarrier::priority::PriorityBudget;
+use netcarrier::relevancy::AreaOfInterest;
+use netcarrier::transport::{self, update_server_with, ClientEntities, EventList, NetworkEvent, init_network};
 use netcarrier::{NetworkController, NetworkIdentifier};
 
 const MS_PER_FRAME: u64 = 50;
@@ -18,6 +20,22 @@ pub fn init() -> Result<(), ErrorKind> {
     let mut net_controller = NetworkController::new(40);
     init_network::<NetworkPacket>(&mut world, SERVER)?;
     world.add_unique(ClientMapper::default());
+    // Players only see each other within 250 pixels, the closest ones first
+    let interest = AreaOfInterest::<NetworkPacket, Position>::new(64.0, 250.0, 30.0, |position| [position.x, position.y]);
+    let mut interest = PriorityBudget::new(interest, 1024);
+    interest.add_hook(|context, id| {
+        if context.client.entity == Some(id) {
+            return 100.0;
+        }
+        let own = context.client.entity.and_then(|entity| context.state.positions(entity));
+        match (own, context.state.positions(id)) {
+            (Some(own), Some(position)) => {
+                let distance = ((own.x - position.x).powi(2) + (own.y - position.y).powi(2)).sqrt();
+                (1.0 - distance / 250.0).max(0.0)
+            }
+            _ => 0.0,
+        }
+    });
 
     loop {
         net_controller.tick();
@@ -25,10 +43,13 @@ pub fn init() -> Result<(), ErrorKind> {
 
         let start = time::Instant::now();
         world.run(process_events);
+        process_inputs(&world);
         world.run(system_update_player);
         world.run(system_move);
 
-        update_server::<NetworkPacket>(&mut world, net_controller.frame);
+        if let Err(e) = update_server_with(&mut world, net_controller.frame, &mut interest) {
+            println!("Error updating server: {}", e);
+        }
 
         let now = time::Instant::now();
         let frame_duration = time::Duration::from_millis(MS_PER_FRAME);
@@ -42,8 +63,7 @@ pub fn init() -> Result<(), ErrorKind> {
 
 fn system_move(mut posisitons: ViewMut<Position>, velocities: View<Velocity>) {
     for (pos, vel) in (&mut posisitons, &velocities).iter() {
-        pos.x += vel.dx * 10.0;
-        pos.y += vel.dy * 10.0;
+        pos.step(vel);
         // println!("{:?}", pos);
     }
 }
@@ -56,8 +76,7 @@ fn system_move(mut posisitons: ViewMut<Position>, velocities: View<Velocity>) {
 
 fn system_update_player(clients_state: View<ClientState>, mut velocities: ViewMut<Velocity>) {
     for (state, velocity) in (&clients_state, &mut velocities).iter() {
-        velocity.dx = (state.right as i32 - state.left as i32) as f32;
-        velocity.dy = (state.down as i32 - state.up as i32) as f32;
+        *velocity = Velocity::from_input(state);
     }
 }
 
@@ -67,6 +86,7 @@ fn process_events(mut all_storages: AllStoragesViewMut) {
         let mut entities = all_storages.borrow::<EntitiesViewMut>();
         let event_list = all_storages.borrow::<UniqueViewMut<EventList>>();
         let mut client_mapper = all_storages.borrow::<UniqueViewMut<ClientMapper>>();
+        let mut client_entities = all_storages.borrow::<UniqueViewMut<ClientEntities>>();
         let mut positions = all_storages.borrow::<ViewMut<Position>>();
         let mut colors = all_storages.borrow::<ViewMut<Color>>();
         let mut rectangles = all_storages.borrow::<ViewMut<Rectangle>>();
@@ -86,6 +106,7 @@ fn process_events(mut all_storages: AllStoragesViewMut) {
                     }
 
                     let net_id = NetworkIdentifier::default();
+                    client_entities.0.insert(addr, net_id.id);
                     let entity = entities.add_entity(
                         (
                             &mut positions,
@@ -108,19 +129,12 @@ fn process_events(mut all_storages: AllStoragesViewMut) {
                 }
                 NetworkEvent::Disconnect(addr) => {
                     println!("Client {} disconnected.", addr);
+                    client_entities.0.remove(&addr);
                     if let Some(entity_id) = client_mapper.remove(&addr) {
                         removed_entities.push(entity_id);
                     }
                 }
-                NetworkEvent::Message(addr, bytes) => {
-                    // TODO: Review how to treat client state
-                    if let Ok(decoded) = bincode::deserialize::<ClientState>(&bytes) {
-                        // println!("Received {:?} from {}.", decoded, addr);
-                        if let Some(entity_id) = client_mapper.get(&addr) {
-                            clients_state[*entity_id] = decoded;
-                        }
-                    }
-                }
+                NetworkEvent::Message(..) => {}
             }
         });
     }
@@ -129,6 +143,18 @@ fn process_events(mut all_storages: AllStoragesViewMut) {
     }
 }
 
+// The commands due this frame in tick order, without a new one the last input is kept
+fn process_inputs(world: &World) {
+    let inputs = transport::take_inputs::<ClientState>(world);
+    world.run(|client_mapper: UniqueView<ClientMapper>, mut clients_state: ViewMut<ClientState>| {
+        for (addr, command) in inputs {
+            if let Some(&entity_id) = client_mapper.get(&addr) {
+                clients_state[entity_id] = command.input;
+            }
+        }
+    });
+}
+
 fn main() -> Result<(), laminar::ErrorKind> {
     println!("Starting server..");
     init()
@@ -1,11 +1,17 @@
 use netcarrier::generate_packet;
+use netcarrier::prediction::Predict;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
+use shipyard::{EntityId, ViewMut, World};
 
-use netcarrier::Delta;
+use netcarrier::{Delta, Interpolate, NetSerialize};
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
-pub struct Color(pub [f32; 4]);
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Delta, NetSerialize)]
+pub struct Color(
+    #[delta(full)]
+    #[net(min = 0.0, max = 1.0, precision = 0.004)]
+    pub [f32; 4],
+);
 
 impl Color {
     pub fn random() -> Self {
@@ -38,15 +44,24 @@ impl Default for ClientState {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+// Inputs only move by whole steps
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Delta, NetSerialize)]
 pub struct Velocity {
+    #[delta(quantize = "i8")]
+    #[net(min = -1, max = 1, precision = 1)]
     pub dx: f32,
+    #[delta(quantize = "i8")]
+    #[net(min = -1, max = 1, precision = 1)]
     pub dy: f32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Delta, Interpolate, NetSerialize)]
 pub struct Position {
+    #[delta(quantize = "i8")]
+    #[net(min = -8192, max = 8192, precision = 0.5)]
     pub x: f32,
+    #[delta(quantize = "i8")]
+    #[net(min = -8192, max = 8192, precision = 0.5)]
     pub y: f32,
 }
 
@@ -54,17 +69,34 @@ impl Position {
     pub fn new(x: f32, y: f32) -> Position {
         Position { x, y }
     }
+
+    /// Movement of one server frame, shared by the server and the client prediction
+    pub fn step(&mut self, velocity: &Velocity) {
+        self.x += velocity.dx * 10.0;
+        self.y += velocity.dy * 10.0;
+    }
 }
 
 impl Velocity {
     pub fn new(dx: f32, dy: f32) -> Velocity {
         Velocity { dx, dy }
     }
+
+    pub fn from_input(state: &ClientState) -> Velocity {
+        Velocity {
+            dx: (state.right as i32 - state.left as i32) as f32,
+            dy: (state.down as i32 - state.up as i32) as f32,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Delta, NetSerialize)]
 pub struct Rectangle {
+    #[delta(constant)]
+    #[net(min = 0, max = 1024, precision = 1)]
     pub width: f32,
+    #[delta(constant)]
+    #[net(min = 0, max = 1024, precision = 1)]
     pub height: f32,
 }
 
@@ -75,91 +107,36 @@ impl Rectangle {
 }
 
 generate_packet!(struct Packet {
+    #[interpolate]
     positions: Position,
     velocities: Velocity,
     colors: Color,
     rectangles: Rectangle,
 });
 
-impl Delta for Position {
-    type DeltaType = (i8, i8);
-
-    fn from(&self, other: &Position) -> Option<Self::DeltaType> {
-        let x = try_f32_to_i8(self.x - other.x);
-        let y = try_f32_to_i8(self.y - other.y);
-        if x.is_none() || y.is_none() {
-            return None;
-        }
-        Some((x.unwrap(), y.unwrap()))
-    }
-
-    fn apply(&self, delta: &Self::DeltaType) -> Position {
-        Position {
-            x: self.x - delta.0 as f32,
-            y: self.y - delta.1 as f32,
-        }
-    }
+/// The player's own rectangle, moved by the client before the server confirms it.
+#[derive(Clone, Debug)]
+pub struct PlayerPrediction {
+    pub position: Position,
 }
 
-impl Delta for Velocity {
-    type DeltaType = (i8, i8);
+impl Predict for PlayerPrediction {
+    type State = NetworkPacket;
+    type Input = ClientState;
 
-    fn from(&self, other: &Self) -> Option<Self::DeltaType> {
-        let x = try_f32_to_i8(self.dx - other.dx);
-        let y = try_f32_to_i8(self.dy - other.dy);
-        if x.is_none() || y.is_none() {
-            return None;
-        }
-        Some((x.unwrap(), y.unwrap()))
+    fn from_state(state: &NetworkPacket, net_id: u32) -> Option<Self> {
+        state.positions(net_id).map(|&position| PlayerPrediction { position })
     }
 
-    fn apply(&self, delta: &Self::DeltaType) -> Self {
-        Velocity {
-            dx: self.dx - delta.0 as f32,
-            dy: self.dy - delta.1 as f32,
-        }
+    fn step(&mut self, input: &ClientState) {
+        self.position.step(&Velocity::from_input(input));
     }
-}
-
-impl Delta for Rectangle {
-    type DeltaType = ();
-
-    fn from(&self, other: &Rectangle) -> Option<Self::DeltaType> {
-        if other.width != self.width || other.height != self.height {
-            None
-        } else {
-            Some(())
-        }
-    }
-
-    fn apply(&self, _delta: &Self::DeltaType) -> Rectangle {
-        Rectangle {
-            width: self.width,
-            height: self.height,
-        }
-    }
-}
-
-impl Delta for Color {
-    type DeltaType = ();
-
-    fn from(&self, other: &Color) -> Option<Self::DeltaType> {
-        if self.0 != other.0 {
-            Some(())
-        } else {
-            None
-        }
-    }
-
-    fn apply(&self, _delta: &Self::DeltaType) -> Color {
-        *self
-    }
-}
 
-fn try_f32_to_i8(x: f32) -> Option<i8> {
-    if x < (i8::MAX as f32) && x >= (i8::MIN as f32) {
-        Some(x as i8)
-    } else {
-        None
+    fn apply(&self, world: &World, entity: EntityId) {
+        world.run(|mut positions: ViewMut<Position>| {
+            if positions.contains(entity) {
+                positions[entity] = self.position;
+            }
+        });
     }
 }
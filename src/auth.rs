@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// How long a challenge waits for its answer.
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Permission to join, issued by the matchmaker and signed with the key it shares with the servers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectToken {
+    /// What the matchmaker admitted the client as, its account id for example.
+    pub user_data: Vec<u8>,
+    /// Seconds since the Unix epoch, the servers refuse the token from then on.
+    pub expires_at: u64,
+    signature: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenError {
+    Expired,
+    InvalidSignature,
+    /// Another address joined with the same token.
+    InUse,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Expired => write!(f, "connect token expired"),
+            TokenError::InvalidSignature => write!(f, "connect token not signed by the matchmaker"),
+            TokenError::InUse => write!(f, "connect token used by another client"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl ConnectToken {
+    pub fn new(key: &[u8], user_data: Vec<u8>, expires_at: SystemTime) -> Self {
+        let expires_at = unix_seconds(expires_at);
+        let mut signature = [0; 32];
+        signature.copy_from_slice(&mac(key, expires_at, &user_data).finalize().into_bytes());
+        ConnectToken {
+            user_data,
+            expires_at,
+            signature,
+        }
+    }
+
+    pub fn verify(&self, key: &[u8], now: SystemTime) -> Result<(), TokenError> {
+        if mac(key, self.expires_at, &self.user_data).verify(&self.signature).is_err() {
+            return Err(TokenError::InvalidSignature);
+        }
+        if unix_seconds(now) >= self.expires_at {
+            return Err(TokenError::Expired);
+        }
+        Ok(())
+    }
+}
+
+fn mac(key: &[u8], expires_at: u64, user_data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.update(&expires_at.to_le_bytes());
+    mac.update(user_data);
+    mac
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Decides whether a client with a valid token joins.
+pub trait Authenticator: Send + Sync + 'static {
+    /// User data kept with the client's connection, or why it is rejected, which the client is told.
+    fn authenticate(&self, addr: SocketAddr, token: &ConnectToken) -> Result<Vec<u8>, String>;
+}
+
+impl<F> Authenticator for F
+where
+    F: Fn(SocketAddr, &ConnectToken) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+{
+    fn authenticate(&self, addr: SocketAddr, token: &ConnectToken) -> Result<Vec<u8>, String> {
+        self(addr, token)
+    }
+}
+
+/// Accepts every valid token, with the user data the matchmaker put in it.
+pub struct TokenUserData;
+
+impl Authenticator for TokenUserData {
+    fn authenticate(&self, _addr: SocketAddr, token: &ConnectToken) -> Result<Vec<u8>, String> {
+        Ok(token.user_data.clone())
+    }
+}
+
+struct Challenge {
+    nonce: u64,
+    token: ConnectToken,
+    sent: Instant,
+}
+
+/// Connect tokens required by a server.
+///
+/// A valid token gets a challenge, the client joins once it sends the nonce back from its address
+/// and the [`Authenticator`] accepts it.
+pub struct ServerAuth {
+    key: Vec<u8>,
+    authenticator: Box<dyn Authenticator>,
+    challenges: HashMap<SocketAddr, Challenge>,
+    // Address that joined with each token, until it expires
+    used_tokens: HashMap<[u8; 32], (SocketAddr, u64)>,
+}
+
+impl ServerAuth {
+    pub fn new<A: Authenticator>(key: &[u8], authenticator: A) -> Self {
+        ServerAuth {
+            key: key.to_vec(),
+            authenticator: Box::new(authenticator),
+            challenges: HashMap::new(),
+            used_tokens: HashMap::new(),
+        }
+    }
+
+    /// Nonce `addr` has to send back, if its token is valid.
+    pub fn challenge(&mut self, addr: SocketAddr, token: &ConnectToken, now: SystemTime) -> Result<u64, TokenError> {
+        token.verify(&self.key, now)?;
+        let now = unix_seconds(now);
+        self.used_tokens.retain(|_, &mut (_, expires_at)| expires_at > now);
+        self.check_unused(addr, token)?;
+
+        let sent = Instant::now();
+        self.challenges
+            .retain(|_, challenge| sent.duration_since(challenge.sent) < CHALLENGE_TIMEOUT);
+        let nonce = rand::thread_rng().gen();
+        self.challenges.insert(
+            addr,
+            Challenge {
+                nonce,
+                token: token.clone(),
+                sent,
+            },
+        );
+        Ok(nonce)
+    }
+
+    /// Authenticates `addr` if `nonce` answers its challenge, `None` if it doesn't.
+    pub fn respond(&mut self, addr: SocketAddr, nonce: u64) -> Option<Result<Vec<u8>, String>> {
+        match self.challenges.get(&addr) {
+            Some(challenge) if challenge.nonce == nonce => {}
+            _ => return None,
+        }
+        let token = self.challenges.remove(&addr)?.token;
+        // Two addresses can be challenged with the same token, only the first one joins
+        if let Err(e) = self.check_unused(addr, &token) {
+            return Some(Err(e.to_string()));
+        }
+        let user_data = self.authenticator.authenticate(addr, &token);
+        if user_data.is_ok() {
+            self.used_tokens.insert(token.signature, (addr, token.expires_at));
+        }
+        Some(user_data)
+    }
+
+    fn check_unused(&self, addr: SocketAddr, token: &ConnectToken) -> Result<(), TokenError> {
+        match self.used_tokens.get(&token.signature) {
+            Some(&(used_by, _)) if used_by != addr => Err(TokenError::InUse),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"matchmaker key";
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn verifies_tokens() {
+        let now = SystemTime::now();
+        let token = ConnectToken::new(KEY, vec![42], now + Duration::from_secs(30));
+        assert_eq!(token.verify(KEY, now), Ok(()));
+        assert_eq!(token.verify(b"other key", now), Err(TokenError::InvalidSignature));
+        assert_eq!(token.verify(KEY, now + Duration::from_secs(31)), Err(TokenError::Expired));
+
+        let forged = ConnectToken {
+            user_data: vec![43],
+            ..token
+        };
+        assert_eq!(forged.verify(KEY, now), Err(TokenError::InvalidSignature));
+    }
+
+    #[test]
+    fn challenges_before_authenticating() {
+        let now = SystemTime::now();
+        let mut auth = ServerAuth::new(KEY, TokenUserData);
+        let token = ConnectToken::new(KEY, vec![7], now + Duration::from_secs(30));
+
+        let nonce = auth.challenge(addr(1), &token, now).unwrap();
+        let other = auth.challenge(addr(2), &token, now).unwrap();
+        assert_eq!(auth.respond(addr(1), nonce.wrapping_add(1)), None);
+        assert_eq!(auth.respond(addr(1), nonce), Some(Ok(vec![7])));
+        // Answered once
+        assert_eq!(auth.respond(addr(1), nonce), None);
+
+        assert_eq!(auth.respond(addr(2), other), Some(Err(TokenError::InUse.to_string())));
+        assert_eq!(auth.challenge(addr(2), &token, now), Err(TokenError::InUse));
+        assert!(auth.challenge(addr(1), &token, now).is_ok());
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{CarrierDeltaPacket, CarrierPacket, NetSerialize};
+
+/// Part of a snapshot with the entities whose network id is in `start..end`.
+///
+/// Each chunk can be applied on its own: entities of its range missing from it were removed.
+#[derive(Serialize, Deserialize, NetSerialize, Clone, Debug, PartialEq)]
+pub struct SnapshotChunk<T> {
+    pub index: u16,
+    pub count: u16,
+    pub start: u32,
+    pub end: u32,
+    pub state: T,
+}
+
+impl<T> SnapshotChunk<T> {
+    pub fn contains(&self, id: u32) -> bool {
+        self.start <= id && id < self.end
+    }
+}
+
+/// Splits `state` in chunks of consecutive network ids, each with a `size` under `mtu` unless it
+/// only has one entity. The chunks cover every id, so the first one starts at 0.
+pub fn split_snapshot<T, F>(state: &T, mtu: usize, size: F) -> Vec<SnapshotChunk<T>>
+where
+    T: CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+    F: Fn(&SnapshotChunk<T>) -> usize,
+{
+    let chunk = |ids: &[u32]| SnapshotChunk {
+        index: 0,
+        count: 0,
+        start: ids.first().copied().unwrap_or(0),
+        end: ids.last().map_or(0, |&id| id + 1),
+        state: state.retain_entities(|id| ids.binary_search(&id).is_ok()),
+    };
+    let mut sized: Vec<(u32, u64)> = state.entities_id().iter().copied().zip(state.entity_sizes()).collect();
+    sized.sort();
+    let ids: Vec<u32> = sized.iter().map(|&(id, _)| id).collect();
+
+    // Estimated from the entity sizes first, then halved while still too big
+    let overhead = size(&chunk(&[]));
+    let mut groups: Vec<&[u32]> = vec![];
+    let mut group_start = 0;
+    let mut group_size = overhead;
+    for (i, &(_, entity_size)) in sized.iter().enumerate() {
+        let entity_size = entity_size as usize;
+        if i > group_start && group_size + entity_size > mtu {
+            groups.push(&ids[group_start..i]);
+            group_start = i;
+            group_size = overhead;
+        }
+        group_size += entity_size;
+    }
+    groups.push(&ids[group_start..]);
+
+    let mut chunks = vec![];
+    // Reversed, so the groups are popped in id order
+    groups.reverse();
+    while let Some(group) = groups.pop() {
+        let candidate = chunk(group);
+        if group.len() > 1 && size(&candidate) > mtu {
+            let (first, second) = group.split_at(group.len() / 2);
+            groups.push(second);
+            groups.push(first);
+        } else {
+            chunks.push(candidate);
+        }
+    }
+
+    let count = chunks.len() as u16;
+    let starts: Vec<u32> = chunks.iter().map(|chunk| chunk.start).collect();
+    for (i, chunk) in chunks.iter_mut().enumerate() {
+        chunk.index = i as u16;
+        chunk.count = count;
+        chunk.start = if i == 0 { 0 } else { starts[i] };
+        chunk.end = starts.get(i + 1).copied().unwrap_or(u32::MAX);
+    }
+    chunks
+}
+
+/// State pieced together from the chunks of a frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssembledState<T> {
+    pub state: T,
+    /// Every chunk was received, otherwise the entities of the missing ones come from an older state.
+    pub complete: bool,
+}
+
+struct FrameChunks<T> {
+    chunks: Vec<Option<SnapshotChunk<T>>>,
+}
+
+impl<T> FrameChunks<T>
+where
+    T: CarrierPacket + Clone,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    fn is_complete(&self) -> bool {
+        self.chunks.iter().all(Option::is_some)
+    }
+
+    fn assemble(&self, base: Option<&T>) -> AssembledState<T> {
+        let received: Vec<&SnapshotChunk<T>> = self.chunks.iter().flatten().collect();
+        let mut state = received[0].state.clone();
+        for chunk in &received[1..] {
+            state = state.extend_entities(&chunk.state);
+        }
+        let complete = self.is_complete();
+        if let (false, Some(base)) = (complete, base) {
+            let missing = base.retain_entities(|id| !received.iter().any(|chunk| chunk.contains(id)));
+            state = state.extend_entities(&missing);
+        }
+        AssembledState { state, complete }
+    }
+}
+
+/// Pieces the snapshot chunks back together per frame.
+///
+/// A frame is assembled once all its chunks are received. A frame still missing chunks when one
+/// of a newer frame arrives is assembled as it is, and its late chunks are ignored.
+pub struct ChunkAssembler<T> {
+    frames: BTreeMap<u32, FrameChunks<T>>,
+    // Newest frame assembled, complete or not
+    assembled_frame: Option<u32>,
+    // Newest incomplete state, the base of the next incomplete ones
+    partial: Option<T>,
+}
+
+impl<T> ChunkAssembler<T>
+where
+    T: CarrierPacket + Clone,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    pub fn new() -> Self {
+        ChunkAssembler {
+            frames: BTreeMap::new(),
+            assembled_frame: None,
+            partial: None,
+        }
+    }
+
+    /// Adds a received chunk and returns the frames it finishes, in frame order. Missing entities
+    /// are taken from `base`, the newest complete state, or from the last incomplete one if newer.
+    pub fn push(&mut self, chunk: SnapshotChunk<T>, base: Option<&T>) -> Vec<AssembledState<T>> {
+        let frame = chunk.state.frame();
+        if matches!(self.assembled_frame, Some(assembled) if frame <= assembled) || chunk.index >= chunk.count {
+            return vec![];
+        }
+        let frame_chunks = self.frames.entry(frame).or_insert_with(|| FrameChunks {
+            chunks: (0..chunk.count).map(|_| None).collect(),
+        });
+        if let Some(slot) = frame_chunks.chunks.get_mut(chunk.index as usize) {
+            *slot = Some(chunk);
+        }
+
+        let finished: Vec<u32> = if self.frames[&frame].is_complete() {
+            self.frames.range(..=frame).map(|(&frame, _)| frame).collect()
+        } else {
+            self.frames.range(..frame).map(|(&frame, _)| frame).collect()
+        };
+        let mut assembled = vec![];
+        for frame in finished {
+            let frame_chunks = self.frames.remove(&frame).unwrap();
+            let base = match (&self.partial, base) {
+                (Some(partial), Some(base)) if partial.frame() < base.frame() => Some(base),
+                (Some(partial), _) => Some(partial),
+                (None, base) => base,
+            };
+            let state = frame_chunks.assemble(base);
+            if !state.complete {
+                self.partial = Some(state.state.clone());
+            }
+            self.assembled_frame = Some(frame);
+            assembled.push(state);
+        }
+        assembled
+    }
+
+    /// Frames with chunks still missing.
+    pub fn pending(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.assembled_frame = None;
+        self.partial = None;
+    }
+}
+
+impl<T> Default for ChunkAssembler<T>
+where
+    T: CarrierPacket + Clone,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    fn default() -> Self {
+        ChunkAssembler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_packet, wire, Delta, NetworkIdentifier};
+    use serde::{Deserialize, Serialize};
+    use shipyard::*;
+
+    #[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub struct Position(#[delta(full)] f32);
+
+    generate_packet!(struct State {
+        positions: Position,
+    });
+
+    fn world(count: usize) -> World {
+        let world = World::default();
+        world.run(|mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>| {
+            for i in 0..count {
+                entities.add_entity((&mut net_ids, &mut positions), (NetworkIdentifier::default(), Position(i as f32)));
+            }
+        });
+        world
+    }
+
+    fn size(chunk: &SnapshotChunk<NetworkPacket>) -> usize {
+        wire::to_bytes(chunk).len()
+    }
+
+    fn sorted(state: &NetworkPacket) -> Vec<u32> {
+        let mut ids = state.entities_id().to_vec();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn chunks_under_mtu() {
+        let state = NetworkPacket::new(&world(100), 1);
+        let chunks = split_snapshot(&state, 200, size);
+
+        assert!(chunks.len() > 4);
+        assert!(chunks.iter().all(|chunk| size(chunk) <= 200));
+        assert_eq!(chunks[0].start, 0);
+        assert_eq!(chunks.last().unwrap().end, u32::MAX);
+        for (i, pair) in chunks.windows(2).enumerate() {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert_eq!(pair[0].index as usize, i);
+        }
+        let ids: usize = chunks.iter().map(|chunk| chunk.state.entities_id().len()).sum();
+        assert_eq!(ids, 100);
+    }
+
+    #[test]
+    fn assembles_complete_frame() {
+        let state = NetworkPacket::new(&world(50), 1);
+        let mut chunks = split_snapshot(&state, 150, size);
+        let mut assembler = ChunkAssembler::new();
+        let last = chunks.remove(0);
+        for chunk in chunks {
+            assert_eq!(assembler.push(chunk, None), vec![]);
+        }
+
+        let assembled = assembler.push(last, None);
+        assert_eq!(assembled.len(), 1);
+        assert!(assembled[0].complete);
+        assert_eq!(sorted(&assembled[0].state), sorted(&state));
+        assert_eq!(assembled[0].state.positions(state.entities_id()[10]), state.positions(state.entities_id()[10]));
+        assert_eq!(assembler.pending(), 0);
+    }
+
+    #[test]
+    fn lost_chunk_keeps_older_entities() {
+        let world = world(50);
+        let base = NetworkPacket::new(&world, 1);
+        world.run(|mut positions: ViewMut<Position>| {
+            for position in (&mut positions).iter() {
+                position.0 += 100.0;
+            }
+        });
+        let state = NetworkPacket::new(&world, 2);
+        let mut chunks = split_snapshot(&state, 150, size);
+        let lost = chunks.remove(1);
+        let mut assembler = ChunkAssembler::new();
+        for chunk in chunks {
+            assert_eq!(assembler.push(chunk, Some(&base)), vec![]);
+        }
+
+        // Given up once the next frame starts arriving
+        let next = split_snapshot(&NetworkPacket::new(&world, 3), 150, size);
+        let assembled = assembler.push(next[0].clone(), Some(&base));
+        assert_eq!(assembled.len(), 1);
+        assert!(!assembled[0].complete);
+        let partial = &assembled[0].state;
+        assert_eq!(sorted(partial), sorted(&state));
+        for &id in state.entities_id() {
+            let expected = if lost.contains(id) { base.positions(id) } else { state.positions(id) };
+            assert_eq!(partial.positions(id), expected);
+        }
+        assert_eq!(assembler.push(lost, Some(&base)), vec![]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::iter;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InputCommand<I> {
+    pub tick: u32,
+    pub input: I,
+}
+
+/// Commands the client resends until the server acknowledges them.
+pub struct CommandQueue<I> {
+    commands: VecDeque<InputCommand<I>>,
+    capacity: usize,
+}
+
+impl<I: Clone> CommandQueue<I> {
+    pub fn new(capacity: usize) -> Self {
+        CommandQueue {
+            commands: VecDeque::with_capacity(capacity + 1),
+            capacity,
+        }
+    }
+
+    /// Queues the command of `tick`, ticks not newer than the last one are ignored.
+    pub fn push(&mut self, tick: u32, input: I) {
+        if matches!(self.newest_tick(), Some(newest) if tick <= newest) {
+            return;
+        }
+        self.commands.push_back(InputCommand { tick, input });
+        // Too old to be useful, the server moved on without it
+        if self.commands.len() > self.capacity {
+            self.commands.pop_front();
+        }
+    }
+
+    /// Drops the commands the server processed.
+    pub fn ack(&mut self, tick: u32) {
+        while matches!(self.commands.front(), Some(command) if command.tick <= tick) {
+            self.commands.pop_front();
+        }
+    }
+
+    /// The newest `count` unacknowledged commands in tick order.
+    pub fn unacked(&self, count: usize) -> Vec<InputCommand<I>> {
+        let skipped = self.commands.len().saturating_sub(count);
+        self.commands.iter().skip(skipped).cloned().collect()
+    }
+
+    pub fn newest_tick(&self) -> Option<u32> {
+        self.commands.back().map(|command| command.tick)
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+}
+
+/// Commands received from a client, processed in tick order.
+pub struct InputBuffer<I> {
+    commands: BTreeMap<u32, I>,
+    capacity: usize,
+    // Commands left waiting after a pop, each one delays the client's inputs by a tick
+    target_depth: usize,
+    last_processed: Option<u32>,
+}
+
+impl<I> InputBuffer<I> {
+    pub fn new(capacity: usize, target_depth: usize) -> Self {
+        InputBuffer {
+            commands: BTreeMap::new(),
+            capacity,
+            target_depth,
+            last_processed: None,
+        }
+    }
+
+    /// Buffers a received command, false if it was a duplicate, already processed or the buffer
+    /// is full. The client keeps resending the commands that were not buffered.
+    pub fn receive(&mut self, command: InputCommand<I>) -> bool {
+        if matches!(self.last_processed, Some(processed) if command.tick <= processed)
+            || self.commands.contains_key(&command.tick)
+            || self.commands.len() >= self.capacity
+        {
+            return false;
+        }
+        self.commands.insert(command.tick, command.input);
+        true
+    }
+
+    /// Commands to process now in tick order: the oldest one, with the ones that would leave more
+    /// than the target depth waiting, so a burst doesn't delay the next commands for good.
+    pub fn take_due(&mut self) -> Vec<InputCommand<I>> {
+        let due = self.commands.len().saturating_sub(self.target_depth).max(1);
+        iter::from_fn(|| self.pop()).take(due).collect()
+    }
+
+    /// Oldest buffered command, it is processed from now on.
+    pub fn pop(&mut self) -> Option<InputCommand<I>> {
+        let tick = *self.commands.keys().next()?;
+        let input = self.commands.remove(&tick)?;
+        self.last_processed = Some(tick);
+        Some(InputCommand { tick, input })
+    }
+
+    /// Tick of the newest processed command, reported back to the client.
+    pub fn last_processed(&self) -> Option<u32> {
+        self.last_processed
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(tick: u32) -> InputCommand<u32> {
+        InputCommand { tick, input: tick * 10 }
+    }
+
+    #[test]
+    fn queue_resends_until_acked() {
+        let mut queue = CommandQueue::new(3);
+        queue.push(1, 10);
+        queue.push(2, 20);
+        queue.push(2, 25);
+        assert_eq!(queue.unacked(8), vec![command(1), command(2)]);
+
+        queue.ack(1);
+        queue.push(3, 30);
+        queue.push(4, 40);
+        queue.push(5, 50);
+        // Only the newest commands are kept
+        assert_eq!(queue.unacked(8), vec![command(3), command(4), command(5)]);
+        assert_eq!(queue.unacked(2), vec![command(4), command(5)]);
+        queue.ack(5);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn buffer_in_tick_order() {
+        let mut buffer = InputBuffer::new(8, 8);
+        assert!(buffer.receive(command(2)));
+        assert!(buffer.receive(command(1)));
+        assert!(!buffer.receive(command(2)));
+
+        assert_eq!(buffer.pop(), Some(command(1)));
+        assert_eq!(buffer.pop(), Some(command(2)));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.last_processed(), Some(2));
+
+        // Resent commands are stale once processed
+        assert!(!buffer.receive(command(1)));
+        assert!(buffer.receive(command(3)));
+    }
+
+    #[test]
+    fn buffer_full() {
+        let mut buffer = InputBuffer::new(2, 2);
+        assert!(buffer.receive(command(1)));
+        assert!(buffer.receive(command(2)));
+        assert!(!buffer.receive(command(3)));
+        buffer.pop();
+        assert!(buffer.receive(command(3)));
+        assert_eq!(buffer.len(), 2);
+    }
+
+    #[test]
+    fn buffer_recovers_latency() {
+        let mut buffer = InputBuffer::new(32, 2);
+        // A burst after the link stalled
+        for tick in 1..=10 {
+            assert!(buffer.receive(command(tick)));
+        }
+        // Caught up in one tick, none of the commands is lost
+        assert_eq!(buffer.take_due(), (1..=8).map(command).collect::<Vec<_>>());
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.last_processed(), Some(8));
+        assert!(!buffer.receive(command(5)));
+
+        // Back to one command a tick, they wait no more than the target depth
+        for tick in 11..=20 {
+            assert!(buffer.receive(command(tick)));
+            assert_eq!(buffer.take_due(), vec![command(tick - 2)]);
+            assert_eq!(buffer.len(), 2);
+        }
+        buffer.take_due();
+        buffer.take_due();
+        assert!(buffer.take_due().is_empty());
+    }
+}
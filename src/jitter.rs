@@ -0,0 +1,421 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{CarrierDeltaPacket, CarrierPacket};
+
+// Shrink the delay at most once in this interval, growing is immediate
+const SHRINK_INTERVAL: Duration = Duration::from_secs(1);
+// States arriving ahead of the delay for this long move the playout sooner
+const REANCHOR_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JitterDelay {
+    Ticks(u32),
+    Millis(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct JitterBufferConfig {
+    /// Time between two server frames.
+    pub tick_rate: Duration,
+    /// How long a state waits in the buffer before being released.
+    pub delay: JitterDelay,
+    /// Adjust the delay from the measured jitter and underruns, between min and max ticks.
+    pub adaptive: bool,
+    pub min_delay_ticks: u32,
+    pub max_delay_ticks: u32,
+    /// Maximum buffered states, the oldest is dropped when exceeded.
+    pub capacity: usize,
+}
+
+impl Default for JitterBufferConfig {
+    fn default() -> Self {
+        JitterBufferConfig {
+            tick_rate: Duration::from_millis(50),
+            delay: JitterDelay::Ticks(3),
+            adaptive: true,
+            min_delay_ticks: 1,
+            max_delay_ticks: 10,
+            capacity: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JitterStats {
+    /// A state was due but the buffer didn't have it yet.
+    pub underruns: u64,
+    /// States dropped because the buffer was full.
+    pub overruns: u64,
+    /// States dropped because a newer one was already released.
+    pub late: u64,
+    /// Measured interarrival jitter.
+    pub jitter: Duration,
+    /// Current delay applied to the states.
+    pub delay: Duration,
+}
+
+/// Holds the received states and releases them in frame order at the server tick rate.
+pub struct JitterBuffer<T> {
+    states: VecDeque<T>,
+    config: JitterBufferConfig,
+    delay_ticks: u32,
+    // Time the first received frame is due, and that frame
+    playout: Option<(Instant, u32)>,
+    // Since when the states arrive ahead of the delay, and the smallest lead since
+    ahead: Option<(Instant, Duration)>,
+    last_arrival: Option<(Instant, u32)>,
+    last_released: Option<u32>,
+    last_underrun: Option<u32>,
+    last_shrink: Option<Instant>,
+    jitter: f64,
+    stats: JitterStats,
+}
+
+impl<T> JitterBuffer<T>
+where
+    T: CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    pub fn new(config: JitterBufferConfig) -> Self {
+        let delay_ticks = match config.delay {
+            JitterDelay::Ticks(ticks) => ticks,
+            JitterDelay::Millis(ms) => {
+                let tick_ms = config.tick_rate.as_secs_f64() * 1000.0;
+                (ms as f64 / tick_ms).ceil() as u32
+            }
+        };
+        let delay_ticks = delay_ticks.max(config.min_delay_ticks).min(config.max_delay_ticks);
+        JitterBuffer {
+            states: VecDeque::with_capacity(config.capacity + 1),
+            config,
+            delay_ticks,
+            playout: None,
+            ahead: None,
+            last_arrival: None,
+            last_released: None,
+            last_underrun: None,
+            last_shrink: None,
+            jitter: 0.0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    /// Buffers a state received at `arrival`.
+    pub fn push(&mut self, state: T, arrival: Instant) {
+        let frame = state.frame();
+        if matches!(self.last_released, Some(released) if frame <= released) {
+            self.stats.late += 1;
+            return;
+        }
+        self.measure_jitter(frame, arrival);
+        match self.playout {
+            None => self.playout = Some((arrival + self.delay(), frame)),
+            Some(_) => self.reanchor(frame, arrival),
+        }
+
+        match self.states.iter().position(|s| s.frame() >= frame) {
+            Some(i) if self.states[i].frame() == frame => return,
+            Some(i) => self.states.insert(i, state),
+            None => self.states.push_back(state),
+        }
+        if self.states.len() > self.config.capacity {
+            self.states.pop_front();
+            self.stats.overruns += 1;
+        }
+    }
+
+    /// Next state in frame order if it is due at `now`.
+    /// Missing frames are skipped once a newer state is due.
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        let due_frame = self.due_frame(now)?;
+        if !matches!(self.states.front(), Some(state) if state.frame() <= due_frame) {
+            let expected = self.last_released.map_or(due_frame, |released| released + 1);
+            if expected <= due_frame && self.last_underrun != Some(due_frame) {
+                self.last_underrun = Some(due_frame);
+                self.stats.underruns += 1;
+                if self.config.adaptive && self.delay_ticks < self.config.max_delay_ticks {
+                    self.set_delay_ticks(self.delay_ticks + 1);
+                }
+            }
+            return None;
+        }
+
+        if self.config.adaptive {
+            self.adapt(now);
+        }
+        let state = self.states.pop_front()?;
+        self.last_released = Some(state.frame());
+        Some(state)
+    }
+
+    /// Next state in frame order, due or not.
+    pub fn peek(&self) -> Option<&T> {
+        self.states.front()
+    }
+
+    /// Frame being played at `now`, with the fraction of the tick elapsed since it was due.
+    pub fn playout_frame(&self, now: Instant) -> Option<f64> {
+        let (start, start_frame) = self.playout?;
+        let elapsed = now.checked_duration_since(start)?;
+        Some(start_frame as f64 + elapsed.as_secs_f64() / self.config.tick_rate.as_secs_f64())
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats {
+            jitter: Duration::from_secs_f64(self.jitter),
+            delay: self.delay(),
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.config.tick_rate * self.delay_ticks
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Drops the buffered states and restarts the playout, the stats are kept.
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.playout = None;
+        self.ahead = None;
+        self.last_arrival = None;
+        self.last_released = None;
+        self.last_underrun = None;
+    }
+
+    fn due_frame(&self, now: Instant) -> Option<u32> {
+        self.playout_frame(now).map(|frame| frame as u32)
+    }
+
+    // Interarrival jitter estimate from RFC 3550
+    fn measure_jitter(&mut self, frame: u32, arrival: Instant) {
+        if let Some((last_arrival, last_frame)) = self.last_arrival {
+            let tick = self.config.tick_rate.as_secs_f64();
+            let arrival_diff = if arrival >= last_arrival {
+                (arrival - last_arrival).as_secs_f64()
+            } else {
+                -(last_arrival - arrival).as_secs_f64()
+            };
+            let sent_diff = (frame as f64 - last_frame as f64) * tick;
+            self.jitter += ((arrival_diff - sent_diff).abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((arrival, frame));
+    }
+
+    // The first state could have been late, the playout would then keep the next ones waiting
+    // longer than the delay
+    fn reanchor(&mut self, frame: u32, arrival: Instant) {
+        let (start, start_frame) = match self.playout {
+            Some(playout) => playout,
+            None => return,
+        };
+        let tick = self.config.tick_rate;
+        let due = if frame >= start_frame {
+            Some(start + tick * (frame - start_frame))
+        } else {
+            start.checked_sub(tick * (start_frame - frame))
+        };
+        let lead = due.and_then(|due| due.checked_duration_since(arrival + self.delay()));
+        match lead {
+            Some(lead) if lead > Duration::from_millis(0) => {
+                let (since, min_lead) = self
+                    .ahead
+                    .map_or((arrival, lead), |(since, min_lead)| (since, min_lead.min(lead)));
+                if arrival.saturating_duration_since(since) >= REANCHOR_INTERVAL {
+                    self.playout = Some((start - min_lead, start_frame));
+                    self.ahead = None;
+                } else {
+                    self.ahead = Some((since, min_lead));
+                }
+            }
+            _ => self.ahead = None,
+        }
+    }
+
+    fn adapt(&mut self, now: Instant) {
+        let tick = self.config.tick_rate.as_secs_f64();
+        let desired = ((2.0 * self.jitter / tick).ceil() as u32 + 1)
+            .max(self.config.min_delay_ticks)
+            .min(self.config.max_delay_ticks);
+        if desired > self.delay_ticks {
+            self.set_delay_ticks(self.delay_ticks + 1);
+        } else if desired < self.delay_ticks
+            && !matches!(self.last_shrink, Some(last) if now.duration_since(last) < SHRINK_INTERVAL)
+        {
+            self.last_shrink = Some(now);
+            self.set_delay_ticks(self.delay_ticks - 1);
+        }
+    }
+
+    // Moves the playout clock so the following states are released later or sooner
+    fn set_delay_ticks(&mut self, delay_ticks: u32) {
+        if let Some((start, frame)) = self.playout {
+            let start = if delay_ticks > self.delay_ticks {
+                start + self.config.tick_rate * (delay_ticks - self.delay_ticks)
+            } else {
+                start - self.config.tick_rate * (self.delay_ticks - delay_ticks)
+            };
+            self.playout = Some((start, frame));
+        }
+        self.delay_ticks = delay_ticks;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_packet, Delta, NetSerialize};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub struct Position(#[delta(full)] f32);
+
+    generate_packet!(struct State {
+        positions: Position,
+    });
+
+    const TICK: Duration = Duration::from_millis(50);
+
+    fn state(frame: u32) -> NetworkPacket {
+        let world = World::default();
+        NetworkPacket::new(&world, frame)
+    }
+
+    fn buffer(delay: JitterDelay, adaptive: bool) -> JitterBuffer<NetworkPacket> {
+        JitterBuffer::new(JitterBufferConfig {
+            tick_rate: TICK,
+            delay,
+            adaptive,
+            ..JitterBufferConfig::default()
+        })
+    }
+
+    fn released(buffer: &mut JitterBuffer<NetworkPacket>, now: Instant) -> Vec<u32> {
+        let mut frames = vec![];
+        while let Some(state) = buffer.pop(now) {
+            frames.push(state.frame());
+        }
+        frames
+    }
+
+    #[test]
+    fn releases_in_frame_order_after_delay() {
+        let start = Instant::now();
+        let mut buffer = buffer(JitterDelay::Ticks(2), false);
+        buffer.push(state(2), start);
+        buffer.push(state(1), start);
+        buffer.push(state(3), start + TICK);
+
+        assert!(released(&mut buffer, start + TICK).is_empty());
+        assert_eq!(released(&mut buffer, start + TICK * 2), vec![1, 2]);
+        assert_eq!(released(&mut buffer, start + TICK * 3), vec![3]);
+    }
+
+    #[test]
+    fn playout_frame_between_states() {
+        let start = Instant::now();
+        let mut buffer = buffer(JitterDelay::Ticks(1), false);
+        buffer.push(state(1), start);
+        buffer.push(state(2), start);
+        assert_eq!(buffer.playout_frame(start), None);
+
+        assert_eq!(released(&mut buffer, start + TICK + TICK / 2), vec![1]);
+        assert_eq!(buffer.playout_frame(start + TICK + TICK / 2), Some(1.5));
+        assert_eq!(buffer.peek().map(|state| state.frame()), Some(2));
+    }
+
+    #[test]
+    fn delay_in_millis() {
+        let buffer = buffer(JitterDelay::Millis(120), false);
+        assert_eq!(buffer.delay(), TICK * 3);
+    }
+
+    #[test]
+    fn late_and_duplicated_states() {
+        let start = Instant::now();
+        let mut buffer = buffer(JitterDelay::Ticks(1), false);
+        buffer.push(state(1), start);
+        buffer.push(state(2), start);
+        buffer.push(state(2), start);
+        assert_eq!(released(&mut buffer, start + TICK * 2), vec![1, 2]);
+
+        buffer.push(state(1), start + TICK * 2);
+        assert_eq!(buffer.stats().late, 1);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn underrun_grows_delay() {
+        let start = Instant::now();
+        let mut buffer = buffer(JitterDelay::Ticks(1), true);
+        buffer.push(state(1), start);
+        assert_eq!(released(&mut buffer, start + TICK), vec![1]);
+
+        assert!(released(&mut buffer, start + TICK * 2).is_empty());
+        let stats = buffer.stats();
+        assert_eq!(stats.underruns, 1);
+        assert_eq!(stats.delay, TICK * 2);
+
+        // Frame 2 is now due one tick later
+        buffer.push(state(2), start + TICK * 2);
+        assert!(released(&mut buffer, start + TICK * 2).is_empty());
+        assert_eq!(released(&mut buffer, start + TICK * 3), vec![2]);
+        assert_eq!(buffer.stats().underruns, 1);
+    }
+
+    #[test]
+    fn jitter_grows_delay() {
+        let start = Instant::now();
+        let mut buffer = buffer(JitterDelay::Ticks(1), true);
+        // States arrive in bursts of two every two ticks
+        for frame in 1..40 {
+            let arrival = start + TICK * (frame + frame % 2);
+            buffer.push(state(frame), arrival);
+            released(&mut buffer, arrival);
+        }
+        let stats = buffer.stats();
+        assert!(stats.jitter > Duration::from_millis(20));
+        assert!(stats.delay > TICK);
+    }
+
+    #[test]
+    fn late_first_state_reanchors() {
+        let start = Instant::now();
+        let mut buffer = buffer(JitterDelay::Ticks(2), false);
+        // Frame 1 is 4 ticks late, the next ones are on time
+        buffer.push(state(1), start + TICK * 5);
+        for frame in 2..=30 {
+            let arrival = start + TICK * frame;
+            buffer.push(state(frame), arrival);
+            released(&mut buffer, arrival);
+        }
+        // Released after the delay, not after the late first state
+        let arrival = start + TICK * 31;
+        buffer.push(state(31), arrival);
+        assert_eq!(released(&mut buffer, arrival + TICK * 2).last(), Some(&31));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn overrun_drops_oldest() {
+        let start = Instant::now();
+        let mut buffer = JitterBuffer::new(JitterBufferConfig {
+            capacity: 2,
+            adaptive: false,
+            ..JitterBufferConfig::default()
+        });
+        for frame in 1..=3 {
+            buffer.push(state(frame), start);
+        }
+        assert_eq!(buffer.stats().overruns, 1);
+        assert_eq!(released(&mut buffer, start + Duration::from_secs(1)), vec![2, 3]);
+    }
+}
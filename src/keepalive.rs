@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Time of the heartbeats and the idle timeouts.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves with [`advance`](ManualClock::advance), for tests.
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<Instant>>);
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeepaliveConfig {
+    /// A heartbeat is sent to a peer that was sent nothing for this long.
+    pub heartbeat_interval: Duration,
+    /// A peer that sent nothing for this long is disconnected.
+    pub idle_timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            heartbeat_interval: Duration::from_secs(1),
+            idle_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Traffic {
+    last_received: Instant,
+    last_sent: Instant,
+}
+
+/// When each connected peer was last sent and received something.
+pub struct Keepalive {
+    clock: Arc<dyn Clock>,
+    peers: HashMap<SocketAddr, Traffic>,
+}
+
+impl Keepalive {
+    pub fn new<C: Clock>(clock: C) -> Self {
+        Keepalive {
+            clock: Arc::new(clock),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// The tracked peers start over from the time of `clock`.
+    pub fn set_clock<C: Clock>(&mut self, clock: C) {
+        self.clock = Arc::new(clock);
+        let now = self.clock.now();
+        for traffic in self.peers.values_mut() {
+            *traffic = Traffic {
+                last_received: now,
+                last_sent: now,
+            };
+        }
+    }
+
+    /// Starts tracking `addr` as if it just sent and was sent something.
+    pub fn track(&mut self, addr: SocketAddr) {
+        let now = self.clock.now();
+        self.peers.insert(
+            addr,
+            Traffic {
+                last_received: now,
+                last_sent: now,
+            },
+        );
+    }
+
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.peers.remove(&addr);
+    }
+
+    /// Untracked addresses are ignored.
+    pub fn received(&mut self, addr: SocketAddr) {
+        let now = self.clock.now();
+        if let Some(traffic) = self.peers.get_mut(&addr) {
+            traffic.last_received = now;
+        }
+    }
+
+    pub fn sent(&mut self, addr: SocketAddr) {
+        let now = self.clock.now();
+        if let Some(traffic) = self.peers.get_mut(&addr) {
+            traffic.last_sent = now;
+        }
+    }
+
+    /// Peers sent nothing for the heartbeat interval.
+    pub fn heartbeats_due(&self, config: &KeepaliveConfig) -> Vec<SocketAddr> {
+        let now = self.clock.now();
+        self.peers
+            .iter()
+            .filter(|(_, traffic)| now.duration_since(traffic.last_sent) >= config.heartbeat_interval)
+            .map(|(&addr, _)| addr)
+            .collect()
+    }
+
+    /// Peers silent for the idle timeout, they are no longer tracked.
+    pub fn take_timed_out(&mut self, config: &KeepaliveConfig) -> Vec<SocketAddr> {
+        let now = self.clock.now();
+        let timed_out: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, traffic)| now.duration_since(traffic.last_received) >= config.idle_timeout)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in &timed_out {
+            self.peers.remove(addr);
+        }
+        timed_out
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive::new(SystemClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeats_and_timeouts() {
+        let clock = ManualClock::new();
+        let config = KeepaliveConfig::default();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut keepalive = Keepalive::new(clock.clone());
+        keepalive.track(peer);
+
+        clock.advance(Duration::from_millis(900));
+        assert!(keepalive.heartbeats_due(&config).is_empty());
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(keepalive.heartbeats_due(&config), vec![peer]);
+        keepalive.sent(peer);
+        assert!(keepalive.heartbeats_due(&config).is_empty());
+
+        clock.advance(Duration::from_secs(8));
+        keepalive.received(peer);
+        clock.advance(Duration::from_secs(9));
+        assert!(keepalive.take_timed_out(&config).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(keepalive.take_timed_out(&config), vec![peer]);
+        // Forgotten once timed out
+        keepalive.received(peer);
+        assert!(keepalive.take_timed_out(&config).is_empty());
+    }
+}
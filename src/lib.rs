@@ -1,13 +1,29 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU32, Ordering};
 
 use bit_vec::BitVec;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use shipyard::*;
 
+// Lets the generated packets refer to ::netcarrier from inside this crate's tests
+extern crate self as netcarrier;
+
+pub mod auth;
+pub mod fragment;
+pub mod input;
+pub mod jitter;
+pub mod keepalive;
+pub mod prediction;
+pub mod priority;
+pub mod relevancy;
 pub mod transport;
+pub mod wire;
 
-pub use proc_macros::generate_packet;
+pub use proc_macros::{generate_packet, Delta, Interpolate, NetSerialize};
+pub use wire::NetSerialize;
 
 #[doc(hidden)]
 pub use ::serde;
@@ -23,15 +39,39 @@ pub struct NetworkIdentifier {
 
 pub trait CarrierPacket
 where
-	Self: Serialize + DeserializeOwned + Delta,
+	Self: Serialize + DeserializeOwned + NetSerialize + Delta,
 	Self::DeltaType: CarrierDeltaPacket
 {
+	/// Hash of the packet's fields, their types and quantization, see [`transport::Handshake`].
+	const SCHEMA_HASH: u64;
+
 	fn frame(&self) -> u32;
+	/// Captures the replicated entities of `world`, in id order like the states rebuilt from deltas.
 	fn new(world: &World, frame: u32) -> Self;
 	fn apply_state(&self, world: &World);
+	/// Blends the components marked with `#[interpolate]` towards `next`, `t` going from 0 to 1.
+	/// The entities and the other components are the ones of `self`.
+	fn interpolate_state(&self, next: &Self, t: f32) -> Self;
+	/// State with only the entities `keep` returns true for, the others are removed on the
+	/// client that applies it.
+	fn retain_entities<F: FnMut(u32) -> bool>(&self, keep: F) -> Self;
+	/// State where the entities `stale` returns true for keep their components from `previous`,
+	/// stale entities that `previous` doesn't have are left out.
+	fn with_stale_entities<F: FnMut(u32) -> bool>(&self, previous: &Self, stale: F) -> Self;
+	/// Entities and components of `self`, with the entities of `other` that `self` doesn't have, in
+	/// id order.
+	fn extend_entities(&self, other: &Self) -> Self;
+	fn entities_id(&self) -> &[u32];
+	/// Size of the entity's id and components on the wire, before delta compression.
+	fn entity_size(&self, id: u32) -> u64;
+	/// [`entity_size`](CarrierPacket::entity_size) of every entity in `entities_id`, in the same order.
+	fn entity_sizes(&self) -> Vec<u64>;
+	/// Checks that `delta` can be applied to `self`, it comes from the network and `apply`
+	/// expects the masks and entities the server builds.
+	fn validate_delta(&self, delta: &Self::DeltaType) -> Result<(), DeltaError>;
 }
 
-pub trait CarrierDeltaPacket: Serialize + DeserializeOwned {
+pub trait CarrierDeltaPacket: Serialize + DeserializeOwned + NetSerialize {
 	fn frame(&self) -> u32;
 	fn snapshot_frame(&self) -> u32;
 }
@@ -53,17 +93,139 @@ pub trait NetworkDeltaState {
     fn snapshot_frame(&self) -> u32;
 }
 
-// TODO: make from return Result, sometimes we can't retrieve an delta
+/// Result of comparing a value against its baseline.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaOutcome<D> {
+    /// Nothing changed, the value doesn't need to be sent.
+    Unchanged,
+    /// The baseline can be turned into the current value with this delta.
+    Delta(D),
+    /// The change can't be expressed as a delta, the full value has to be sent.
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeltaError {
+    /// A bitmask doesn't have one bit for each entity id.
+    MaskLength { expected: usize, found: usize },
+    Custom(String),
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeltaError::MaskLength { expected, found } => {
+                write!(f, "bitmask has {} entities, expected {}", found, expected)
+            }
+            DeltaError::Custom(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// Difference between two versions of a value.
+///
+/// `current.from(&baseline)` returns the delta that `baseline.apply(&delta)` turns back into
+/// `current`, see [`DeltaOutcome`] for when nothing or the full value has to be sent instead.
+/// It can be derived with `#[derive(Delta)]`, see the `proc_macros` crate for the field attributes.
 pub trait Delta {
     type DeltaType: Serialize + DeserializeOwned;
 
-    fn from(&self, other: &Self) -> Option<Self::DeltaType>;
-    fn apply(&self, other: &Self::DeltaType) -> Self;
+    fn from(&self, baseline: &Self) -> Result<DeltaOutcome<Self::DeltaType>, DeltaError>;
+    fn apply(&self, delta: &Self::DeltaType) -> Self;
+}
+
+/// Blends two values, `t` is 0 for `self` and 1 for `next`.
+///
+/// It can be derived with `#[derive(Interpolate)]`, fields marked `#[interpolate(snap)]` keep
+/// the value of `self`.
+pub trait Interpolate {
+    fn interpolate(&self, next: &Self, t: f32) -> Self;
+}
+
+macro_rules! impl_interpolate {
+    ($($ty:ty),*) => {
+        $(
+            impl Interpolate for $ty {
+                fn interpolate(&self, next: &Self, t: f32) -> Self {
+                    self + (next - self) * t as $ty
+                }
+            }
+        )*
+    };
+}
+
+impl_interpolate!(f32, f64);
+
+macro_rules! impl_interpolate_array {
+    ($($len:expr),*) => {
+        $(
+            impl<T: Interpolate + Copy> Interpolate for [T; $len] {
+                fn interpolate(&self, next: &Self, t: f32) -> Self {
+                    let mut value = *self;
+                    for (value, next) in value.iter_mut().zip(next.iter()) {
+                        *value = value.interpolate(next, t);
+                    }
+                    value
+                }
+            }
+        )*
+    };
+}
+
+impl_interpolate_array!(2, 3, 4);
+
+/// Rounds a value to the nearest integer of type `Q`, `None` if it does not fit.
+#[doc(hidden)]
+pub fn quantize<Q: TryFrom<i64>>(value: f64) -> Option<Q> {
+    let rounded = value.round();
+    if !rounded.is_finite() || rounded < i64::MIN as f64 || rounded > i64::MAX as f64 {
+        return None;
+    }
+    Q::try_from(rounded as i64).ok()
+}
+
+/// Network ids spawned and despawned going from `baseline` to `current`.
+pub fn entities_diff(baseline: &[u32], current: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let baseline_ids: HashSet<u32> = baseline.iter().copied().collect();
+    let current_ids: HashSet<u32> = current.iter().copied().collect();
+    let spawned = current.iter().copied().filter(|id| !baseline_ids.contains(id)).collect();
+    let despawned = baseline.iter().copied().filter(|id| !current_ids.contains(id)).collect();
+    (spawned, despawned)
+}
+
+/// Checks that the entities a delta spawns are new to its baseline.
+pub fn validate_spawned_entities_id(baseline: &[u32], spawned: &[u32]) -> Result<(), DeltaError> {
+    let mut entities_id: HashSet<u32> = baseline.iter().copied().collect();
+    for &id in spawned {
+        if !entities_id.insert(id) {
+            return Err(DeltaError::Custom(format!("spawned entity {} already exists", id)));
+        }
+    }
+    Ok(())
+}
+
+/// Entities of a delta: the ids of `baseline` without the despawned ones, with the spawned ones.
+///
+/// Sorted, so both ends get the same order whatever the order of their baseline.
+pub fn rebuild_entities_id(baseline: &[u32], spawned: &[u32], despawned: &[u32]) -> Vec<u32> {
+    let despawned: HashSet<u32> = despawned.iter().copied().collect();
+    let mut entities_id: Vec<u32> = baseline
+        .iter()
+        .copied()
+        .filter(|id| !despawned.contains(id))
+        .chain(spawned.iter().copied())
+        .collect();
+    entities_id.sort_unstable();
+    entities_id
 }
 
 pub struct NetworkController {
     pub frame: u32,
     snapshot_frequency: u32,
+    // Frame of the last snapshot sent to each client
+    snapshots_sent: HashMap<SocketAddr, u32>,
 }
 
 impl NetworkController {
@@ -71,6 +233,7 @@ impl NetworkController {
         NetworkController {
             frame: 0,
             snapshot_frequency,
+            snapshots_sent: HashMap::new(),
         }
     }
 
@@ -79,7 +242,153 @@ impl NetworkController {
     }
 
     pub fn is_snapshot_frame(&self) -> bool {
-        (self.frame % self.snapshot_frequency) == 0
+        self.frame.is_multiple_of(self.snapshot_frequency)
+    }
+
+    /// Whether `client`, which acked up to `acked`, is sent a snapshot of `frame`.
+    ///
+    /// A snapshot the client didn't ack yet is only sent again after the snapshot frequency, its
+    /// chunks included.
+    pub fn send_snapshot(&mut self, client: SocketAddr, frame: u32, acked: Option<u32>) -> bool {
+        match self.snapshots_sent.get(&client) {
+            Some(&sent) if acked < Some(sent) && frame < sent.saturating_add(self.snapshot_frequency) => false,
+            _ => {
+                self.snapshots_sent.insert(client, frame);
+                true
+            }
+        }
+    }
+
+    /// Forgets the snapshots sent to the clients not in `clients`.
+    pub fn retain_clients(&mut self, clients: &[SocketAddr]) {
+        self.snapshots_sent.retain(|addr, _| clients.contains(addr));
+    }
+}
+
+/// The most recent states, ordered by frame.
+pub struct StateHistory<T> {
+    states: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> StateHistory<T>
+where
+    T: CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    pub fn new(capacity: usize) -> Self {
+        StateHistory {
+            states: VecDeque::with_capacity(capacity + 1),
+            capacity,
+        }
+    }
+
+    /// Inserts the state in frame order, dropping the oldest one when full.
+    pub fn push(&mut self, state: T) {
+        let frame = state.frame();
+        match self.states.iter().position(|s| s.frame() >= frame) {
+            Some(i) if self.states[i].frame() == frame => return,
+            Some(i) => self.states.insert(i, state),
+            None => self.states.push_back(state),
+        }
+        if self.states.len() > self.capacity {
+            self.states.pop_front();
+        }
+    }
+
+    pub fn get(&self, frame: u32) -> Option<&T> {
+        self.states.iter().find(|s| s.frame() == frame)
+    }
+
+    pub fn latest(&self) -> Option<&T> {
+        self.states.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+    }
+}
+
+/// Deltas that arrived before their baseline, waiting for it to be received.
+pub struct PendingDeltas<D> {
+    deltas: VecDeque<D>,
+    capacity: usize,
+    max_age: u32,
+    expired: u64,
+}
+
+impl<D: CarrierDeltaPacket> PendingDeltas<D> {
+    /// Keeps at most `capacity` deltas, dropping the ones `max_age` frames older than the newest state.
+    pub fn new(capacity: usize, max_age: u32) -> Self {
+        PendingDeltas {
+            deltas: VecDeque::with_capacity(capacity + 1),
+            capacity,
+            max_age,
+            expired: 0,
+        }
+    }
+
+    /// Queues the delta, the oldest one is expired when full.
+    pub fn push(&mut self, delta: D) {
+        self.deltas.push_back(delta);
+        if self.deltas.len() > self.capacity {
+            let oldest = self
+                .deltas
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, delta)| delta.frame())
+                .map(|(i, _)| i)
+                .unwrap();
+            self.deltas.remove(oldest);
+            self.expired += 1;
+        }
+    }
+
+    /// Removes and returns the deltas waiting for the given baseline frame.
+    pub fn take(&mut self, snapshot_frame: u32) -> Vec<D> {
+        let mut taken = vec![];
+        let mut i = 0;
+        while i < self.deltas.len() {
+            if self.deltas[i].snapshot_frame() == snapshot_frame {
+                taken.extend(self.deltas.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        taken
+    }
+
+    /// Drops the deltas too old compared to the newest received frame.
+    pub fn expire(&mut self, newest_frame: u32) {
+        let max_age = self.max_age;
+        let len = self.deltas.len();
+        self.deltas.retain(|delta| newest_frame.saturating_sub(delta.frame()) <= max_age);
+        self.expired += (len - self.deltas.len()) as u64;
+    }
+
+    /// How many deltas were dropped without ever being applied.
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
     }
 }
 
@@ -104,6 +413,95 @@ where
         masked_ids
     }
 
+    /// Components by the network id of their entity.
+    pub fn components_by_id(&self, entities_id: &[u32]) -> HashMap<u32, &T> {
+        self.entities_mask
+            .iter()
+            .zip(entities_id)
+            .filter(|(bit, _)| *bit)
+            .map(|(_, &id)| id)
+            .zip(&self.values)
+            .collect()
+    }
+
+    /// Component of the entity with the network id `id`, if it has one.
+    pub fn get(&self, entities_id: &[u32], id: u32) -> Option<&T> {
+        let index = entities_id.iter().position(|&x| x == id)?;
+        if !self.entities_mask.get(index)? {
+            return None;
+        }
+        let value_index = self.entities_mask.iter().take(index).filter(|&bit| bit).count();
+        self.values.get(value_index)
+    }
+
+    /// Keeps the components of the entities `keep` returns true for.
+    pub fn retain<F: FnMut(u32) -> bool>(&mut self, entities_id: &[u32], mut keep: F) {
+        let mut values = self.values.drain(..);
+        let mut kept = vec![];
+        for (i, &id) in entities_id.iter().enumerate() {
+            if !self.entities_mask.get(i).unwrap_or(false) {
+                continue;
+            }
+            let value = values.next().expect("Values should match the mask");
+            if keep(id) {
+                kept.push(value);
+            } else {
+                self.entities_mask.set(i, false);
+            }
+        }
+        drop(values);
+        self.values = kept;
+    }
+
+    /// Components of `previous` for the entities marked in `stale`, of `self` for the others.
+    pub fn merge(&self, entities_id: &[u32], stale: &[bool], previous: &NetworkBitmask<T>, previous_entities_id: &[u32]) -> NetworkBitmask<T> {
+        let previous = previous.components_by_id(previous_entities_id);
+        let mut entities_mask: BitVec<u32> = BitVec::from_elem(entities_id.len(), false);
+        let mut values = vec![];
+        let mut self_values = self.values.iter();
+        for (i, id) in entities_id.iter().enumerate() {
+            let value = if self.entities_mask.get(i).unwrap_or(false) { self_values.next() } else { None };
+            let value = if stale[i] { previous.get(id).copied() } else { value };
+            if let Some(value) = value {
+                entities_mask.set(i, true);
+                values.push(value.clone());
+            }
+        }
+        NetworkBitmask { entities_mask, values }
+    }
+
+    /// Components of `self`, followed by the ones of the `other` entities missing from `entities_id`.
+    pub fn extend(&self, entities_id: &[u32], other: &NetworkBitmask<T>, other_entities_id: &[u32]) -> NetworkBitmask<T> {
+        let ids: HashSet<u32> = entities_id.iter().copied().collect();
+        let mut entities_mask = self.entities_mask.clone();
+        let mut values = self.values.clone();
+        let mut other_values = other.values.iter();
+        for (i, id) in other_entities_id.iter().enumerate() {
+            let value = if other.entities_mask.get(i).unwrap_or(false) { other_values.next() } else { None };
+            if !ids.contains(id) {
+                entities_mask.push(value.is_some());
+                values.extend(value.cloned());
+            }
+        }
+        NetworkBitmask { entities_mask, values }
+    }
+
+    /// Components of the entities marked in `kept`, the mask only has bits for those entities.
+    pub fn select(&self, kept: &[bool]) -> NetworkBitmask<T> {
+        let mut entities_mask: BitVec<u32> = BitVec::new();
+        let mut values = vec![];
+        let mut self_values = self.values.iter();
+        for (i, &keep) in kept.iter().enumerate() {
+            let bit = self.entities_mask.get(i).unwrap_or(false);
+            let value = if bit { self_values.next() } else { None };
+            if keep {
+                entities_mask.push(bit);
+                values.extend(value.cloned());
+            }
+        }
+        NetworkBitmask { entities_mask, values }
+    }
+
     //TODO: this should not be pub (test purposes only?, maybe pass to NetworkState)
     pub fn add_value(&mut self, value: T) {
         self.values.push(value);
@@ -135,35 +533,95 @@ where
     }
 }
 
+impl<T> NetworkBitmask<T>
+where
+    T: Clone + NetSerialize,
+{
+    /// Bits of the entity's component on the wire, 0 if it doesn't have one.
+    pub fn component_bits(&self, entities_id: &[u32], id: u32, quantize: Option<wire::Quantize>) -> u64 {
+        self.get(entities_id, id)
+            .map_or(0, |component| wire::serialized_bits(component, quantize))
+    }
+
+    /// [`component_bits`](NetworkBitmask::component_bits) of every entity, in the mask order.
+    pub fn components_bits(&self, quantize: Option<wire::Quantize>) -> Vec<u64> {
+        let mut values = self.values.iter();
+        self.entities_mask
+            .iter()
+            .map(|bit| {
+                if bit {
+                    values.next().map_or(0, |component| wire::serialized_bits(component, quantize))
+                } else {
+                    0
+                }
+            })
+            .collect()
+    }
+}
+
 impl<T> NetworkBitmask<T>
 where
     T: Clone + Delta,
     T::DeltaType: Clone
 {
-    pub fn get_delta_bitmask(&self, delta_entities_id: &[u32], snapshot: &NetworkBitmask<T>, snapshot_entities_id: &[u32]) -> (NetworkBitmask<T>, NetworkBitmask<T::DeltaType>) {
-        let ids_element = self.masked_entities_id(delta_entities_id);
-        let snapshot_ids = snapshot.masked_entities_id(snapshot_entities_id);
+    fn check_mask_len(&self, entities_id: &[u32]) -> Result<(), DeltaError> {
+        if self.entities_mask.len() != entities_id.len() {
+            return Err(DeltaError::MaskLength {
+                expected: entities_id.len(),
+                found: self.entities_mask.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Components in the order of `new_entities_id`, a permutation of `entities_id`.
+    pub fn reorder(&self, entities_id: &[u32], new_entities_id: &[u32]) -> Result<NetworkBitmask<T>, DeltaError> {
+        self.check_mask_len(entities_id)?;
+        if entities_id == new_entities_id {
+            return Ok(self.clone());
+        }
+        let components = self.components_by_id(entities_id);
+        let mut entities_mask: BitVec<u32> = BitVec::from_elem(new_entities_id.len(), false);
+        let mut values = vec![];
+        for (i, id) in new_entities_id.iter().enumerate() {
+            if let Some(&component) = components.get(id) {
+                entities_mask.set(i, true);
+                values.push(component.clone());
+            }
+        }
+        Ok(NetworkBitmask { entities_mask, values })
+    }
+
+    /// Splits the components into the ones sent in full and the ones sent as deltas.
+    /// Components unchanged since the snapshot are left out of both.
+    pub fn get_delta_bitmask(&self, delta_entities_id: &[u32], snapshot: &NetworkBitmask<T>, snapshot_entities_id: &[u32]) -> Result<(NetworkBitmask<T>, NetworkBitmask<T::DeltaType>), DeltaError> {
+        self.check_mask_len(delta_entities_id)?;
+        snapshot.check_mask_len(snapshot_entities_id)?;
+        let snapshot_components = snapshot.components_by_id(snapshot_entities_id);
         let mut element = vec![];
         let mut mask_element: BitVec<u32> = BitVec::from_elem(delta_entities_id.len(), false);
         let mut delta_element = vec![];
         let mut delta_mask_element: BitVec<u32> = BitVec::from_elem(delta_entities_id.len(), false);
-        for (i, &id) in ids_element.iter().enumerate() {
-            match snapshot_ids.iter().position(|&x| x == id) {
-                Some(snapshot_index) => {
-                    let snapshot_component = &snapshot.values[snapshot_index];
-                    let current_component = &self.values[i];
-                    if let Some(delta) = snapshot_component.from(current_component) {
-                        delta_mask_element.set(i, true);
-                        delta_element.push(delta);
-                    } else {
-                        mask_element.set(i, true);
-                        element.push(current_component.clone());
+        let masked_positions = self.entities_mask.iter().enumerate().filter(|(_, bit)| *bit);
+        for (current_component, (i, _)) in self.values.iter().zip(masked_positions) {
+            let id = delta_entities_id[i];
+            match snapshot_components.get(&id) {
+                Some(&snapshot_component) => {
+                    match current_component.from(snapshot_component)? {
+                        DeltaOutcome::Unchanged => {}
+                        DeltaOutcome::Delta(delta) => {
+                            delta_mask_element.set(i, true);
+                            delta_element.push(delta);
+                        }
+                        DeltaOutcome::Full => {
+                            mask_element.set(i, true);
+                            element.push(current_component.clone());
+                        }
                     }
                 },
                 None => {
-                    let current_component = self.values[i].clone();
                     mask_element.set(i, true);
-                    element.push(current_component);
+                    element.push(current_component.clone());
                 }
             }
         }
@@ -177,28 +635,110 @@ where
             entities_mask: delta_mask_element,
         };
 
-        (network_element, delta_network_element)
+        Ok((network_element, delta_network_element))
     }
 
-    pub fn apply_delta_bitmask(&self, snapshot_entities_id: &[u32], delta: &NetworkBitmask<T::DeltaType>, delta_entities_id: &[u32]) -> NetworkBitmask<T> {
-        let snapshot_ids = self.masked_entities_id(&snapshot_entities_id);
-        let ids_element = delta.masked_entities_id(&delta_entities_id);
-        let mut element = vec![];
-        for (i, &id) in ids_element.iter().enumerate() {
-            let snapshot_index = snapshot_ids.iter().position(|&x| x == id).expect("All deltas ids must be in the snapshot");
-            let snapshot_component = self.values[snapshot_index].clone();
-            let delta_component = delta.values[i].clone();
-            let component = snapshot_component.apply(&delta_component);
-            element.push(component);
+    /// Network ids that had this component in the snapshot and lost it, but are still replicated.
+    pub fn removed_entities_id(&self, delta_entities_id: &[u32], snapshot: &NetworkBitmask<T>, snapshot_entities_id: &[u32]) -> Vec<u32> {
+        let ids_element: HashSet<u32> = self.masked_entities_id(delta_entities_id).into_iter().collect();
+        let delta_ids: HashSet<u32> = delta_entities_id.iter().copied().collect();
+        snapshot
+            .masked_entities_id(snapshot_entities_id)
+            .into_iter()
+            .filter(|id| delta_ids.contains(id) && !ids_element.contains(id))
+            .collect()
+    }
+
+    /// Checks that [`apply_delta_bitmask`](NetworkBitmask::apply_delta_bitmask) can rebuild the
+    /// components from `full` and `delta`: both masks have a bit for each entity of
+    /// `delta_entities_id`, a value for each set bit, and deltas are only for components the
+    /// snapshot has.
+    pub fn validate_delta_bitmask(&self, snapshot_entities_id: &[u32], full: &NetworkBitmask<T>, delta: &NetworkBitmask<T::DeltaType>, delta_entities_id: &[u32]) -> Result<(), DeltaError> {
+        let masks = [
+            (&full.entities_mask, full.values.len()),
+            (&delta.entities_mask, delta.values.len()),
+        ];
+        for (mask, values) in masks.iter() {
+            if mask.len() != delta_entities_id.len() {
+                return Err(DeltaError::MaskLength {
+                    expected: delta_entities_id.len(),
+                    found: mask.len(),
+                });
+            }
+            let set = mask.iter().filter(|&bit| bit).count();
+            if set != *values {
+                return Err(DeltaError::Custom(format!("{} values for {} set bits", values, set)));
+            }
+        }
+        let snapshot_components: HashSet<u32> = self.masked_entities_id(snapshot_entities_id).into_iter().collect();
+        for (i, bit) in delta.entities_mask.iter().enumerate() {
+            if bit && !snapshot_components.contains(&delta_entities_id[i]) {
+                return Err(DeltaError::Custom(format!(
+                    "delta for entity {} without a component in the snapshot",
+                    delta_entities_id[i]
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the components from the snapshot: full values are taken as they are, deltas are
+    /// applied to the snapshot component and unchanged components are copied from the snapshot.
+    pub fn apply_delta_bitmask(&self, snapshot_entities_id: &[u32], full: &NetworkBitmask<T>, delta: &NetworkBitmask<T::DeltaType>, removed_entities_id: &[u32], delta_entities_id: &[u32]) -> NetworkBitmask<T> {
+        let snapshot_components = self.components_by_id(snapshot_entities_id);
+        let removed_entities_id: HashSet<u32> = removed_entities_id.iter().copied().collect();
+        let mut entities_mask: BitVec<u32> = BitVec::from_elem(delta_entities_id.len(), false);
+        let mut values = vec![];
+        let mut full_values = full.values.iter();
+        let mut delta_values = delta.values.iter();
+        for (i, id) in delta_entities_id.iter().enumerate() {
+            let snapshot_component = snapshot_components.get(id).copied();
+            let component = if full.entities_mask.get(i).unwrap_or(false) {
+                full_values.next().cloned()
+            } else if delta.entities_mask.get(i).unwrap_or(false) {
+                let delta_component = delta_values.next().expect("Delta values should match its mask");
+                let snapshot_component = snapshot_component.expect("All deltas ids must be in the snapshot");
+                Some(snapshot_component.apply(delta_component))
+            } else if !removed_entities_id.contains(id) {
+                snapshot_component.cloned()
+            } else {
+                None
+            };
+            if let Some(component) = component {
+                entities_mask.set(i, true);
+                values.push(component);
+            }
+        }
+
+        NetworkBitmask {
+            entities_mask,
+            values,
+        }
+    }
+}
+
+impl<T> NetworkBitmask<T>
+where
+    T: Clone + Interpolate,
+{
+    /// Blends each component with the one of the same entity in `next`.
+    /// Components that `next` doesn't have are kept as they are.
+    pub fn interpolate(&self, entities_id: &[u32], next: &NetworkBitmask<T>, next_entities_id: &[u32], t: f32) -> NetworkBitmask<T> {
+        let next_components = next.components_by_id(next_entities_id);
+        let values = self
+            .values
+            .iter()
+            .zip(self.masked_entities_id(entities_id))
+            .map(|(component, id)| match next_components.get(&id) {
+                Some(next_component) => component.interpolate(next_component, t),
+                None => component.clone(),
+            })
+            .collect();
+
+        NetworkBitmask {
+            entities_mask: self.entities_mask.clone(),
+            values,
         }
-        
-        let network_element = NetworkBitmask {
-            values: element,
-            entities_mask: delta.entities_mask.clone(),
-        };
-        // Append full networkbitmask with delta networkbitmask
-        // network_element.join(&delta.$element);
-        network_element
     }
 }
 
@@ -206,22 +746,18 @@ pub fn replicate<T: 'static + Sync + Send + fmt::Debug + Copy + Serialize>(
     world: &World,
     entities_id: &[u32],
 ) -> NetworkBitmask<T> {
-    let mut values = vec![];
-    let mut entities_mask: BitVec<u32> = BitVec::from_elem(entities_id.len(), false);
+    let positions: HashMap<u32, usize> = entities_id.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    // Placed by entity, the storage can iterate in another order than entities_id
+    let mut components: Vec<Option<T>> = vec![None; entities_id.len()];
     world.run(|storage: View<T>, net_ids: View<NetworkIdentifier>| {
         for (component, net_id) in (&storage, &net_ids).iter() {
-            // TODO: use sparce set for the sweat O(1) get instead of a find here
-            let id_pos = entities_id
-                .iter()
-                .position(|&x| x == net_id.id)
-                .expect("All network ids should be contained.");
-            entities_mask.set(id_pos, true);
-            values.push(*component);
+            let id_pos = *positions.get(&net_id.id).expect("All network ids should be contained.");
+            components[id_pos] = Some(*component);
         }
     });
     NetworkBitmask {
-        entities_mask,
-        values,
+        entities_mask: components.iter().map(Option::is_some).collect(),
+        values: components.into_iter().flatten().collect(),
     }
 }
 
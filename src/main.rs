@@ -3,7 +3,7 @@ use serde::{Deserialize, Serialize};
 use shipyard::*;
 use bit_vec::BitVec;
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, NetSerialize, Clone, Copy, Debug, PartialEq)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
@@ -15,7 +15,7 @@ impl Position {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, NetSerialize, Debug, Clone, PartialEq)]
 pub struct DeltaPosition {
     pub x: u8,
     pub y: u8,
@@ -24,8 +24,8 @@ pub struct DeltaPosition {
 impl Delta for Position {
     type DeltaType = DeltaPosition;
 
-    fn from(&self, _other: &Position) -> Option<DeltaPosition> {
-        Some(DeltaPosition { x: 0, y: 0 })
+    fn from(&self, _baseline: &Position) -> Result<DeltaOutcome<DeltaPosition>, DeltaError> {
+        Ok(DeltaOutcome::Delta(DeltaPosition { x: 0, y: 0 }))
     }
 
     fn apply(&self, _other: &Self::DeltaType) -> Position {
@@ -74,7 +74,10 @@ fn main() {
     // state.entities_id.push(2);
     // state.positions.add_value(Position::new(1.0, 1.0));
 
-    let delta_state = state.from(&snapshot).unwrap();
+    let delta_state = match state.from(&snapshot).unwrap() {
+        DeltaOutcome::Delta(delta_state) => delta_state,
+        outcome => panic!("Expected a delta, got {:?}", outcome),
+    };
     let applied_state = snapshot.apply(&delta_state);
     assert_eq!(applied_state, state);
     println!("{:?}", applied_state);
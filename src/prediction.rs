@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use shipyard::{EntityId, World};
+
+/// State of the locally owned entity that the client simulates ahead of the server.
+pub trait Predict: Clone {
+    /// Packet the server states are received as.
+    type State;
+    type Input: Clone;
+
+    /// Reads the entity with the network id `net_id` from a server state, `None` if it isn't there.
+    fn from_state(state: &Self::State, net_id: u32) -> Option<Self>;
+    /// Simulation step, must do what the server does with the same input.
+    fn step(&mut self, input: &Self::Input);
+    /// Writes the predicted state to the local entity.
+    fn apply(&self, world: &World, entity: EntityId);
+}
+
+struct PredictedTick<P: Predict> {
+    tick: u32,
+    input: P::Input,
+    state: Option<P>,
+}
+
+/// Inputs not yet processed by the server, with the state predicted after each of them.
+pub struct Prediction<P: Predict> {
+    tick: u32,
+    history: VecDeque<PredictedTick<P>>,
+    capacity: usize,
+    predicted: Option<P>,
+    reconciled_frame: Option<u32>,
+}
+
+impl<P: Predict> Prediction<P> {
+    pub fn new(capacity: usize) -> Self {
+        Prediction {
+            tick: 0,
+            history: VecDeque::with_capacity(capacity + 1),
+            capacity,
+            predicted: None,
+            reconciled_frame: None,
+        }
+    }
+
+    /// Advances one client tick with `input`, returns the tick the input is tagged with.
+    /// Nothing is predicted until the first server state is reconciled.
+    pub fn push_input(&mut self, input: P::Input) -> u32 {
+        self.tick += 1;
+        if let Some(predicted) = &mut self.predicted {
+            predicted.step(&input);
+        }
+        self.history.push_back(PredictedTick {
+            tick: self.tick,
+            input,
+            state: self.predicted.clone(),
+        });
+        // The server is too far behind, the oldest input can't be replayed anymore
+        if self.history.len() > self.capacity {
+            self.history.pop_front();
+        }
+        self.tick
+    }
+
+    /// Rolls back to the server state of `frame`, built with the inputs up to `input_tick`,
+    /// and replays the inputs the server didn't process yet. Older frames are ignored.
+    pub fn reconcile(&mut self, frame: u32, server_state: P, input_tick: Option<u32>) {
+        if matches!(self.reconciled_frame, Some(reconciled) if frame <= reconciled) {
+            return;
+        }
+        self.reconciled_frame = Some(frame);
+        if let Some(input_tick) = input_tick {
+            while matches!(self.history.front(), Some(predicted) if predicted.tick <= input_tick) {
+                self.history.pop_front();
+            }
+        }
+
+        let mut state = server_state;
+        for predicted in self.history.iter_mut() {
+            state.step(&predicted.input);
+            predicted.state = Some(state.clone());
+        }
+        self.predicted = Some(state);
+    }
+
+    /// Tick of the last input.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn predicted(&self) -> Option<&P> {
+        self.predicted.as_ref()
+    }
+
+    /// State predicted after the input of `tick`, while the input is unacknowledged.
+    pub fn predicted_at(&self, tick: u32) -> Option<&P> {
+        self.history
+            .iter()
+            .find(|predicted| predicted.tick == tick)
+            .and_then(|predicted| predicted.state.as_ref())
+    }
+
+    pub fn reconciled_frame(&self) -> Option<u32> {
+        self.reconciled_frame
+    }
+
+    /// Number of inputs waiting for the server.
+    pub fn unacked(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Forgets the predicted state and the inputs, the tick keeps counting.
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.predicted = None;
+        self.reconciled_frame = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Player(i32);
+
+    impl Predict for Player {
+        type State = Vec<(u32, i32)>;
+        type Input = i32;
+
+        fn from_state(state: &Self::State, net_id: u32) -> Option<Self> {
+            state.iter().find(|(id, _)| *id == net_id).map(|(_, x)| Player(*x))
+        }
+
+        fn step(&mut self, input: &i32) {
+            self.0 += input;
+        }
+
+        fn apply(&self, _world: &World, _entity: EntityId) {}
+    }
+
+    #[test]
+    fn predicts_after_first_state() {
+        let mut prediction = Prediction::<Player>::new(8);
+        assert_eq!(prediction.push_input(1), 1);
+        assert_eq!(prediction.predicted(), None);
+
+        prediction.reconcile(1, Player(10), None);
+        assert_eq!(prediction.predicted(), Some(&Player(11)));
+        prediction.push_input(2);
+        assert_eq!(prediction.predicted(), Some(&Player(13)));
+        assert_eq!(prediction.predicted_at(2), Some(&Player(13)));
+    }
+
+    #[test]
+    fn replays_unacked_inputs() {
+        let mut prediction = Prediction::<Player>::new(8);
+        prediction.reconcile(1, Player(0), None);
+        for input in 1..=4 {
+            prediction.push_input(input);
+        }
+        assert_eq!(prediction.predicted(), Some(&Player(10)));
+
+        // The server processed inputs 1 and 2 but the entity was pushed back
+        prediction.reconcile(2, Player(-5), Some(2));
+        assert_eq!(prediction.unacked(), 2);
+        assert_eq!(prediction.predicted(), Some(&Player(2)));
+        assert_eq!(prediction.predicted_at(3), Some(&Player(-2)));
+
+        // Older states don't roll back again
+        prediction.reconcile(1, Player(100), Some(1));
+        assert_eq!(prediction.predicted(), Some(&Player(2)));
+    }
+
+    #[test]
+    fn drops_oldest_inputs() {
+        let mut prediction = Prediction::<Player>::new(2);
+        for input in 1..=3 {
+            prediction.push_input(input);
+        }
+        assert_eq!(prediction.unacked(), 2);
+        prediction.reconcile(1, Player(0), None);
+        assert_eq!(prediction.predicted(), Some(&Player(5)));
+    }
+}
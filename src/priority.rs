@@ -0,0 +1,240 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use shipyard::World;
+
+use super::transport::{NetworkClient, NetworkSerializable};
+use super::{CarrierDeltaPacket, CarrierPacket, Delta};
+
+/// What a priority hook knows about the entity's client.
+pub struct PriorityContext<'a, T> {
+    pub client: &'a NetworkClient,
+    /// State selected for the client this tick.
+    pub state: &'a T,
+    /// Last state sent to the client, to compare with.
+    pub previous: Option<&'a T>,
+}
+
+/// Priority an entity gains for the client each tick, on top of the base priority of 1.
+pub type PriorityHook<T> = Box<dyn Fn(&PriorityContext<T>, u32) -> f32 + Send + Sync>;
+
+/// What the budget did to a client's last state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BudgetStats {
+    /// Entities sent, in priority order.
+    pub sent: Vec<u32>,
+    /// Entities left out, the client keeps their last sent components or doesn't spawn them yet.
+    pub left_out: Vec<u32>,
+    /// Estimated size of the sent entities.
+    pub bytes: u64,
+}
+
+struct ClientPriorities<T> {
+    priorities: HashMap<u32, f32>,
+    last_sent: Option<T>,
+    stats: BudgetStats,
+}
+
+/// Caps the bytes each client gets per tick from the states `inner` selects.
+///
+/// Every entity gains priority each tick, through the hooks, until it is sent. The state is
+/// filled in priority order until the budget is used up, the highest priority entity is always
+/// sent. Sizes are estimated from the full components, before delta compression.
+pub struct PriorityBudget<S: NetworkSerializable> {
+    inner: S,
+    pub budget: u64,
+    hooks: Vec<PriorityHook<S::Packet>>,
+    clients: HashMap<SocketAddr, ClientPriorities<S::Packet>>,
+    // Clients selected since the last capture, the others are gone
+    selected: HashSet<SocketAddr>,
+}
+
+impl<S: NetworkSerializable> PriorityBudget<S> {
+    pub fn new(inner: S, budget: u64) -> Self {
+        PriorityBudget {
+            inner,
+            budget,
+            hooks: vec![],
+            clients: HashMap::new(),
+            selected: HashSet::new(),
+        }
+    }
+
+    /// Adds a hook raising entity priorities, such as the distance to the client's entity or a
+    /// recent change.
+    pub fn add_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&PriorityContext<S::Packet>, u32) -> f32 + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn stats(&self, client: SocketAddr) -> Option<&BudgetStats> {
+        self.clients.get(&client).map(|priorities| &priorities.stats)
+    }
+
+    /// Accumulated priority of the entity for the client.
+    pub fn priority(&self, client: SocketAddr, id: u32) -> Option<f32> {
+        self.clients.get(&client)?.priorities.get(&id).copied()
+    }
+}
+
+impl<S> NetworkSerializable for PriorityBudget<S>
+where
+    S: NetworkSerializable,
+    S::Packet: CarrierPacket + Clone,
+    <S::Packet as Delta>::DeltaType: CarrierDeltaPacket,
+{
+    type Capture = S::Capture;
+    type Packet = S::Packet;
+
+    fn serialize(&mut self, world: &World, frame: u32) -> S::Capture {
+        let selected = std::mem::take(&mut self.selected);
+        self.clients.retain(|addr, _| selected.contains(addr));
+        self.inner.serialize(world, frame)
+    }
+
+    fn select_for_client(&mut self, client: &NetworkClient, capture: &S::Capture) -> S::Packet {
+        self.selected.insert(client.addr);
+        let state = self.inner.select_for_client(client, capture);
+        let priorities = self.clients.entry(client.addr).or_insert_with(|| ClientPriorities {
+            priorities: HashMap::new(),
+            last_sent: None,
+            stats: BudgetStats::default(),
+        });
+
+        let context = PriorityContext {
+            client,
+            state: &state,
+            previous: priorities.last_sent.as_ref(),
+        };
+        let mut accumulated: HashMap<u32, f32> = HashMap::new();
+        let sizes: HashMap<u32, u64> = state.entities_id().iter().copied().zip(state.entity_sizes()).collect();
+        for &id in state.entities_id() {
+            let gained: f32 = 1.0 + self.hooks.iter().map(|hook| hook(&context, id)).sum::<f32>();
+            accumulated.insert(id, priorities.priorities.get(&id).copied().unwrap_or(0.0) + gained);
+        }
+        let mut ordered: Vec<(u32, f32)> = accumulated.iter().map(|(&id, &priority)| (id, priority)).collect();
+        ordered.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal).then(a.0.cmp(&b.0)));
+
+        let mut stats = BudgetStats::default();
+        for (id, _) in ordered {
+            let size = sizes[&id];
+            if stats.sent.is_empty() || (stats.left_out.is_empty() && stats.bytes + size <= self.budget) {
+                stats.sent.push(id);
+                stats.bytes += size;
+                accumulated.remove(&id);
+            } else {
+                stats.left_out.push(id);
+            }
+        }
+
+        let state = match &priorities.last_sent {
+            Some(previous) if !stats.left_out.is_empty() => {
+                state.with_stale_entities(previous, |id| accumulated.contains_key(&id))
+            }
+            None if !stats.left_out.is_empty() => state.retain_entities(|id| !accumulated.contains_key(&id)),
+            _ => state,
+        };
+        priorities.priorities = accumulated;
+        priorities.last_sent = Some(state.clone());
+        priorities.stats = stats;
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::WholeWorld;
+    use crate::{generate_packet, Delta, NetSerialize, NetworkIdentifier};
+    use serde::{Deserialize, Serialize};
+    use shipyard::*;
+
+    #[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub struct Position(#[delta(full)] f32);
+
+    generate_packet!(struct State {
+        positions: Position,
+    });
+
+    // Ids and positions, 8 bytes each
+    fn spawn(world: &World, count: usize) -> Vec<(EntityId, u32)> {
+        world.run(|mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>| {
+            (0..count)
+                .map(|i| {
+                    let net_id = NetworkIdentifier::default();
+                    let entity = entities.add_entity((&mut net_ids, &mut positions), (net_id, Position(i as f32)));
+                    (entity, net_id.id)
+                })
+                .collect()
+        })
+    }
+
+    fn client() -> NetworkClient {
+        NetworkClient {
+            addr: "127.0.0.1:5001".parse().unwrap(),
+            entity: None,
+        }
+    }
+
+    fn tick(budget: &mut PriorityBudget<WholeWorld<NetworkPacket>>, world: &World, frame: u32) -> NetworkPacket {
+        let capture = budget.serialize(world, frame);
+        budget.select_for_client(&client(), &capture)
+    }
+
+    #[test]
+    fn left_out_entities_catch_up() {
+        let world = World::default();
+        let ids: Vec<u32> = spawn(&world, 3).into_iter().map(|(_, id)| id).collect();
+        let mut budget = PriorityBudget::new(WholeWorld::<NetworkPacket>::default(), 16);
+
+        // New entities left out are not spawned yet
+        let state = tick(&mut budget, &world, 1);
+        assert_eq!(state.entities_id(), &ids[..2]);
+        let stats = budget.stats(client().addr).unwrap();
+        assert_eq!(stats.left_out, vec![ids[2]]);
+        assert_eq!(stats.bytes, 16);
+        assert_eq!(budget.priority(client().addr, ids[2]), Some(1.0));
+
+        let state = tick(&mut budget, &world, 2);
+        assert_eq!(budget.stats(client().addr).unwrap().sent[0], ids[2]);
+        assert_eq!(state.entities_id().len(), 3);
+    }
+
+    #[test]
+    fn stale_entities_keep_last_sent_components() {
+        let world = World::default();
+        let entities = spawn(&world, 2);
+        let mut budget = PriorityBudget::new(WholeWorld::<NetworkPacket>::default(), 1000);
+        tick(&mut budget, &world, 1);
+
+        budget.budget = 8;
+        world.run(|mut positions: ViewMut<Position>| {
+            for &(entity, _) in &entities {
+                positions[entity].0 += 10.0;
+            }
+        });
+        let state = tick(&mut budget, &world, 2);
+        assert_eq!(state.positions(entities[0].1), Some(&Position(10.0)));
+        assert_eq!(state.positions(entities[1].1), Some(&Position(1.0)));
+    }
+
+    #[test]
+    fn hooks_raise_priority() {
+        let world = World::default();
+        let ids: Vec<u32> = spawn(&world, 3).into_iter().map(|(_, id)| id).collect();
+        let mut budget = PriorityBudget::new(WholeWorld::<NetworkPacket>::default(), 8);
+        let last = ids[2];
+        budget.add_hook(move |_, id| if id == last { 5.0 } else { 0.0 });
+
+        tick(&mut budget, &world, 1);
+        let stats = budget.stats(client().addr).unwrap();
+        assert_eq!(stats.sent, vec![last]);
+        assert_eq!(stats.left_out, vec![ids[0], ids[1]]);
+    }
+}
@@ -0,0 +1,112 @@
+//! Times building and applying the states of a world with 10k entities.
+//!
+//! Run with `cargo bench -p proc_macros`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use netcarrier::transport::NetworkIdMapping;
+use netcarrier::{generate_packet, Delta, DeltaOutcome, NetSerialize, NetworkIdentifier};
+use serde::{Deserialize, Serialize};
+
+const ENTITIES: usize = 10_000;
+const RUNS: u32 = 10;
+
+#[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    #[delta(quantize = "i8")]
+    pub x: f32,
+    #[delta(quantize = "i8")]
+    pub y: f32,
+}
+
+#[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Score(#[delta(full)] u32);
+
+generate_packet!(struct State {
+    positions: Position,
+    scores: Score,
+});
+
+fn spawn(world: &World) -> Vec<EntityId> {
+    world.run(
+        |mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>, mut scores: ViewMut<Score>| {
+            (0..ENTITIES)
+                .map(|i| {
+                    let entity = entities.add_entity((&mut net_ids, &mut positions), (NetworkIdentifier::default(), Position { x: i as f32, y: 0.0 }));
+                    if i % 2 == 0 {
+                        entities.add_component(&mut scores, Score(0), entity);
+                    }
+                    entity
+                })
+                .collect()
+        },
+    )
+}
+
+// Moves a tenth of the entities and replaces a hundredth of them
+fn step(world: &mut World, entities: &mut Vec<EntityId>, frame: usize) {
+    world.run(|mut positions: ViewMut<Position>| {
+        for entity in entities.iter().skip(frame % 10).step_by(10) {
+            positions[*entity].x += 1.0;
+        }
+    });
+    for _ in 0..ENTITIES / 100 {
+        let entity = entities.remove(frame % entities.len());
+        world.run(|mut all_storages: AllStoragesViewMut| {
+            all_storages.delete(entity);
+        });
+    }
+    let spawned = world.run(|mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>| {
+        (0..ENTITIES / 100)
+            .map(|_| entities.add_entity((&mut net_ids, &mut positions), (NetworkIdentifier::default(), Position { x: 0.0, y: 0.0 })))
+            .collect::<Vec<_>>()
+    });
+    entities.extend(spawned);
+}
+
+fn report(name: &str, times: &[Duration]) {
+    let total: Duration = times.iter().sum();
+    let best = times.iter().min().unwrap();
+    println!("{:<12} mean {:>10.3?}  best {:>10.3?}", name, total / times.len() as u32, best);
+}
+
+fn main() {
+    let mut world = World::default();
+    let mut entities = spawn(&world);
+    let client = World::default();
+    client.add_unique(NetworkIdMapping(HashMap::new()));
+
+    let (mut capture, mut delta, mut apply, mut apply_state) = (vec![], vec![], vec![], vec![]);
+    let mut baseline = NetworkPacket::new(&world, 0);
+    for frame in 1..=RUNS {
+        step(&mut world, &mut entities, frame as usize);
+
+        let start = Instant::now();
+        let state = NetworkPacket::new(&world, frame);
+        capture.push(start.elapsed());
+
+        let start = Instant::now();
+        let delta_packet = match state.from(&baseline).unwrap() {
+            DeltaOutcome::Delta(delta_packet) => delta_packet,
+            outcome => panic!("Expected a delta, got {:?}", outcome),
+        };
+        delta.push(start.elapsed());
+
+        let start = Instant::now();
+        let applied = baseline.apply(&delta_packet);
+        apply.push(start.elapsed());
+
+        let start = Instant::now();
+        applied.apply_state(&client);
+        apply_state.push(start.elapsed());
+
+        baseline = state;
+    }
+
+    println!("{} entities, {} runs", ENTITIES, RUNS);
+    report("capture", &capture);
+    report("delta", &delta);
+    report("apply", &apply);
+    report("apply_state", &apply_state);
+}
@@ -0,0 +1,218 @@
+use proc_macro2::{Literal, TokenStream};
+use quote::{format_ident, quote};
+use syn::{DeriveInput, Lit, Meta, NestedMeta};
+
+use super::net_serialize;
+
+enum DeltaKind {
+    // The field type implements Delta itself
+    Nested,
+    // Numeric difference rounded to a multiple of precision and stored as ty
+    Quantized { ty: syn::Type, precision: f64 },
+    // The whole value is sent only when it changed
+    Full,
+    // The value is never sent, a change sends the full component
+    Constant,
+}
+
+fn delta_kind(field: &syn::Field) -> syn::Result<DeltaKind> {
+    let mut kind = DeltaKind::Nested;
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("delta")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[delta(...)]")),
+        };
+        let mut quantize = None;
+        let mut precision = None;
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("full") => kind = DeltaKind::Full,
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("constant") => kind = DeltaKind::Constant,
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("quantize") => match &value.lit {
+                    Lit::Str(ty) => quantize = Some(ty.parse::<syn::Type>()?),
+                    lit => return Err(syn::Error::new_spanned(lit, "expected an integer type, e.g. quantize = \"i8\"")),
+                },
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("precision") => match &value.lit {
+                    Lit::Float(float) => precision = Some(float.base10_parse::<f64>()?),
+                    Lit::Int(int) => precision = Some(int.base10_parse::<f64>()?),
+                    lit => return Err(syn::Error::new_spanned(lit, "expected a number, e.g. precision = 0.1")),
+                },
+                nested => {
+                    return Err(syn::Error::new_spanned(
+                        nested,
+                        "unknown delta attribute, expected `full`, `constant`, `quantize = \"..\"` or `precision = ..`",
+                    ))
+                }
+            }
+        }
+        match (quantize, precision) {
+            (Some(ty), precision) => {
+                kind = DeltaKind::Quantized {
+                    ty,
+                    precision: precision.unwrap_or(1.0),
+                }
+            }
+            (None, Some(_)) => return Err(syn::Error::new_spanned(attr, "precision requires quantize = \"..\"")),
+            (None, None) => {}
+        }
+    }
+    Ok(kind)
+}
+
+pub fn impl_delta_derive(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &ast.ident;
+    let vis = &ast.vis;
+    let delta_name = format_ident!("{}Delta", name);
+
+    if !ast.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&ast.generics, "Delta cannot be derived for generic types"));
+    }
+
+    let (fields, named) = match &ast.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => (&fields.named, true),
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Unnamed(fields),
+            ..
+        }) => (&fields.unnamed, false),
+        _ => return Err(syn::Error::new_spanned(ast, "Delta can only be derived for structs with fields")),
+    };
+
+    let mut delta_fields = vec![];
+    // Serialized with the quantization of the field they come from
+    let mut delta_members = vec![];
+    let mut from_checks = vec![];
+    let mut from_fields = vec![];
+    let mut apply_fields = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        };
+        let delta_member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(delta_fields.len().into()),
+        };
+        let field_vis = &field.vis;
+        let ty = &field.ty;
+
+        let mut quantize = net_serialize::field_quantize(&field.attrs)?;
+
+        let delta_ty = match delta_kind(field)? {
+            DeltaKind::Nested => {
+                from_fields.push(quote! {
+                    #delta_member: match ::netcarrier::Delta::from(&self.#member, &baseline.#member)? {
+                        ::netcarrier::DeltaOutcome::Unchanged => None,
+                        ::netcarrier::DeltaOutcome::Delta(delta) => {
+                            changed = true;
+                            Some(delta)
+                        }
+                        ::netcarrier::DeltaOutcome::Full => return Ok(::netcarrier::DeltaOutcome::Full),
+                    }
+                });
+                apply_fields.push(quote! {
+                    #member: match &delta.#delta_member {
+                        Some(delta) => ::netcarrier::Delta::apply(&self.#member, delta),
+                        None => ::std::clone::Clone::clone(&self.#member),
+                    }
+                });
+                quote! { Option<<#ty as ::netcarrier::Delta>::DeltaType> }
+            }
+            DeltaKind::Quantized { ty: quantized_ty, precision } => {
+                let precision = Literal::f64_unsuffixed(precision);
+                from_fields.push(quote! {
+                    #delta_member: match ::netcarrier::quantize::<#quantized_ty>(
+                        ((self.#member as f64) - (baseline.#member as f64)) / #precision
+                    ) {
+                        Some(0) => 0,
+                        Some(delta) => {
+                            changed = true;
+                            delta
+                        }
+                        None => return Ok(::netcarrier::DeltaOutcome::Full),
+                    }
+                });
+                apply_fields.push(quote! {
+                    #member: ((self.#member as f64) + (delta.#delta_member as f64) * #precision) as #ty
+                });
+                quantize = quote! { None };
+                quote! { #quantized_ty }
+            }
+            DeltaKind::Full => {
+                from_fields.push(quote! {
+                    #delta_member: if self.#member != baseline.#member {
+                        changed = true;
+                        Some(::std::clone::Clone::clone(&self.#member))
+                    } else {
+                        None
+                    }
+                });
+                apply_fields.push(quote! {
+                    #member: match &delta.#delta_member {
+                        Some(value) => ::std::clone::Clone::clone(value),
+                        None => ::std::clone::Clone::clone(&self.#member),
+                    }
+                });
+                quote! { Option<#ty> }
+            }
+            DeltaKind::Constant => {
+                from_checks.push(quote! {
+                    if self.#member != baseline.#member {
+                        return Ok(::netcarrier::DeltaOutcome::Full);
+                    }
+                });
+                apply_fields.push(quote! { #member: ::std::clone::Clone::clone(&self.#member) });
+                continue;
+            }
+        };
+
+        delta_members.push((delta_member, quantize));
+        delta_fields.push(match &field.ident {
+            Some(ident) => quote! { #field_vis #ident: #delta_ty },
+            None => quote! { #field_vis #delta_ty },
+        });
+    }
+
+    let delta_struct = if named {
+        quote! { #vis struct #delta_name { #(#delta_fields,)* } }
+    } else {
+        quote! { #vis struct #delta_name(#(#delta_fields,)*); }
+    };
+
+    let delta_net_serialize = net_serialize::impl_net_serialize(&delta_name, &syn::Generics::default(), &delta_members);
+
+    Ok(quote! {
+        #[derive(::netcarrier::serde::Serialize, ::netcarrier::serde::Deserialize, PartialEq, Debug, Clone)]
+        #delta_struct
+
+        #delta_net_serialize
+
+        impl ::netcarrier::Delta for #name {
+            type DeltaType = #delta_name;
+
+            #[allow(unused_variables, unused_mut)]
+            fn from(&self, baseline: &Self) -> Result<::netcarrier::DeltaOutcome<Self::DeltaType>, ::netcarrier::DeltaError> {
+                #(#from_checks)*
+
+                let mut changed = false;
+                let delta = #delta_name {
+                    #(#from_fields,)*
+                };
+                if changed {
+                    Ok(::netcarrier::DeltaOutcome::Delta(delta))
+                } else {
+                    Ok(::netcarrier::DeltaOutcome::Unchanged)
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn apply(&self, delta: &Self::DeltaType) -> Self {
+                #name {
+                    #(#apply_fields,)*
+                }
+            }
+        }
+    })
+}
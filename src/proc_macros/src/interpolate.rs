@@ -0,0 +1,55 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{DeriveInput, Meta, NestedMeta};
+
+// The field keeps the value of the earlier state instead of being blended
+fn is_snap(field: &syn::Field) -> syn::Result<bool> {
+    let mut snap = false;
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("interpolate")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[interpolate(snap)]")),
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("snap") => snap = true,
+                nested => return Err(syn::Error::new_spanned(nested, "unknown interpolate attribute, expected `snap`")),
+            }
+        }
+    }
+    Ok(snap)
+}
+
+pub fn impl_interpolate_derive(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let fields = match &ast.data {
+        syn::Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(ast, "Interpolate can only be derived for structs")),
+    };
+
+    let mut interpolated_fields = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        };
+        interpolated_fields.push(if is_snap(field)? {
+            quote! { #member: ::std::clone::Clone::clone(&self.#member) }
+        } else {
+            quote! { #member: ::netcarrier::Interpolate::interpolate(&self.#member, &next.#member, t) }
+        });
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::netcarrier::Interpolate for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn interpolate(&self, next: &Self, t: f32) -> Self {
+                #name {
+                    #(#interpolated_fields,)*
+                }
+            }
+        }
+    })
+}
@@ -5,33 +5,44 @@ use quote::quote;
 use syn::{parse_macro_input, DeriveInput};
 use proc_macro2;
 
+mod delta;
+mod interpolate;
+mod net_serialize;
+
 fn impl_network_delta(fields: &syn::punctuated::Punctuated<syn::Field, syn::token::Comma>) -> proc_macro2::TokenStream {
     let get_delta_bitmask = fields.iter().map(|f| {
         let name = f.ident.as_ref().unwrap();
         let delta_name = syn::Ident::new(&format!("delta_{}", name), name.span()); 
+        let removed_name = syn::Ident::new(&format!("removed_{}", name), name.span()); 
 
         quote! {
-            let (#name, #delta_name) = self.#name.get_delta_bitmask(&self.entities_id, &snapshot.#name, &snapshot.entities_id);
+            let (#name, #delta_name) = self
+                .#name
+                .reorder(&self.entities_id, &entities_id)?
+                .get_delta_bitmask(&entities_id, &snapshot.#name, &snapshot.entities_id)?;
+            let #removed_name = self.#name.removed_entities_id(&self.entities_id, &snapshot.#name, &snapshot.entities_id);
         }
     });
 
     let fields_delta_name = fields.iter().map(|f| {
         let name = f.ident.as_ref().unwrap();
         let delta_name = syn::Ident::new(&format!("delta_{}", name), name.span()); 
+        let removed_name = syn::Ident::new(&format!("removed_{}", name), name.span()); 
 
         quote! {
             #name,
             #delta_name,
+            #removed_name,
         }
     });
 
     let apply_delta_bitmask = fields.iter().map(|f| {
         let name = f.ident.as_ref().unwrap();
         let delta_name = syn::Ident::new(&format!("delta_{}", name), name.span()); 
+        let removed_name = syn::Ident::new(&format!("removed_{}", name), name.span()); 
 
         quote! {
-            let mut #name = self.#name.apply_delta_bitmask(&self.entities_id, &delta.#delta_name, &delta.entities_id);
-            #name.join(&delta.#name);
+            let #name = self.#name.apply_delta_bitmask(&self.entities_id, &delta.#name, &delta.#delta_name, &delta.#removed_name, &entities_id);
         }
     });
 
@@ -45,23 +56,29 @@ fn impl_network_delta(fields: &syn::punctuated::Punctuated<syn::Field, syn::toke
         impl ::netcarrier::Delta for NetworkPacket {
             type DeltaType = NetworkDeltaPacket;
 
-            fn from(&self, snapshot: &Self) -> Option<Self::DeltaType> {
+            fn from(&self, snapshot: &Self) -> Result<::netcarrier::DeltaOutcome<Self::DeltaType>, ::netcarrier::DeltaError> {
+                let (spawned_entities_id, despawned_entities_id) = ::netcarrier::entities_diff(&snapshot.entities_id, &self.entities_id);
+                // The masks follow the order the client rebuilds
+                let entities_id = ::netcarrier::rebuild_entities_id(&snapshot.entities_id, &spawned_entities_id, &despawned_entities_id);
                 #(#get_delta_bitmask)*
 
-                Some(NetworkDeltaPacket {
+                // Always a delta, even without changes the client needs to know about the frame
+                Ok(::netcarrier::DeltaOutcome::Delta(NetworkDeltaPacket {
                     frame: self.frame(),
                     snapshot_frame: snapshot.frame(),
-                    entities_id: self.entities_id.clone(),
+                    spawned_entities_id,
+                    despawned_entities_id,
                     #(#fields_delta_name)*
-                })
+                }))
             }
 
             fn apply(&self, delta: &Self::DeltaType) -> Self {
+                let entities_id = ::netcarrier::rebuild_entities_id(&self.entities_id, &delta.spawned_entities_id, &delta.despawned_entities_id);
                 #(#apply_delta_bitmask)*
 
                 NetworkPacket {
                     frame: delta.frame,
-                    entities_id: delta.entities_id.clone(),
+                    entities_id,
                     #(#fields_name,)*
                 }
             }
@@ -71,6 +88,80 @@ fn impl_network_delta(fields: &syn::punctuated::Punctuated<syn::Field, syn::toke
     expanded
 }
 
+fn impl_packet_net_serialize(fields: &syn::punctuated::Punctuated<syn::Field, syn::token::Comma>, quantizes: &[proc_macro2::TokenStream]) -> proc_macro2::TokenStream {
+    let names: Vec<&syn::Ident> = fields.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+    let delta_names: Vec<syn::Ident> = names.iter().map(|name| syn::Ident::new(&format!("delta_{}", name), name.span())).collect();
+    let removed_names: Vec<syn::Ident> = names.iter().map(|name| syn::Ident::new(&format!("removed_{}", name), name.span())).collect();
+
+    quote! {
+        impl ::netcarrier::wire::NetSerialize for NetworkPacket {
+            fn net_serialize(&self, writer: &mut ::netcarrier::wire::BitWriter, _quantize: Option<::netcarrier::wire::Quantize>) {
+                writer.write_varint(self.frame as u64);
+                ::netcarrier::wire::write_ids(writer, &self.entities_id);
+                #(::netcarrier::wire::NetSerialize::net_serialize(&self.#names, writer, #quantizes);)*
+            }
+
+            fn net_deserialize(
+                reader: &mut ::netcarrier::wire::BitReader,
+                _quantize: Option<::netcarrier::wire::Quantize>,
+            ) -> Result<Self, ::netcarrier::wire::WireError> {
+                let frame = ::netcarrier::wire::NetSerialize::net_deserialize(reader, None)?;
+                let entities_id = ::netcarrier::wire::read_ids(reader)?;
+                Ok(NetworkPacket {
+                    frame,
+                    #(#names: ::netcarrier::NetworkBitmask::net_deserialize_for(reader, Some(entities_id.len()), #quantizes)?,)*
+                    entities_id,
+                })
+            }
+        }
+
+        impl ::netcarrier::wire::NetSerialize for NetworkDeltaPacket {
+            fn net_serialize(&self, writer: &mut ::netcarrier::wire::BitWriter, _quantize: Option<::netcarrier::wire::Quantize>) {
+                writer.write_varint(self.frame as u64);
+                // The snapshot is a few frames old
+                writer.write_varint(self.frame.wrapping_sub(self.snapshot_frame) as u64);
+                ::netcarrier::wire::write_ids(writer, &self.spawned_entities_id);
+                ::netcarrier::wire::write_ids(writer, &self.despawned_entities_id);
+                #(
+                    ::netcarrier::wire::NetSerialize::net_serialize(&self.#names, writer, #quantizes);
+                    ::netcarrier::wire::NetSerialize::net_serialize(&self.#delta_names, writer, #quantizes);
+                    ::netcarrier::wire::write_ids(writer, &self.#removed_names);
+                )*
+            }
+
+            fn net_deserialize(
+                reader: &mut ::netcarrier::wire::BitReader,
+                _quantize: Option<::netcarrier::wire::Quantize>,
+            ) -> Result<Self, ::netcarrier::wire::WireError> {
+                let frame: u32 = ::netcarrier::wire::NetSerialize::net_deserialize(reader, None)?;
+                let snapshot_frame = frame.wrapping_sub(::netcarrier::wire::NetSerialize::net_deserialize(reader, None)?);
+                let spawned_entities_id = ::netcarrier::wire::read_ids(reader)?;
+                let despawned_entities_id = ::netcarrier::wire::read_ids(reader)?;
+                // The entities are only known with the baseline, every mask must be as long as the first
+                let mut entities: Option<usize> = None;
+                #(
+                    let #names = ::netcarrier::NetworkBitmask::net_deserialize_for(reader, entities, #quantizes)?;
+                    entities = Some(#names.entities_mask.len());
+                    let #delta_names = ::netcarrier::NetworkBitmask::net_deserialize_for(reader, entities, #quantizes)?;
+                    let #removed_names = ::netcarrier::wire::read_ids(reader)?;
+                )*
+                Ok(NetworkDeltaPacket {
+                    frame,
+                    snapshot_frame,
+                    spawned_entities_id,
+                    despawned_entities_id,
+                    #(#names, #delta_names, #removed_names,)*
+                })
+            }
+        }
+    }
+}
+
+// FNV-1a, stable across builds and platforms unlike the std hashers
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
 #[proc_macro]
 pub fn generate_packet(input: TokenStream) -> TokenStream {
     // println!("{:#?}", input);
@@ -87,6 +178,19 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
         unimplemented!();
     };
 
+    // Fields marked #[net(min = .., max = .., precision = ..)] send their floats quantized
+    let quantizes = match fields
+        .iter()
+        .map(|f| net_serialize::quantize(&f.attrs).map(|quantize| match quantize {
+            Some(quantize) => quote! { Some(#quantize) },
+            None => quote! { None },
+        }))
+        .collect::<syn::Result<Vec<_>>>()
+    {
+        Ok(quantizes) => quantizes,
+        Err(err) => return err.to_compile_error().into(),
+    };
+
     let fields_type = fields.iter().map(|f| {
         let name = &f.ident;
         let ty = &f.ty;
@@ -99,8 +203,12 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
         let name = f.ident.as_ref().unwrap();
         let delta_name = format!("delta_{}", name);
         let delta_ident = syn::Ident::new(&delta_name, name.span()); 
+        let removed_ident = syn::Ident::new(&format!("removed_{}", name), name.span()); 
         let ty = &f.ty;
-        quote! { #delta_ident: ::netcarrier::NetworkBitmask<<#ty as ::netcarrier::Delta>::DeltaType> }
+        quote! {
+            #delta_ident: ::netcarrier::NetworkBitmask<<#ty as ::netcarrier::Delta>::DeltaType>,
+            #removed_ident: Vec<u32>
+        }
     });
 
     let fields_initialized = fields.iter().map(|f| {
@@ -127,10 +235,102 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
 					}
 				}
 			}
+			// The entity is still there without this component
+			let masked_entities_ids: ::std::collections::HashSet<u32> = masked_entities_ids.into_iter().collect();
+			for net_id in &self.entities_id {
+				if let Some(&id) = net_id_mapping.0.get(net_id) {
+					if #name.contains(id) && !masked_entities_ids.contains(net_id) {
+						#name.remove(id);
+					}
+				}
+			}
 		}}
     });
     
+    // Components marked #[interpolate] are blended, the others are taken from the earlier state
+    let field_interpolate_state = fields.iter().map(|f| {
+        let name = &f.ident;
+        if f.attrs.iter().any(|attr| attr.path.is_ident("interpolate")) {
+            quote! { #name: self.#name.interpolate(&self.entities_id, &next.#name, &next.entities_id, t) }
+        } else {
+            quote! { #name: self.#name.clone() }
+        }
+    });
+
+    let field_getters = fields.iter().map(|f| {
+        let name = &f.ident;
+        let ty = &f.ty;
+
+        quote! {
+            pub fn #name(&self, net_id: u32) -> Option<&#ty> {
+                self.#name.get(&self.entities_id, net_id)
+            }
+        }
+    });
+
+    let field_retain = fields.iter().map(|f| {
+        let name = f.ident.as_ref().unwrap();
+        let retain_name = syn::Ident::new(&format!("retain_{}", name), name.span());
+
+        quote! {
+            /// Keeps this component only on the entities `keep` returns true for.
+            pub fn #retain_name<F: FnMut(u32) -> bool>(&mut self, keep: F) {
+                self.#name.retain(&self.entities_id, keep);
+            }
+        }
+    });
+
+    let field_select = fields.iter().map(|f| {
+        let name = &f.ident;
+        quote! { #name: self.#name.select(&kept) }
+    });
+
+    let field_merge = fields.iter().map(|f| {
+        let name = &f.ident;
+        quote! { #name: self.#name.merge(&self.entities_id, &stale, &previous.#name, &previous.entities_id).select(&kept) }
+    });
+
+    let field_extend = fields.iter().map(|f| {
+        let name = &f.ident;
+        quote! {
+            #name: self
+                .#name
+                .extend(&self.entities_id, &other.#name, &other.entities_id)
+                .reorder(&extended_entities_id, &entities_id)
+                .expect("The extended components should match the extended entities.")
+        }
+    });
+
+    let field_bits = fields.iter().zip(&quantizes).map(|(f, quantize)| {
+        let name = &f.ident;
+        quote! { self.#name.component_bits(&self.entities_id, id, #quantize) }
+    });
+
+    let field_components_bits = fields.iter().zip(&quantizes).map(|(f, quantize)| {
+        let name = &f.ident;
+        quote! { self.#name.components_bits(#quantize) }
+    });
+
+    let validated_names: Vec<&syn::Ident> = fields.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+    let validated_delta_names: Vec<syn::Ident> = validated_names
+        .iter()
+        .map(|name| syn::Ident::new(&format!("delta_{}", name), name.span()))
+        .collect();
+
+    // Both ends of a connection must have the same fields, types and quantization
+    let schema: String = fields
+        .iter()
+        .zip(&quantizes)
+        .map(|(f, quantize)| {
+            let name = &f.ident;
+            let ty = &f.ty;
+            quote! { #name: #ty = #quantize; }.to_string()
+        })
+        .collect();
+    let schema_hash = fnv1a(schema.as_bytes());
+
     let impl_network_delta = impl_network_delta(fields);
+    let impl_packet_net_serialize = impl_packet_net_serialize(fields, &quantizes);
 
     let expanded = quote! {
         use ::netcarrier::shipyard::*;
@@ -147,12 +347,21 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
         pub struct NetworkDeltaPacket {
             frame: u32,
             snapshot_frame: u32,
-            entities_id: Vec<u32>,
+            spawned_entities_id: Vec<u32>,
+            despawned_entities_id: Vec<u32>,
             #(#fields_type_clone,)*
             #(#delta_fields_type,)*
         }
 
+        impl NetworkPacket {
+            #(#field_getters)*
+
+            #(#field_retain)*
+        }
+
         impl ::netcarrier::CarrierPacket for NetworkPacket {
+            const SCHEMA_HASH: u64 = #schema_hash;
+
             fn frame(&self) -> u32 {
                 self.frame
             }
@@ -164,6 +373,8 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
 						entities_id.push(net_id.id);
 					}
 				});
+				// In id order like the states rebuilt from deltas, not in storage order
+				entities_id.sort_unstable();
 				
 				NetworkPacket {
                     frame,
@@ -178,18 +389,18 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
 					{
 						let mut entities = all_storages.borrow::<::netcarrier::shipyard::EntitiesViewMut>();
 						let mut net_id_mapping = all_storages.borrow::<::netcarrier::shipyard::UniqueViewMut<::netcarrier::transport::NetworkIdMapping>>();
+						let known: Vec<u32> = net_id_mapping.0.keys().copied().collect();
+						let (spawned, despawned) = ::netcarrier::entities_diff(&known, &self.entities_id);
 						// Create new ids
-						for entity_id in &self.entities_id {
-							if !net_id_mapping.0.contains_key(&entity_id) {
-								let entity = entities.add_entity((), ());
-								net_id_mapping.0.insert(*entity_id, entity);
-							}
+						for net_id in spawned {
+							let entity = entities.add_entity((), ());
+							net_id_mapping.0.insert(net_id, entity);
 						}
 
-						//Remove entities
-						for net_id in net_id_mapping.0.keys() {
-							if !self.entities_id.contains(net_id) {
-								removed_entities.push(net_id_mapping.0[net_id]);
+						//Remove entities, they get a new entity if they come back
+						for net_id in despawned {
+							if let Some(entity) = net_id_mapping.0.remove(&net_id) {
+								removed_entities.push(entity);
 							}
 						}
 
@@ -200,6 +411,77 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
 					}
 				});
             }
+
+            #[allow(unused_variables)]
+            fn interpolate_state(&self, next: &Self, t: f32) -> Self {
+                NetworkPacket {
+                    frame: self.frame,
+                    entities_id: self.entities_id.clone(),
+                    #(#field_interpolate_state,)*
+                }
+            }
+
+            fn retain_entities<F: FnMut(u32) -> bool>(&self, mut keep: F) -> Self {
+                let kept: Vec<bool> = self.entities_id.iter().map(|&id| keep(id)).collect();
+                NetworkPacket {
+                    frame: self.frame,
+                    entities_id: self.entities_id.iter().zip(&kept).filter(|(_, &keep)| keep).map(|(&id, _)| id).collect(),
+                    #(#field_select,)*
+                }
+            }
+
+            fn with_stale_entities<F: FnMut(u32) -> bool>(&self, previous: &Self, mut stale: F) -> Self {
+                let stale: Vec<bool> = self.entities_id.iter().map(|&id| stale(id)).collect();
+                let previous_ids: ::std::collections::HashSet<u32> = previous.entities_id.iter().copied().collect();
+                let kept: Vec<bool> = self
+                    .entities_id
+                    .iter()
+                    .zip(&stale)
+                    .map(|(id, &stale)| !stale || previous_ids.contains(id))
+                    .collect();
+                NetworkPacket {
+                    frame: self.frame,
+                    entities_id: self.entities_id.iter().zip(&kept).filter(|(_, &keep)| keep).map(|(&id, _)| id).collect(),
+                    #(#field_merge,)*
+                }
+            }
+
+            fn extend_entities(&self, other: &Self) -> Self {
+                let ids: ::std::collections::HashSet<u32> = self.entities_id.iter().copied().collect();
+                let mut extended_entities_id = self.entities_id.clone();
+                extended_entities_id.extend(other.entities_id.iter().filter(|id| !ids.contains(id)));
+                let mut entities_id = extended_entities_id.clone();
+                entities_id.sort_unstable();
+                NetworkPacket {
+                    frame: self.frame,
+                    #(#field_extend,)*
+                    entities_id,
+                }
+            }
+
+            fn entities_id(&self) -> &[u32] {
+                &self.entities_id
+            }
+
+            fn entity_size(&self, id: u32) -> u64 {
+                // The id in entities_id, in full
+                (32u64 #(+ #field_bits)*).div_ceil(8)
+            }
+
+            fn entity_sizes(&self) -> Vec<u64> {
+                let mut bits = vec![32u64; self.entities_id.len()];
+                #(for (bits, component_bits) in bits.iter_mut().zip(#field_components_bits) {
+                    *bits += component_bits;
+                })*
+                bits.into_iter().map(|bits| bits.div_ceil(8)).collect()
+            }
+
+            fn validate_delta(&self, delta: &NetworkDeltaPacket) -> Result<(), ::netcarrier::DeltaError> {
+                ::netcarrier::validate_spawned_entities_id(&self.entities_id, &delta.spawned_entities_id)?;
+                let entities_id = ::netcarrier::rebuild_entities_id(&self.entities_id, &delta.spawned_entities_id, &delta.despawned_entities_id);
+                #(self.#validated_names.validate_delta_bitmask(&self.entities_id, &delta.#validated_names, &delta.#validated_delta_names, &entities_id)?;)*
+                Ok(())
+            }
         }
 
         impl ::netcarrier::CarrierDeltaPacket for NetworkDeltaPacket {
@@ -214,7 +496,43 @@ pub fn generate_packet(input: TokenStream) -> TokenStream {
 
         #impl_network_delta
 
+        #impl_packet_net_serialize
     };
     expanded.into()
 }
 
+/// Derives `netcarrier::Delta` field by field, generating a `<Name>Delta` struct as the `DeltaType`.
+///
+/// Fields without attributes must implement `Delta` themselves, otherwise:
+/// - `#[delta(quantize = "i8", precision = 0.1)]`: numeric difference stored as an `i8` multiple of
+///   `precision` (defaults to 1), a difference that does not fit sends the full component.
+/// - `#[delta(full)]`: the whole value is sent only when it changed.
+/// - `#[delta(constant)]`: the value is never sent, a change sends the full component.
+#[proc_macro_derive(Delta, attributes(delta, net))]
+pub fn derive_delta(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    delta::impl_delta_derive(&ast)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `netcarrier::Interpolate` by interpolating every field.
+/// Fields marked `#[interpolate(snap)]` keep the value of the earlier state instead.
+#[proc_macro_derive(Interpolate, attributes(interpolate))]
+pub fn derive_interpolate(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    interpolate::impl_interpolate_derive(&ast)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Derives `netcarrier::wire::NetSerialize`, serializing every field in order.
+/// Floats inside fields marked `#[net(min = .., max = .., precision = ..)]` are sent as steps of
+/// `precision` between `min` and `max`, the others use the quantization the struct is sent with.
+#[proc_macro_derive(NetSerialize, attributes(net))]
+pub fn derive_net_serialize(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    net_serialize::impl_net_serialize_derive(&ast)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
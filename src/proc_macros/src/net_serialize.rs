@@ -0,0 +1,114 @@
+use proc_macro2::{Literal, TokenStream};
+use quote::quote;
+use syn::{DeriveInput, Lit, Meta, NestedMeta};
+
+fn number(lit: &Lit) -> syn::Result<f64> {
+    match lit {
+        Lit::Float(float) => float.base10_parse::<f64>(),
+        Lit::Int(int) => int.base10_parse::<f64>(),
+        lit => Err(syn::Error::new_spanned(lit, "expected a number")),
+    }
+}
+
+/// `Quantize` given by the `#[net(min = .., max = .., precision = ..)]` attribute of the field.
+pub fn quantize(attrs: &[syn::Attribute]) -> syn::Result<Option<TokenStream>> {
+    let mut quantize = None;
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("net")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[net(min = .., max = .., precision = ..)]")),
+        };
+        let (mut min, mut max, mut precision) = (None, None, None);
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("min") => min = Some(number(&value.lit)?),
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("max") => max = Some(number(&value.lit)?),
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("precision") => {
+                    precision = Some(number(&value.lit)?)
+                }
+                nested => {
+                    return Err(syn::Error::new_spanned(
+                        nested,
+                        "unknown net attribute, expected `min = ..`, `max = ..` or `precision = ..`",
+                    ))
+                }
+            }
+        }
+        let (min, max, precision) = match (min, max, precision) {
+            (Some(min), Some(max), Some(precision)) if min < max && precision > 0.0 => (min, max, precision),
+            (Some(_), Some(_), Some(_)) => {
+                return Err(syn::Error::new_spanned(attr, "expected min < max and a positive precision"))
+            }
+            _ => return Err(syn::Error::new_spanned(attr, "expected min, max and precision")),
+        };
+        let (min, max, precision) = (
+            Literal::f64_unsuffixed(min),
+            Literal::f64_unsuffixed(max),
+            Literal::f64_unsuffixed(precision),
+        );
+        quantize = Some(quote! {
+            ::netcarrier::wire::Quantize { min: #min, max: #max, precision: #precision }
+        });
+    }
+    Ok(quantize)
+}
+
+/// Quantization the field is serialized with: its own, or the one the struct is serialized with.
+pub fn field_quantize(attrs: &[syn::Attribute]) -> syn::Result<TokenStream> {
+    Ok(match quantize(attrs)? {
+        Some(quantize) => quote! { Some(#quantize) },
+        None => quote! { quantize },
+    })
+}
+
+/// `NetSerialize` for a struct, the fields in order with their quantization.
+pub fn impl_net_serialize(name: &syn::Ident, generics: &syn::Generics, fields: &[(syn::Member, TokenStream)]) -> TokenStream {
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let serialize = fields.iter().map(|(member, quantize)| {
+        quote! { ::netcarrier::wire::NetSerialize::net_serialize(&self.#member, writer, #quantize); }
+    });
+    let deserialize = fields.iter().map(|(member, quantize)| {
+        quote! { #member: ::netcarrier::wire::NetSerialize::net_deserialize(reader, #quantize)? }
+    });
+
+    quote! {
+        impl #impl_generics ::netcarrier::wire::NetSerialize for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn net_serialize(&self, writer: &mut ::netcarrier::wire::BitWriter, quantize: Option<::netcarrier::wire::Quantize>) {
+                #(#serialize)*
+            }
+
+            #[allow(unused_variables)]
+            fn net_deserialize(
+                reader: &mut ::netcarrier::wire::BitReader,
+                quantize: Option<::netcarrier::wire::Quantize>,
+            ) -> Result<Self, ::netcarrier::wire::WireError> {
+                Ok(#name {
+                    #(#deserialize,)*
+                })
+            }
+        }
+    }
+}
+
+pub fn impl_net_serialize_derive(ast: &DeriveInput) -> syn::Result<TokenStream> {
+    let fields = match &ast.data {
+        syn::Data::Struct(data) => &data.fields,
+        _ => return Err(syn::Error::new_spanned(ast, "NetSerialize can only be derived for structs")),
+    };
+
+    let mut members = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        };
+        members.push((member, field_quantize(&field.attrs)?));
+    }
+    // Every type parameter is serialized
+    let mut generics = ast.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!(::netcarrier::wire::NetSerialize));
+    }
+    Ok(impl_net_serialize(&ast.ident, &generics, &members))
+}
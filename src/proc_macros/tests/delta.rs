@@ -0,0 +1,122 @@
+use netcarrier::{Delta, DeltaOutcome};
+
+#[derive(Delta, Clone, Copy, Debug, PartialEq)]
+struct Position {
+    #[delta(quantize = "i8")]
+    x: f32,
+    #[delta(quantize = "i16", precision = 0.5)]
+    y: f32,
+}
+
+#[derive(Delta, Clone, Debug, PartialEq)]
+struct Player {
+    position: Position,
+    #[delta(full)]
+    name: String,
+    #[delta(constant)]
+    team: u8,
+}
+
+#[derive(Delta, Clone, Copy, Debug, PartialEq)]
+struct Color(#[delta(full)] [f32; 4]);
+
+#[derive(Delta, Clone, Copy, Debug, PartialEq)]
+struct Rectangle {
+    #[delta(constant)]
+    width: f32,
+    #[delta(constant)]
+    height: f32,
+}
+
+fn round_trip<T: Delta + PartialEq + std::fmt::Debug>(baseline: &T, current: &T) {
+    match current.from(baseline).unwrap() {
+        DeltaOutcome::Delta(delta) => assert_eq!(&baseline.apply(&delta), current),
+        DeltaOutcome::Unchanged => assert_eq!(baseline, current),
+        DeltaOutcome::Full => panic!("Delta should be available"),
+    }
+}
+
+fn delta<T: Delta>(baseline: &T, current: &T) -> T::DeltaType {
+    match current.from(baseline).unwrap() {
+        DeltaOutcome::Delta(delta) => delta,
+        _ => panic!("Delta should be available"),
+    }
+}
+
+#[test]
+fn quantized_round_trip() {
+    let baseline = Position { x: 100.0, y: 100.0 };
+    let current = Position { x: 90.0, y: 112.5 };
+    round_trip(&baseline, &current);
+
+    assert_eq!(delta(&baseline, &current), PositionDelta { x: -10, y: 25 });
+}
+
+#[test]
+fn quantized_rounds_to_precision() {
+    let baseline = Position { x: 0.0, y: 0.0 };
+    let current = Position { x: 1.4, y: 0.8 };
+    let delta = delta(&baseline, &current);
+    assert_eq!(baseline.apply(&delta), Position { x: 1.0, y: 1.0 });
+}
+
+#[test]
+fn quantized_out_of_range() {
+    let baseline = Position { x: 0.0, y: 0.0 };
+    let current = Position { x: 200.0, y: 0.0 };
+    assert_eq!(current.from(&baseline), Ok(DeltaOutcome::Full));
+}
+
+#[test]
+fn quantized_unchanged() {
+    let baseline = Position { x: 10.0, y: 10.0 };
+    let current = Position { x: 10.2, y: 10.0 };
+    assert_eq!(current.from(&baseline), Ok(DeltaOutcome::Unchanged));
+}
+
+#[test]
+fn nested_round_trip() {
+    let baseline = Player {
+        position: Position { x: 0.0, y: 0.0 },
+        name: "player".to_string(),
+        team: 1,
+    };
+    let mut current = baseline.clone();
+    current.position.x = 12.0;
+    round_trip(&baseline, &current);
+
+    current.name = "renamed".to_string();
+    round_trip(&baseline, &current);
+    assert_eq!(delta(&baseline, &current).name, Some("renamed".to_string()));
+}
+
+#[test]
+fn nested_out_of_range() {
+    let baseline = Player {
+        position: Position { x: 0.0, y: 0.0 },
+        name: "player".to_string(),
+        team: 1,
+    };
+    let mut current = baseline.clone();
+    current.position.x = -500.0;
+    assert_eq!(current.from(&baseline), Ok(DeltaOutcome::Full));
+}
+
+#[test]
+fn full_only_when_changed() {
+    let baseline = Color([0.0, 0.0, 0.0, 1.0]);
+    assert_eq!(baseline.from(&baseline), Ok(DeltaOutcome::Unchanged));
+
+    let current = Color([1.0, 0.0, 0.0, 1.0]);
+    assert_eq!(delta(&baseline, &current), ColorDelta(Some([1.0, 0.0, 0.0, 1.0])));
+    round_trip(&baseline, &current);
+}
+
+#[test]
+fn constant_changed() {
+    let baseline = Rectangle { width: 20.0, height: 20.0 };
+    assert_eq!(baseline.from(&baseline), Ok(DeltaOutcome::Unchanged));
+
+    let current = Rectangle { width: 40.0, height: 20.0 };
+    assert_eq!(current.from(&baseline), Ok(DeltaOutcome::Full));
+}
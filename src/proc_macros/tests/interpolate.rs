@@ -0,0 +1,47 @@
+use netcarrier::Interpolate;
+
+#[derive(Interpolate, Clone, Copy, Debug, PartialEq)]
+struct Position {
+    x: f32,
+    y: f64,
+}
+
+#[derive(Interpolate, Clone, Debug, PartialEq)]
+struct Sprite {
+    position: Position,
+    color: [f32; 4],
+    #[interpolate(snap)]
+    name: String,
+}
+
+#[derive(Interpolate, Clone, Copy, Debug, PartialEq)]
+struct Angle(f32);
+
+#[test]
+fn blends_fields() {
+    let from = Position { x: 0.0, y: 10.0 };
+    let next = Position { x: 10.0, y: 20.0 };
+    assert_eq!(from.interpolate(&next, 0.0), from);
+    assert_eq!(from.interpolate(&next, 0.25), Position { x: 2.5, y: 12.5 });
+    assert_eq!(from.interpolate(&next, 1.0), next);
+
+    assert_eq!(Angle(1.0).interpolate(&Angle(2.0), 0.5), Angle(1.5));
+}
+
+#[test]
+fn nested_and_snapped_fields() {
+    let from = Sprite {
+        position: Position { x: 0.0, y: 0.0 },
+        color: [0.0, 0.0, 0.0, 1.0],
+        name: "from".to_string(),
+    };
+    let next = Sprite {
+        position: Position { x: 4.0, y: 4.0 },
+        color: [1.0, 1.0, 1.0, 1.0],
+        name: "next".to_string(),
+    };
+    let sprite = from.interpolate(&next, 0.5);
+    assert_eq!(sprite.position, Position { x: 2.0, y: 2.0 });
+    assert_eq!(sprite.color, [0.5, 0.5, 0.5, 1.0]);
+    assert_eq!(sprite.name, "from");
+}
@@ -0,0 +1,730 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use netcarrier::auth::{ConnectToken, ServerAuth};
+use netcarrier::jitter::{JitterBufferConfig, JitterDelay};
+use netcarrier::keepalive::ManualClock;
+use netcarrier::transport::loopback::{LoopbackConditions, LoopbackNetwork, LoopbackReceiver, LoopbackSender, LoopbackTransport};
+use netcarrier::transport::{
+    self, ClientConfig, ClientConnection, ClientGameSnapshots, ClientJitterBuffer, ClientUserData, ConnectionState, DeliveryRequirement,
+    DisconnectReason, EventList, Handshake,
+    Message, NetworkClient, NetworkEvent, NetworkSender, NetworkSerializable, ServerConfig, Transport, TransportError,
+    TransportSender, WholeWorld, PROTOCOL_VERSION,
+};
+use netcarrier::wire::{self, BitReader, BitWriter, Quantize, WireError};
+use netcarrier::{generate_packet, Delta, NetSerialize, NetworkController, NetworkIdentifier};
+use serde::{Deserialize, Serialize};
+
+#[derive(Delta, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    #[delta(quantize = "i16")]
+    pub x: f32,
+}
+
+thread_local! {
+    // Positions serialized by the thread, the server runs in the test's
+    static SERIALIZED: Cell<usize> = const { Cell::new(0) };
+}
+
+impl NetSerialize for Position {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        SERIALIZED.with(|serialized| serialized.set(serialized.get() + 1));
+        self.x.net_serialize(writer, quantize);
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        Ok(Position {
+            x: f32::net_deserialize(reader, quantize)?,
+        })
+    }
+}
+
+generate_packet!(struct State {
+    positions: Position,
+});
+
+// Built against another packet, the server rejects it
+mod other_schema {
+    use super::Position;
+    use netcarrier::generate_packet;
+
+    generate_packet!(struct State {
+        positions: Position,
+        targets: Position,
+    });
+}
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn addr(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+struct Server {
+    world: World,
+    frame: u32,
+    clients: HashMap<SocketAddr, EntityId>,
+}
+
+impl Server {
+    fn new(network: &LoopbackNetwork) -> Self {
+        Server::with_transport(network.bind(addr(5000)))
+    }
+
+    fn with_transport<N: Transport>(transport: N) -> Self {
+        let mut world = World::default();
+        transport::init_server_transport::<NetworkPacket, _>(&mut world, transport);
+        Server {
+            world,
+            frame: 0,
+            clients: HashMap::new(),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.tick_with(&mut WholeWorld::default());
+    }
+
+    // Spawns an entity for each client, moves them and sends the state
+    fn tick_with<S: NetworkSerializable<Packet = NetworkPacket>>(&mut self, serializable: &mut S) {
+        let events: Vec<NetworkEvent> = self.world.run(|events: UniqueView<EventList>| events.0.lock().unwrap().drain(..).collect());
+        for event in events {
+            match event {
+                NetworkEvent::Connect(addr) => {
+                    let entity = self.world.run(
+                        |mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>| {
+                            entities.add_entity((&mut net_ids, &mut positions), (NetworkIdentifier::default(), Position { x: 0.0 }))
+                        },
+                    );
+                    self.clients.insert(addr, entity);
+                }
+                NetworkEvent::Disconnect(addr) => {
+                    if let Some(entity) = self.clients.remove(&addr) {
+                        self.world.run(|mut all_storages: AllStoragesViewMut| {
+                            all_storages.delete(entity);
+                        });
+                    }
+                }
+                NetworkEvent::Message(..) => {}
+            }
+        }
+        self.world.run(|mut positions: ViewMut<Position>| {
+            for position in (&mut positions).iter() {
+                position.x += 1.0;
+            }
+        });
+
+        self.frame += 1;
+        transport::update_server_with(&mut self.world, self.frame, serializable).unwrap();
+    }
+
+    // Entities that don't belong to a client
+    fn spawn(&self, count: usize) {
+        self.world.run(|mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>| {
+            for i in 0..count {
+                entities.add_entity((&mut net_ids, &mut positions), (NetworkIdentifier::default(), Position { x: i as f32 }));
+            }
+        });
+    }
+
+    fn positions(&self) -> Vec<f32> {
+        positions(&self.world)
+    }
+}
+
+// Records the size of the datagrams it sends, and can lose the first one
+struct TappedTransport {
+    transport: LoopbackTransport,
+    lose_first: bool,
+    sent: Arc<Mutex<Vec<usize>>>,
+}
+
+impl TappedTransport {
+    fn new(transport: LoopbackTransport) -> Self {
+        TappedTransport {
+            transport,
+            lose_first: false,
+            sent: Arc::new(Mutex::new(vec![])),
+        }
+    }
+}
+
+struct TappedSender {
+    sender: LoopbackSender,
+    lose_next: AtomicBool,
+    sent: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Transport for TappedTransport {
+    type Sender = TappedSender;
+    type Receiver = LoopbackReceiver;
+
+    fn split(self) -> (TappedSender, LoopbackReceiver) {
+        let (sender, receiver) = self.transport.split();
+        let sender = TappedSender {
+            sender,
+            lose_next: AtomicBool::new(self.lose_first),
+            sent: self.sent,
+        };
+        (sender, receiver)
+    }
+}
+
+impl TransportSender for TappedSender {
+    fn send(&self, addr: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Result<(), TransportError> {
+        if self.lose_next.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.sent.lock().unwrap().push(payload.len());
+        self.sender.send(addr, payload, delivery)
+    }
+}
+
+// Clients only see the entities on even positions
+struct EvenPositions;
+
+impl NetworkSerializable for EvenPositions {
+    type Capture = NetworkPacket;
+    type Packet = NetworkPacket;
+
+    fn serialize(&mut self, world: &World, frame: u32) -> NetworkPacket {
+        NetworkPacket::new(world, frame)
+    }
+
+    fn select_for_client(&mut self, _client: &NetworkClient, capture: &NetworkPacket) -> NetworkPacket {
+        capture.retain_entities(|id| matches!(capture.positions(id), Some(position) if (position.x as u32 & 1) == 0))
+    }
+}
+
+struct FirstClientOnly;
+
+impl NetworkSerializable for FirstClientOnly {
+    type Capture = NetworkPacket;
+    type Packet = NetworkPacket;
+
+    fn serialize(&mut self, world: &World, frame: u32) -> NetworkPacket {
+        NetworkPacket::new(world, frame)
+    }
+
+    fn select_for_client(&mut self, client: &NetworkClient, capture: &NetworkPacket) -> NetworkPacket {
+        capture.retain_entities(|_| client.addr == addr(5001))
+    }
+}
+
+fn client(network: &LoopbackNetwork, port: u16) -> World {
+    let mut world = World::default();
+    let config = ClientConfig {
+        jitter_buffer: JitterBufferConfig {
+            delay: JitterDelay::Ticks(0),
+            min_delay_ticks: 0,
+            adaptive: false,
+            ..JitterBufferConfig::default()
+        },
+        ..ClientConfig::default()
+    };
+    transport::init_client_transport::<NetworkPacket, _>(&mut world, network.bind(addr(port)), addr(5000), config);
+    world
+}
+
+// Sends an input, which also acks the received states
+fn client_tick(world: &World) {
+    transport::send_input(world, &(), addr(5000));
+}
+
+// Applies the newest received state, the frame if there is one
+fn apply_latest(world: &World) -> Option<u32> {
+    let state = world.run(|snapshots: UniqueView<ClientGameSnapshots<NetworkPacket>>| {
+        snapshots.0.lock().unwrap().latest().cloned()
+    })?;
+    state.apply_state(world);
+    Some(state.frame())
+}
+
+fn positions(world: &World) -> Vec<f32> {
+    let mut positions: Vec<f32> = world.run(|positions: View<Position>| positions.iter().map(|position| position.x).collect());
+    positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    positions
+}
+
+fn tick(network: &LoopbackNetwork, server: &mut Server, clients: &[&World]) {
+    for client in clients {
+        client_tick(client);
+    }
+    assert!(network.wait_idle(TIMEOUT));
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+    network.step();
+    assert!(network.wait_idle(TIMEOUT));
+}
+
+#[test]
+fn replicates_to_clients() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let first = client(&network, 5001);
+    let second = client(&network, 5002);
+
+    for _ in 0..5 {
+        tick(&network, &mut server, &[&first, &second]);
+    }
+
+    assert_eq!(server.positions(), vec![5.0, 5.0]);
+    for client in &[&first, &second] {
+        assert_eq!(apply_latest(client), Some(server.frame));
+        assert_eq!(positions(client), server.positions());
+    }
+}
+
+#[test]
+fn delivers_after_latency() {
+    let network = LoopbackNetwork::with_conditions(LoopbackConditions {
+        latency: 2,
+        ..LoopbackConditions::default()
+    });
+    let mut server = Server::new(&network);
+    let client = client(&network, 5001);
+
+    client_tick(&client);
+    network.step();
+    network.step();
+    assert!(network.wait_idle(TIMEOUT));
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+    // The answer to the handshake and the snapshot
+    assert_eq!(network.in_flight(), 2);
+
+    network.step();
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(apply_latest(&client), None);
+    network.step();
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(apply_latest(&client), Some(server.frame));
+    assert_eq!(positions(&client), vec![1.0]);
+}
+
+#[test]
+fn converges_over_bad_link() {
+    let network = LoopbackNetwork::with_conditions(LoopbackConditions {
+        latency: 1,
+        loss: 0.3,
+        duplication: 0.2,
+        reordering: 0.3,
+        seed: 7,
+    });
+    let mut server = Server::new(&network);
+    let client = client(&network, 5001);
+
+    for _ in 0..40 {
+        tick(&network, &mut server, &[&client]);
+    }
+    assert!(apply_latest(&client).is_some());
+
+    network.set_conditions(LoopbackConditions::default());
+    for _ in 0..3 {
+        tick(&network, &mut server, &[&client]);
+    }
+    assert_eq!(apply_latest(&client), Some(server.frame));
+    assert_eq!(positions(&client), server.positions());
+}
+
+#[test]
+fn disconnected_client_is_removed() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let staying = client(&network, 5001);
+    let leaving = client(&network, 5002);
+    tick(&network, &mut server, &[&staying, &leaving]);
+    apply_latest(&staying);
+    assert_eq!(positions(&staying).len(), 2);
+
+    network.disconnect(addr(5002));
+    assert!(network.wait_idle(TIMEOUT));
+    tick(&network, &mut server, &[&staying]);
+    apply_latest(&staying);
+    assert_eq!(positions(&staying), vec![2.0]);
+}
+
+#[test]
+fn client_tracks_its_connection() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let reconnecting = client(&network, 5001);
+    let mut staying_out = World::default();
+    let config = ClientConfig {
+        reconnect: false,
+        ..ClientConfig::default()
+    };
+    transport::init_client_transport::<NetworkPacket, _>(&mut staying_out, network.bind(addr(5002)), addr(5000), config);
+
+    tick(&network, &mut server, &[&reconnecting, &staying_out]);
+    for client in &[&reconnecting, &staying_out] {
+        assert_eq!(connection(client), ConnectionState::Connected);
+        assert_eq!(events(client), vec![NetworkEvent::Connect(addr(5000))]);
+    }
+
+    network.disconnect(addr(5000));
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(connection(&reconnecting), ConnectionState::Reconnecting);
+    assert_eq!(connection(&staying_out), ConnectionState::Disconnected(DisconnectReason::TimedOut));
+    for client in &[&reconnecting, &staying_out] {
+        assert_eq!(events(client), vec![NetworkEvent::Disconnect(addr(5000))]);
+        assert_eq!(apply_latest(client), None);
+        assert!(client.run(|jit_buffer: UniqueView<ClientJitterBuffer<NetworkPacket>>| jit_buffer.0.lock().unwrap().is_empty()));
+    }
+}
+
+#[test]
+fn handshake_sent_again_until_answered() {
+    let network = LoopbackNetwork::new();
+    // The server's first packet is its handshake reply
+    let mut server = Server::with_transport(TappedTransport {
+        lose_first: true,
+        ..TappedTransport::new(network.bind(addr(5000)))
+    });
+    let client = client(&network, 5001);
+    let clock = ManualClock::new();
+    client.run(|network: UniqueView<NetworkSender>| network.set_clock(clock.clone()));
+    tick(&network, &mut server, &[&client]);
+    assert_eq!(connection(&client), ConnectionState::Connecting);
+
+    let interval = ClientConfig::default().connect_interval;
+    clock.advance(interval / 2);
+    transport::client_keepalive::<NetworkPacket>(&client, addr(5000));
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(connection(&client), ConnectionState::Connecting);
+
+    clock.advance(interval / 2);
+    transport::client_keepalive::<NetworkPacket>(&client, addr(5000));
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(connection(&client), ConnectionState::Connected);
+    assert_eq!(events(&client), vec![NetworkEvent::Connect(addr(5000))]);
+    assert_eq!(server.clients.len(), 1);
+}
+
+#[test]
+fn idle_session_stays_connected() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let client = client(&network, 5001);
+    let clock = ManualClock::new();
+    for world in &[&server.world, &client] {
+        world.run(|network: UniqueView<NetworkSender>| network.set_clock(clock.clone()));
+    }
+    tick(&network, &mut server, &[&client]);
+    assert_eq!(events(&client), vec![NetworkEvent::Connect(addr(5000))]);
+
+    // Ten minutes without inputs or state changes
+    let idle_tick = |server: &mut Server, client_alive: bool| {
+        clock.advance(Duration::from_millis(500));
+        if client_alive {
+            transport::client_keepalive::<NetworkPacket>(&client, addr(5000));
+        }
+        assert!(network.wait_idle(TIMEOUT));
+        server.frame += 1;
+        transport::update_server::<NetworkPacket>(&mut server.world, server.frame).unwrap();
+        assert!(network.wait_idle(TIMEOUT));
+        events(&server.world)
+    };
+    for _ in 0..1200 {
+        assert_eq!(idle_tick(&mut server, true), vec![]);
+    }
+    assert_eq!(connection(&client), ConnectionState::Connected);
+    assert_eq!(events(&client), vec![]);
+
+    // Without the client's heartbeats the server times it out
+    let mut disconnected = vec![];
+    for _ in 0..20 {
+        disconnected.extend(idle_tick(&mut server, false));
+    }
+    assert_eq!(disconnected, vec![NetworkEvent::Disconnect(addr(5001))]);
+}
+
+#[test]
+fn slow_movement_does_not_drift() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let client = client(&network, 5001);
+    // The first snapshot, then the first delta against it
+    tick(&network, &mut server, &[&client]);
+    tick(&network, &mut server, &[&client]);
+
+    // Less than half the precision of Position::x each tick
+    for _ in 0..20 {
+        server.world.run(|mut positions: ViewMut<Position>| {
+            for position in (&mut positions).iter() {
+                position.x += 0.3;
+            }
+        });
+        server.frame += 1;
+        transport::update_server::<NetworkPacket>(&mut server.world, server.frame).unwrap();
+        assert!(network.wait_idle(TIMEOUT));
+        network.step();
+        assert!(network.wait_idle(TIMEOUT));
+        assert_eq!(apply_latest(&client), Some(server.frame));
+        client_tick(&client);
+        assert!(network.wait_idle(TIMEOUT));
+
+        let (server_x, client_x) = (server.positions()[0], positions(&client)[0]);
+        assert!((server_x - client_x).abs() <= 1.0, "server at {}, client at {}", server_x, client_x);
+    }
+}
+
+#[test]
+fn entities_leave_and_enter_view() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let client = client(&network, 5001);
+
+    for x in 1..=4 {
+        client_tick(&client);
+        assert!(network.wait_idle(TIMEOUT));
+        server.tick_with(&mut EvenPositions);
+        assert!(network.wait_idle(TIMEOUT));
+        network.step();
+        assert!(network.wait_idle(TIMEOUT));
+
+        assert_eq!(apply_latest(&client), Some(server.frame));
+        let expected = if x % 2 == 0 { vec![x as f32] } else { vec![] };
+        assert_eq!(positions(&client), expected);
+    }
+}
+
+#[test]
+fn splits_snapshots_over_mtu() {
+    let network = LoopbackNetwork::new();
+    let transport = TappedTransport::new(network.bind(addr(5000)));
+    let sent = transport.sent.clone();
+    let mut server = Server::with_transport(transport);
+    server.world.run(|mut config: UniqueViewMut<ServerConfig>| config.mtu = 200);
+    // Snapshots the client didn't ack are sent again every frame
+    server.world.run(|mut controller: UniqueViewMut<NetworkController>| *controller = NetworkController::new(1));
+    server.spawn(100);
+    let client = client(&network, 5001);
+
+    client_tick(&client);
+    assert!(network.wait_idle(TIMEOUT));
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(apply_latest(&client), Some(server.frame));
+    assert_eq!(positions(&client), server.positions());
+    // The handshake reply, then the chunks
+    let chunks = sent.lock().unwrap().split_off(1);
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|&len| len <= 200), "{:?}", chunks);
+
+    // Not acked yet, the next states are snapshots of the same size
+    server.world.run(|mut config: UniqueViewMut<ServerConfig>| config.mtu = 10_000);
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+    let unsplit = sent.lock().unwrap().pop().unwrap();
+    server.world.run(|mut config: UniqueViewMut<ServerConfig>| config.mtu = unsplit - 1);
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(apply_latest(&client), Some(server.frame));
+    let chunks = sent.lock().unwrap().split_off(1);
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|&len| len < unsplit), "{:?} in {}", chunks, unsplit - 1);
+
+    // Acked, the next states are deltas
+    for _ in 0..3 {
+        tick(&network, &mut server, &[&client]);
+    }
+    assert_eq!(apply_latest(&client), Some(server.frame));
+    assert_eq!(positions(&client), server.positions());
+}
+
+#[test]
+fn unacked_snapshot_sent_again_after_frequency() {
+    let network = LoopbackNetwork::new();
+    let transport = TappedTransport::new(network.bind(addr(5000)));
+    let sent = transport.sent.clone();
+    let mut server = Server::with_transport(transport);
+    server.world.run(|mut controller: UniqueViewMut<NetworkController>| *controller = NetworkController::new(4));
+    let client = client(&network, 5001);
+    assert!(network.wait_idle(TIMEOUT));
+
+    // The client never acks, its snapshot is sent again every 4 frames
+    let mut sent_per_tick = vec![];
+    for _ in 0..9 {
+        server.tick();
+        assert!(network.wait_idle(TIMEOUT));
+        sent_per_tick.push(sent.lock().unwrap().drain(..).count());
+    }
+    // The first tick also answers the handshake
+    assert_eq!(sent_per_tick, vec![2, 0, 0, 0, 1, 0, 0, 0, 1]);
+
+    // Acked, the next state is sent right away
+    tick(&network, &mut server, &[&client]);
+    assert_eq!(apply_latest(&client), Some(server.frame));
+}
+
+#[test]
+fn shared_state_is_serialized_once() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let clients: Vec<World> = (5001..5004).map(|port| client(&network, port)).collect();
+    for client in &clients {
+        client_tick(client);
+    }
+    assert!(network.wait_idle(TIMEOUT));
+
+    SERIALIZED.with(|serialized| serialized.set(0));
+    server.tick();
+    // One snapshot of the 3 client entities for the 3 clients
+    assert_eq!(SERIALIZED.with(Cell::get), 3);
+    assert!(network.wait_idle(TIMEOUT));
+    for client in &clients {
+        assert_eq!(apply_latest(client), Some(server.frame));
+        assert_eq!(positions(client), server.positions());
+    }
+
+    // Only the first client sees the entities, the others share the same delta
+    for client in &clients {
+        client_tick(client);
+    }
+    assert!(network.wait_idle(TIMEOUT));
+    server.tick_with(&mut FirstClientOnly);
+    assert!(network.wait_idle(TIMEOUT));
+    assert_eq!(apply_latest(&clients[0]), Some(server.frame));
+    assert_eq!(positions(&clients[0]), server.positions());
+    for client in &clients[1..] {
+        assert_eq!(apply_latest(client), Some(server.frame));
+        assert_eq!(positions(client), vec![]);
+    }
+}
+
+#[test]
+fn batches_messages_per_destination() {
+    let message = |destination: Vec<SocketAddr>, payload: &[u8], delivery| Message::new(destination, payload.to_vec(), delivery);
+    let messages = vec![
+        message(vec![addr(1), addr(2)], &[1; 10], DeliveryRequirement::Unreliable),
+        message(vec![addr(1)], &[2; 10], DeliveryRequirement::Unreliable),
+        message(vec![addr(1)], &[3; 10], DeliveryRequirement::Reliable),
+        message(vec![addr(1)], &[4; 10], DeliveryRequirement::Unreliable),
+    ];
+
+    let datagrams = transport::batch_messages(&messages, 30);
+    let summary: Vec<_> = datagrams
+        .iter()
+        .map(|(destination, delivery, datagram)| {
+            let frames = wire::read_frames(datagram).unwrap();
+            (destination.port(), *delivery, frames.iter().map(|frame| frame[0]).collect::<Vec<_>>())
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1, DeliveryRequirement::Unreliable, vec![1, 2]),
+            (2, DeliveryRequirement::Unreliable, vec![1]),
+            (1, DeliveryRequirement::Reliable, vec![3]),
+            (1, DeliveryRequirement::Unreliable, vec![4]),
+        ]
+    );
+
+    // Alone in their datagram, the destinations of a message share its bytes
+    let shared = transport::batch_messages(&messages[..1], 1);
+    assert_eq!(shared[0].2.as_ptr(), shared[1].2.as_ptr());
+}
+
+fn connection(world: &World) -> ConnectionState {
+    world.run(|connection: UniqueView<ClientConnection>| connection.0.lock().unwrap().clone())
+}
+
+fn events(world: &World) -> Vec<NetworkEvent> {
+    world.run(|events: UniqueView<EventList>| events.0.lock().unwrap().drain(..).collect())
+}
+
+#[test]
+fn rejects_other_packet_schema() {
+    let network = LoopbackNetwork::new();
+    let mut server = Server::new(&network);
+    let mut other = World::default();
+    let config = ClientConfig::default();
+    transport::init_client_transport::<other_schema::NetworkPacket, _>(&mut other, network.bind(addr(5001)), addr(5000), config);
+    let accepted = client(&network, 5002);
+    assert!(network.wait_idle(TIMEOUT));
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+
+    assert_eq!(server.clients.keys().collect::<Vec<_>>(), vec![&addr(5002)]);
+    assert_eq!(connection(&accepted), ConnectionState::Connected);
+    match connection(&other) {
+        ConnectionState::Disconnected(DisconnectReason::Rejected(reason)) => assert!(reason.contains("generate_packet! structs differ"), "{}", reason),
+        state => panic!("not rejected: {:?}", state),
+    }
+
+    // Its commands are dropped
+    client_tick(&other);
+    assert!(network.wait_idle(TIMEOUT));
+    assert!(transport::take_inputs::<()>(&server.world).iter().all(|(client, _)| *client == addr(5002)));
+}
+
+#[test]
+fn authenticates_connect_tokens() {
+    const KEY: &[u8] = b"matchmaker key";
+    let network = LoopbackNetwork::new();
+    let mut world = World::default();
+    let auth = ServerAuth::new(KEY, |_addr: SocketAddr, token: &ConnectToken| match token.user_data.as_slice() {
+        [0] => Err("banned".to_string()),
+        user_data => Ok(user_data.to_vec()),
+    });
+    transport::init_server_transport_with_auth::<NetworkPacket, _>(&mut world, network.bind(addr(5000)), auth);
+    let mut server = Server {
+        world,
+        frame: 0,
+        clients: HashMap::new(),
+    };
+
+    let expires_at = SystemTime::now() + Duration::from_secs(30);
+    let with_token = |port: u16, token: Option<ConnectToken>| {
+        let mut world = World::default();
+        let config = ClientConfig {
+            connect_token: token,
+            ..ClientConfig::default()
+        };
+        transport::init_client_transport::<NetworkPacket, _>(&mut world, network.bind(addr(port)), addr(5000), config);
+        world
+    };
+    let admitted = with_token(5001, Some(ConnectToken::new(KEY, vec![42], expires_at)));
+    let banned = with_token(5002, Some(ConnectToken::new(KEY, vec![0], expires_at)));
+    let forged = with_token(5003, Some(ConnectToken::new(b"other key", vec![42], expires_at)));
+    let anonymous = with_token(5004, None);
+    // The challenge and its answer
+    for _ in 0..2 {
+        assert!(network.wait_idle(TIMEOUT));
+    }
+    server.tick();
+    assert!(network.wait_idle(TIMEOUT));
+
+    assert_eq!(server.clients.keys().collect::<Vec<_>>(), vec![&addr(5001)]);
+    assert_eq!(connection(&admitted), ConnectionState::Connected);
+    let user_data = server.world.run(|user_data: UniqueView<ClientUserData>| user_data.0.lock().unwrap().clone());
+    assert_eq!(user_data, vec![(addr(5001), vec![42])].into_iter().collect());
+    for (client, reason) in [(&banned, "banned"), (&forged, "not signed"), (&anonymous, "requires a connect token")] {
+        match connection(client) {
+            ConnectionState::Disconnected(DisconnectReason::Rejected(rejection)) => assert!(rejection.contains(reason), "{}", rejection),
+            state => panic!("not rejected: {:?}", state),
+        }
+    }
+}
+
+#[test]
+fn handshake_mismatches() {
+    let handshake = Handshake::new::<NetworkPacket>();
+    assert_eq!(handshake.mismatch(&handshake), None);
+    assert_ne!(NetworkPacket::SCHEMA_HASH, other_schema::NetworkPacket::SCHEMA_HASH);
+
+    let older = Handshake {
+        protocol_version: PROTOCOL_VERSION - 1,
+        ..handshake
+    };
+    assert_eq!(
+        handshake.mismatch(&older),
+        Some(format!("protocol version {} instead of {}", PROTOCOL_VERSION - 1, PROTOCOL_VERSION))
+    );
+}
@@ -0,0 +1,475 @@
+use bit_vec::BitVec;
+use netcarrier::wire;
+use netcarrier::{
+    generate_packet, Delta, DeltaOutcome, Interpolate, NetSerialize, NetworkBitmask, PendingDeltas, StateHistory,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Delta, Interpolate, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    #[delta(quantize = "i8")]
+    pub x: f32,
+    #[delta(quantize = "i8")]
+    pub y: f32,
+}
+
+#[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Color(#[delta(full)] pub [f32; 4]);
+
+generate_packet!(struct State {
+    #[interpolate]
+    #[net(min = -1000, max = 1000, precision = 0.25)]
+    positions: Position,
+    colors: Color,
+});
+
+fn bitmask<T>(mask: &[bool], values: Vec<T>) -> NetworkBitmask<T> {
+    let mut entities_mask: BitVec<u32> = BitVec::new();
+    mask.iter().for_each(|&bit| entities_mask.push(bit));
+    NetworkBitmask { entities_mask, values }
+}
+
+fn snapshot() -> NetworkPacket {
+    NetworkPacket {
+        frame: 0,
+        entities_id: vec![1, 2],
+        positions: bitmask(&[true, true], vec![Position { x: 0.0, y: 0.0 }, Position { x: 10.0, y: 10.0 }]),
+        colors: bitmask(&[true, true], vec![Color([1.0; 4]), Color([0.0; 4])]),
+    }
+}
+
+fn delta(snapshot: &NetworkPacket, state: &NetworkPacket) -> NetworkDeltaPacket {
+    match state.from(snapshot).unwrap() {
+        DeltaOutcome::Delta(delta) => delta,
+        outcome => panic!("Expected a delta, got {:?}", outcome),
+    }
+}
+
+#[test]
+fn unchanged_components_left_out() {
+    let snapshot = snapshot();
+    let mut state = snapshot.clone();
+    state.frame = 1;
+    state.positions.values[1] = Position { x: 15.0, y: 10.0 };
+
+    let delta = delta(&snapshot, &state);
+    assert_eq!(delta.delta_positions.values, vec![PositionDelta { x: 5, y: 0 }]);
+    assert_eq!(delta.delta_positions.entities_mask, bitmask::<()>(&[false, true], vec![]).entities_mask);
+    assert!(delta.positions.values.is_empty());
+    assert!(delta.colors.values.is_empty());
+    assert!(delta.delta_colors.values.is_empty());
+    assert_eq!(snapshot.apply(&delta), state);
+}
+
+#[test]
+fn full_and_new_entities() {
+    let snapshot = snapshot();
+    let mut state = snapshot.clone();
+    state.frame = 1;
+    state.entities_id.push(3);
+    state.positions = bitmask(
+        &[true, true, true],
+        vec![Position { x: 500.0, y: 0.0 }, Position { x: 10.0, y: 10.0 }, Position { x: 1.0, y: 1.0 }],
+    );
+    state.colors.entities_mask.push(false);
+
+    let delta = delta(&snapshot, &state);
+    assert_eq!(delta.positions.values, vec![Position { x: 500.0, y: 0.0 }, Position { x: 1.0, y: 1.0 }]);
+    assert!(delta.delta_positions.values.is_empty());
+    assert_eq!(snapshot.apply(&delta), state);
+}
+
+#[test]
+fn removed_components_and_entities() {
+    let snapshot = snapshot();
+    let mut state = snapshot.clone();
+    state.frame = 1;
+    state.entities_id = vec![2];
+    state.positions = bitmask(&[false], vec![]);
+    state.colors = bitmask(&[true], vec![Color([0.0; 4])]);
+
+    let delta = delta(&snapshot, &state);
+    assert_eq!(delta.removed_positions, vec![2]);
+    assert!(delta.removed_colors.is_empty());
+    assert_eq!(snapshot.apply(&delta), state);
+}
+
+#[test]
+fn mismatched_mask() {
+    let snapshot = snapshot();
+    let mut state = snapshot.clone();
+    state.entities_id.push(3);
+
+    assert!(state.from(&snapshot).is_err());
+}
+
+#[test]
+fn invalid_deltas_rejected() {
+    let mut snapshot = snapshot();
+    snapshot.colors = bitmask(&[true, false], vec![Color([1.0; 4])]);
+    let mut state = snapshot.clone();
+    state.frame = 1;
+    state.positions.values[1] = Position { x: 15.0, y: 10.0 };
+    let valid = delta(&snapshot, &state);
+    assert!(snapshot.validate_delta(&valid).is_ok());
+
+    let mut short_mask = valid.clone();
+    short_mask.delta_positions = bitmask(&[true], vec![PositionDelta { x: 5, y: 0 }]);
+    assert!(snapshot.validate_delta(&short_mask).is_err());
+
+    let mut missing_value = valid.clone();
+    missing_value.delta_positions.values.clear();
+    assert!(snapshot.validate_delta(&missing_value).is_err());
+
+    // Entity 2 has no color to apply a delta to
+    let mut no_baseline = valid.clone();
+    no_baseline.delta_colors = bitmask(&[false, true], vec![ColorDelta(Some([0.5; 4]))]);
+    assert!(snapshot.validate_delta(&no_baseline).is_err());
+
+    let mut respawned = valid.clone();
+    respawned.spawned_entities_id = vec![2];
+    respawned.positions.entities_mask.push(false);
+    respawned.delta_positions.entities_mask.push(false);
+    respawned.colors.entities_mask.push(false);
+    respawned.delta_colors.entities_mask.push(false);
+    assert!(snapshot.validate_delta(&respawned).is_err());
+}
+
+#[test]
+fn state_history_keeps_newest_frames() {
+    let mut history = StateHistory::new(2);
+    for &frame in &[3, 1, 2, 2, 4] {
+        let mut state = snapshot();
+        state.frame = frame;
+        history.push(state);
+    }
+    assert_eq!(history.len(), 2);
+    assert!(history.get(2).is_none());
+    assert_eq!(history.get(3).map(|s| s.frame()), Some(3));
+    assert_eq!(history.latest().map(|s| s.frame()), Some(4));
+}
+
+fn delta_frame(snapshot_frame: u32, frame: u32) -> NetworkDeltaPacket {
+    let mut baseline = snapshot();
+    baseline.frame = snapshot_frame;
+    let mut state = snapshot();
+    state.frame = frame;
+    delta(&baseline, &state)
+}
+
+#[test]
+fn pending_deltas_wait_for_baseline() {
+    let mut pending = PendingDeltas::new(4, 10);
+    pending.push(delta_frame(5, 6));
+    pending.push(delta_frame(5, 7));
+    pending.push(delta_frame(6, 8));
+
+    let frames: Vec<u32> = pending.take(5).iter().map(|delta| delta.frame).collect();
+    assert_eq!(frames, vec![6, 7]);
+    assert_eq!(pending.len(), 1);
+    assert!(pending.take(5).is_empty());
+    assert_eq!(pending.expired(), 0);
+}
+
+#[test]
+fn pending_deltas_expire() {
+    let mut pending = PendingDeltas::new(2, 10);
+    pending.push(delta_frame(1, 2));
+    pending.push(delta_frame(1, 4));
+    pending.push(delta_frame(1, 3));
+    assert_eq!(pending.len(), 2);
+    assert_eq!(pending.expired(), 1);
+
+    pending.expire(14);
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending.expired(), 2);
+    assert_eq!(pending.take(1)[0].frame, 4);
+}
+
+#[test]
+fn interpolate_state() {
+    let from = snapshot();
+    let mut next = from.clone();
+    next.frame = 2;
+    next.entities_id = vec![2, 3];
+    next.positions = bitmask(&[true, true], vec![Position { x: 20.0, y: 30.0 }, Position { x: 5.0, y: 5.0 }]);
+    next.colors = bitmask(&[true, false], vec![Color([1.0; 4])]);
+
+    let state = from.interpolate_state(&next, 0.5);
+    assert_eq!(state.frame, 0);
+    assert_eq!(state.entities_id, vec![1, 2]);
+    // Entity 1 is missing from next and keeps its position, entity 3 isn't spawned yet
+    assert_eq!(state.positions.values, vec![Position { x: 0.0, y: 0.0 }, Position { x: 15.0, y: 20.0 }]);
+    assert_eq!(state.colors, from.colors);
+}
+
+#[test]
+fn component_getters() {
+    let mut state = snapshot();
+    state.entities_id.push(3);
+    state.positions = bitmask(&[false, true, true], vec![Position { x: 10.0, y: 10.0 }, Position { x: 3.0, y: 3.0 }]);
+
+    assert_eq!(state.positions(1), None);
+    assert_eq!(state.positions(3), Some(&Position { x: 3.0, y: 3.0 }));
+    assert_eq!(state.colors(2), Some(&Color([0.0; 4])));
+    assert_eq!(state.colors(4), None);
+}
+
+#[test]
+fn retain_entities_and_components() {
+    let mut state = snapshot();
+    state.entities_id.push(3);
+    state.positions = bitmask(&[true, false, true], vec![Position { x: 0.0, y: 0.0 }, Position { x: 3.0, y: 3.0 }]);
+    state.colors = bitmask(&[true, true, false], vec![Color([1.0; 4]), Color([0.0; 4])]);
+
+    let selected = state.retain_entities(|id| id != 1);
+    assert_eq!(selected.entities_id, vec![2, 3]);
+    assert_eq!(selected.positions, bitmask(&[false, true], vec![Position { x: 3.0, y: 3.0 }]));
+    assert_eq!(selected.colors, bitmask(&[true, false], vec![Color([0.0; 4])]));
+
+    state.retain_positions(|id| id == 3);
+    assert_eq!(state.positions, bitmask(&[false, false, true], vec![Position { x: 3.0, y: 3.0 }]));
+}
+
+#[test]
+fn stale_entities_from_previous_state() {
+    let previous = snapshot();
+    let mut state = previous.clone();
+    state.frame = 1;
+    state.entities_id.push(3);
+    state.positions = bitmask(&[true, true, true], vec![Position { x: 1.0, y: 1.0 }, Position { x: 11.0, y: 11.0 }, Position { x: 3.0, y: 3.0 }]);
+    state.colors = bitmask(&[false, false, true], vec![Color([0.5; 4])]);
+
+    let merged = state.with_stale_entities(&previous, |id| id != 1);
+    assert_eq!(merged.entities_id, vec![1, 2]);
+    assert_eq!(merged.positions, bitmask(&[true, true], vec![Position { x: 1.0, y: 1.0 }, Position { x: 10.0, y: 10.0 }]));
+    assert_eq!(merged.colors, bitmask(&[false, true], vec![Color([0.0; 4])]));
+    // 13 bits per quantized coordinate
+    assert_eq!(state.entity_size(3), (32 + 2 * 13 + 4 * 32_u64).div_ceil(8));
+    let sizes: Vec<u64> = state.entities_id.iter().map(|&id| state.entity_size(id)).collect();
+    assert_eq!(state.entity_sizes(), sizes);
+}
+
+#[test]
+fn wire_round_trip() {
+    let snapshot = snapshot();
+    let bytes = wire::to_bytes(&snapshot);
+    assert_eq!(wire::from_bytes::<NetworkPacket>(&bytes), Ok(snapshot.clone()));
+    assert!(bytes.len() * 2 < bincode::serialized_size(&snapshot).unwrap() as usize);
+
+    let mut state = snapshot.clone();
+    state.frame = 3;
+    state.entities_id.push(3);
+    state.positions = bitmask(&[true, true, true], vec![Position { x: 1.0, y: 0.0 }, Position { x: 10.0, y: 10.0 }, Position { x: 3.0, y: 3.0 }]);
+    state.colors = bitmask(&[true, false, false], vec![Color([1.0; 4])]);
+    let delta = delta(&snapshot, &state);
+    let bytes = wire::to_bytes(&delta);
+    assert_eq!(wire::from_bytes::<NetworkDeltaPacket>(&bytes), Ok(delta.clone()));
+    assert!(bytes.len() * 2 < bincode::serialized_size(&delta).unwrap() as usize);
+}
+
+#[test]
+fn quantized_positions() {
+    let mut state = snapshot();
+    state.positions = bitmask(&[true, true], vec![Position { x: 0.3, y: -2000.0 }, Position { x: 10.0, y: 10.0 }]);
+    let received = wire::from_bytes::<NetworkPacket>(&wire::to_bytes(&state)).unwrap();
+    assert_eq!(received.positions(1), Some(&Position { x: 0.25, y: -1000.0 }));
+    assert_eq!(received.positions(2), state.positions(2));
+}
+
+#[test]
+fn wire_mask_length_checked() {
+    let mut state = snapshot();
+    state.colors = bitmask(&[true], vec![Color([1.0; 4])]);
+    assert!(wire::from_bytes::<NetworkPacket>(&wire::to_bytes(&state)).is_err());
+}
+
+// Same state with its entities sorted, the order deltas rebuild them in
+fn sorted(state: &NetworkPacket) -> NetworkPacket {
+    let mut entities_id = state.entities_id.clone();
+    entities_id.sort();
+    NetworkPacket {
+        frame: state.frame,
+        positions: state.positions.reorder(&state.entities_id, &entities_id).unwrap(),
+        colors: state.colors.reorder(&state.entities_id, &entities_id).unwrap(),
+        entities_id,
+    }
+}
+
+#[test]
+fn delta_carries_spawned_and_despawned_ids() {
+    let snapshot = snapshot();
+    let mut state = snapshot.clone();
+    state.frame = 1;
+    let unchanged = delta(&snapshot, &state);
+    assert!(unchanged.spawned_entities_id.is_empty());
+    assert!(unchanged.despawned_entities_id.is_empty());
+
+    // 1 despawned, 4 and 3 spawned
+    state.entities_id = vec![4, 2, 3];
+    state.positions = bitmask(&[true, true, false], vec![Position { x: 4.0, y: 4.0 }, Position { x: 10.0, y: 10.0 }]);
+    state.colors = bitmask(&[true, false, true], vec![Color([0.4; 4]), Color([0.3; 4])]);
+    let delta = delta(&snapshot, &state);
+    assert_eq!(delta.spawned_entities_id, vec![4, 3]);
+    assert_eq!(delta.despawned_entities_id, vec![1]);
+
+    let applied = snapshot.apply(&delta);
+    assert_eq!(applied, sorted(&state));
+    for &id in &state.entities_id {
+        assert_eq!(applied.positions(id), state.positions(id));
+        assert_eq!(applied.colors(id), state.colors(id));
+    }
+}
+
+#[test]
+fn baselines_in_another_order() {
+    // The client's copy of the baseline was rebuilt from an older delta, sorted
+    let mut server_baseline = snapshot();
+    server_baseline.entities_id = vec![2, 1];
+    server_baseline.colors = bitmask(&[true, true], vec![Color([0.0; 4]), Color([1.0; 4])]);
+    server_baseline.positions = bitmask(&[true, true], vec![Position { x: 10.0, y: 10.0 }, Position { x: 0.0, y: 0.0 }]);
+    let client_baseline = sorted(&server_baseline);
+    assert_eq!(client_baseline, snapshot());
+
+    let mut state = server_baseline.clone();
+    state.frame = 1;
+    state.entities_id.push(3);
+    state.positions.add_value(Position { x: 3.0, y: 3.0 });
+    state.colors.entities_mask.push(false);
+    state.positions.values[0] = Position { x: 12.0, y: 10.0 };
+
+    let delta = delta(&server_baseline, &state);
+    assert_eq!(client_baseline.apply(&delta), sorted(&state));
+    assert_eq!(server_baseline.apply(&delta), sorted(&state));
+}
+
+// Small deterministic generator, the states only need to differ between runs of the loop
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, max: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % max
+    }
+
+    fn state(&mut self, frame: u32, ids: u64) -> NetworkPacket {
+        let mut state = NetworkPacket {
+            frame,
+            entities_id: vec![],
+            positions: bitmask(&[], vec![]),
+            colors: bitmask(&[], vec![]),
+        };
+        for id in 0..ids as u32 {
+            if self.next(4) == 0 {
+                continue;
+            }
+            state.entities_id.push(id);
+            match self.next(3) {
+                0 => state.positions.entities_mask.push(false),
+                // Either within a quantized delta or sent in full
+                1 => state.positions.add_value(Position { x: self.next(4) as f32, y: 0.0 }),
+                _ => state.positions.add_value(Position { x: self.next(1000) as f32, y: 1.0 }),
+            }
+            if self.next(2) == 0 {
+                state.colors.entities_mask.push(false);
+            } else {
+                state.colors.add_value(Color([self.next(2) as f32; 4]));
+            }
+        }
+        // Storage order, not sorted
+        let mut order = state.entities_id.clone();
+        for i in (1..order.len()).rev() {
+            order.swap(i, self.next(i as u64 + 1) as usize);
+        }
+        NetworkPacket {
+            frame,
+            positions: state.positions.reorder(&state.entities_id, &order).unwrap(),
+            colors: state.colors.reorder(&state.entities_id, &order).unwrap(),
+            entities_id: order,
+        }
+    }
+}
+
+#[test]
+fn random_deltas_rebuild_the_state() {
+    let mut rng = Lcg(7);
+    for _ in 0..50 {
+        let snapshot = rng.state(0, 200);
+        let state = rng.state(1, 200);
+        let delta = delta(&snapshot, &state);
+        let applied = snapshot.apply(&delta);
+        assert_eq!(applied, sorted(&state));
+        // Checked entity by entity too, through the linear search of the getters
+        for &id in &state.entities_id {
+            assert_eq!(applied.positions(id), state.positions(id));
+            assert_eq!(applied.colors(id), state.colors(id));
+        }
+        let removed: Vec<u32> = snapshot
+            .entities_id
+            .iter()
+            .copied()
+            .filter(|&id| state.entities_id.contains(&id) && snapshot.positions(id).is_some() && state.positions(id).is_none())
+            .collect();
+        assert_eq!(delta.removed_positions, removed);
+    }
+}
+
+#[test]
+fn replicate_follows_entities_id() {
+    let world = World::default();
+    let ids = world.run(|mut entities: EntitiesViewMut, mut net_ids: ViewMut<netcarrier::NetworkIdentifier>, mut positions: ViewMut<Position>| {
+        (0..100)
+            .map(|i| {
+                let net_id = netcarrier::NetworkIdentifier::default();
+                let entity = entities.add_entity(&mut net_ids, net_id);
+                if i % 3 != 0 {
+                    entities.add_component(&mut positions, Position { x: i as f32, y: 0.0 }, entity);
+                }
+                net_id.id
+            })
+            .collect::<Vec<u32>>()
+    });
+    let mut entities_id = ids.clone();
+    entities_id.reverse();
+    let positions = netcarrier::replicate::<Position>(&world, &entities_id);
+    for (i, &id) in ids.iter().enumerate() {
+        let expected = if i % 3 != 0 { Some(&Position { x: i as f32, y: 0.0 }) } else { None };
+        assert_eq!(positions.get(&entities_id, id), expected);
+    }
+}
+
+#[test]
+fn snapshot_then_delta_matches_capture() {
+    let world = World::default();
+    let entities = world.run(|mut entities: EntitiesViewMut, mut net_ids: ViewMut<netcarrier::NetworkIdentifier>, mut positions: ViewMut<Position>, mut colors: ViewMut<Color>| {
+        (0..4)
+            .map(|i| {
+                entities.add_entity(
+                    (&mut net_ids, &mut positions, &mut colors),
+                    (netcarrier::NetworkIdentifier::default(), Position { x: i as f32, y: 0.0 }, Color([i as f32; 4])),
+                )
+            })
+            .collect::<Vec<EntityId>>()
+    });
+    // The last entity takes the place of the first one in the storage
+    world.run(|mut all_storages: AllStoragesViewMut| {
+        all_storages.delete(entities[0]);
+    });
+    let storage_ids: Vec<u32> = world.run(|net_ids: View<netcarrier::NetworkIdentifier>| net_ids.iter().map(|net_id| net_id.id).collect());
+    let mut sorted_ids = storage_ids.clone();
+    sorted_ids.sort();
+    assert_ne!(storage_ids, sorted_ids);
+
+    let snapshot = NetworkPacket::new(&world, 1);
+    assert_eq!(snapshot.entities_id, sorted_ids);
+    let received: NetworkPacket = wire::from_bytes(&wire::to_bytes(&snapshot)).unwrap();
+    assert_eq!(received, snapshot);
+
+    world.run(|mut positions: ViewMut<Position>| {
+        for position in (&mut positions).iter() {
+            position.y += 2.0;
+        }
+    });
+    let state = NetworkPacket::new(&world, 2);
+    assert_eq!(received.apply(&delta(&snapshot, &state)), state);
+}
@@ -0,0 +1,250 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+
+use shipyard::*;
+
+use super::transport::{NetworkClient, NetworkSerializable};
+use super::{CarrierDeltaPacket, CarrierPacket, NetworkIdentifier};
+
+/// Network ids bucketed by position in square cells.
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<u32>>,
+    positions: HashMap<u32, [f32; 2]>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> Self {
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: [f32; 2]) -> (i32, i32) {
+        (
+            (position[0] / self.cell_size).floor() as i32,
+            (position[1] / self.cell_size).floor() as i32,
+        )
+    }
+
+    pub fn insert(&mut self, id: u32, position: [f32; 2]) {
+        let cell = self.cell(position);
+        self.cells.entry(cell).or_default().push(id);
+        self.positions.insert(id, position);
+    }
+
+    pub fn position(&self, id: u32) -> Option<[f32; 2]> {
+        self.positions.get(&id).copied()
+    }
+
+    /// Network ids within `radius` of `center`, only the cells overlapping the radius are visited.
+    pub fn within(&self, center: [f32; 2], radius: f32) -> Vec<u32> {
+        let (min_x, min_y) = self.cell([center[0] - radius, center[1] - radius]);
+        let (max_x, max_y) = self.cell([center[0] + radius, center[1] + radius]);
+        let mut ids = vec![];
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                let cell = match self.cells.get(&(x, y)) {
+                    Some(cell) => cell,
+                    None => continue,
+                };
+                ids.extend(cell.iter().copied().filter(|id| {
+                    let position = self.positions[id];
+                    let (dx, dy) = (position[0] - center[0], position[1] - center[1]);
+                    dx * dx + dy * dy <= radius * radius
+                }));
+            }
+        }
+        ids
+    }
+}
+
+/// World state of a tick with the grid of its positioned entities.
+pub struct GridCapture<T> {
+    pub state: T,
+    pub grid: SpatialGrid,
+}
+
+/// Sends each client the entities within `radius` of the entity it controls.
+///
+/// Entities enter a client's view within `radius` and leave it past `radius + hysteresis`, so
+/// the ones moving along the border don't flicker. Entities without the position component are
+/// sent to everyone, clients without an entity only get those.
+pub struct AreaOfInterest<T, P> {
+    pub cell_size: f32,
+    pub radius: f32,
+    pub hysteresis: f32,
+    position: fn(&P) -> [f32; 2],
+    visible: HashMap<SocketAddr, HashSet<u32>>,
+    // Clients selected since the last capture, the others are gone
+    selected: HashSet<SocketAddr>,
+    packet: PhantomData<T>,
+}
+
+impl<T, P> AreaOfInterest<T, P> {
+    /// `position` reads the coordinates of the position component `P`.
+    pub fn new(cell_size: f32, radius: f32, hysteresis: f32, position: fn(&P) -> [f32; 2]) -> Self {
+        AreaOfInterest {
+            cell_size,
+            radius,
+            hysteresis,
+            position,
+            visible: HashMap::new(),
+            selected: HashSet::new(),
+            packet: PhantomData,
+        }
+    }
+}
+
+impl<T, P> NetworkSerializable for AreaOfInterest<T, P>
+where
+    T: CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+    P: 'static + Send + Sync,
+{
+    type Capture = GridCapture<T>;
+    type Packet = T;
+
+    fn serialize(&mut self, world: &World, frame: u32) -> GridCapture<T> {
+        let selected = std::mem::take(&mut self.selected);
+        self.visible.retain(|addr, _| selected.contains(addr));
+
+        let mut grid = SpatialGrid::new(self.cell_size);
+        world.run(|positions: View<P>, net_ids: View<NetworkIdentifier>| {
+            for (position, net_id) in (&positions, &net_ids).iter() {
+                grid.insert(net_id.id, (self.position)(position));
+            }
+        });
+        GridCapture {
+            state: T::new(world, frame),
+            grid,
+        }
+    }
+
+    fn select_for_client(&mut self, client: &NetworkClient, capture: &GridCapture<T>) -> T {
+        self.selected.insert(client.addr);
+        let visible = self.visible.entry(client.addr).or_default();
+        let center = client.entity.and_then(|id| capture.grid.position(id));
+        let mut now_visible = HashSet::new();
+        if let Some(center) = center {
+            for id in capture.grid.within(center, self.radius + self.hysteresis) {
+                let position = capture.grid.position(id).unwrap();
+                let (dx, dy) = (position[0] - center[0], position[1] - center[1]);
+                if visible.contains(&id) || dx * dx + dy * dy <= self.radius * self.radius {
+                    now_visible.insert(id);
+                }
+            }
+        }
+        *visible = now_visible;
+
+        let grid = &capture.grid;
+        capture
+            .state
+            .retain_entities(|id| grid.position(id).is_none() || visible.contains(&id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_packet, Delta, NetSerialize};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub struct Position {
+        #[delta(full)]
+        x: f32,
+        #[delta(full)]
+        y: f32,
+    }
+
+    #[derive(Delta, NetSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+    pub struct Score(#[delta(full)] u32);
+
+    generate_packet!(struct State {
+        positions: Position,
+        scores: Score,
+    });
+
+    fn spawn(world: &World, position: Option<Position>) -> (EntityId, u32) {
+        world.run(
+            |mut entities: EntitiesViewMut, mut net_ids: ViewMut<NetworkIdentifier>, mut positions: ViewMut<Position>, mut scores: ViewMut<Score>| {
+                let net_id = NetworkIdentifier::default();
+                let entity = entities.add_entity((&mut net_ids, &mut scores), (net_id, Score(0)));
+                if let Some(position) = position {
+                    entities.add_component(&mut positions, position, entity);
+                }
+                (entity, net_id.id)
+            },
+        )
+    }
+
+    fn move_to(world: &World, entity: EntityId, x: f32) {
+        world.run(|mut positions: ViewMut<Position>| positions[entity].x = x);
+    }
+
+    fn ids(state: &NetworkPacket) -> Vec<u32> {
+        let mut ids = state.entities_id.clone();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn grid_within_radius() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, [0.0, 0.0]);
+        grid.insert(2, [14.0, 0.0]);
+        grid.insert(3, [-9.0, -9.0]);
+        grid.insert(4, [40.0, 40.0]);
+
+        let mut ids = grid.within([0.0, 0.0], 15.0);
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(grid.within([40.0, 35.0], 4.0), vec![]);
+    }
+
+    #[test]
+    fn hysteresis_at_the_border() {
+        let world = World::default();
+        let (_, owner) = spawn(&world, Some(Position { x: 0.0, y: 0.0 }));
+        let (other, other_id) = spawn(&world, Some(Position { x: 12.0, y: 0.0 }));
+        let (_, global) = spawn(&world, None);
+        let client = NetworkClient {
+            addr: "127.0.0.1:5001".parse().unwrap(),
+            entity: Some(owner),
+        };
+        let mut interest = AreaOfInterest::<NetworkPacket, Position>::new(4.0, 10.0, 5.0, |position| [position.x, position.y]);
+        let mut select = |frame| {
+            let capture = interest.serialize(&world, frame);
+            ids(&interest.select_for_client(&client, &capture))
+        };
+
+        // Past the radius, not seen yet
+        assert_eq!(select(1), vec![owner, global]);
+        move_to(&world, other, 9.0);
+        assert_eq!(select(2), vec![owner, other_id, global]);
+        // Within the hysteresis, still visible
+        move_to(&world, other, 14.0);
+        assert_eq!(select(3), vec![owner, other_id, global]);
+        move_to(&world, other, 16.0);
+        assert_eq!(select(4), vec![owner, global]);
+    }
+
+    #[test]
+    fn client_without_entity() {
+        let world = World::default();
+        spawn(&world, Some(Position { x: 0.0, y: 0.0 }));
+        let (_, global) = spawn(&world, None);
+        let mut interest = AreaOfInterest::<NetworkPacket, Position>::new(4.0, 10.0, 5.0, |position| [position.x, position.y]);
+        let client = NetworkClient {
+            addr: "127.0.0.1:5001".parse().unwrap(),
+            entity: None,
+        };
+
+        let capture = interest.serialize(&world, 1);
+        assert_eq!(ids(&interest.select_for_client(&client, &capture)), vec![global]);
+    }
+}
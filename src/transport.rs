@@ -1,18 +1,40 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::marker::PhantomData;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::{Delta, NetworkController, CarrierDeltaPacket, CarrierPacket};
+use super::auth::{ConnectToken, ServerAuth};
+use super::fragment::{self, ChunkAssembler, SnapshotChunk};
+use super::input::{CommandQueue, InputBuffer, InputCommand};
+use super::jitter::{JitterBuffer, JitterBufferConfig};
+use super::keepalive::{Clock, Keepalive, KeepaliveConfig};
+use super::prediction::{Predict, Prediction};
+use super::{
+    CarrierDeltaPacket, CarrierPacket, Delta, DeltaError, DeltaOutcome, NetSerialize, NetworkController,
+    PendingDeltas, StateHistory,
+};
+use super::wire::{self, BitReader, BitWriter, Quantize, WireError};
 use bytes::Bytes;
-use crossbeam_channel::{Receiver, SendError, Sender};
-use laminar::{ErrorKind, Packet, Socket, SocketEvent};
+#[cfg(feature = "laminar")]
+use ::laminar::{ErrorKind, Socket};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use shipyard::*;
 
+pub mod conditioner;
+#[cfg(feature = "laminar")]
+pub mod laminar;
+pub mod loopback;
+#[cfg(feature = "encryption")]
+pub mod secure;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Message {
     pub destination: Vec<SocketAddr>,
@@ -21,58 +43,424 @@ pub struct Message {
 }
 
 impl Message {
+    /// The payload is shared by every destination, a `Vec<u8>` or `Bytes` isn't copied.
     pub fn new(
         destination: Vec<SocketAddr>,
-        payload: &[u8],
+        payload: impl Into<Bytes>,
         delivery: DeliveryRequirement,
     ) -> Self {
         Self {
             destination,
-            payload: Bytes::copy_from_slice(payload),
+            payload: payload.into(),
             delivery,
         }
     }
 }
 
+/// Datagrams sending `messages`, each message in a frame.
+///
+/// Messages for the same destination with the same delivery share a datagram up to `mtu` bytes.
+/// A message is framed once, a datagram with a single frame is shared by its destinations.
+pub fn batch_messages<'m>(
+    messages: impl IntoIterator<Item = &'m Message>,
+    mtu: usize,
+) -> Vec<(SocketAddr, DeliveryRequirement, Bytes)> {
+    struct Batch {
+        destination: SocketAddr,
+        delivery: DeliveryRequirement,
+        frames: Vec<Bytes>,
+        len: usize,
+    }
+
+    let mut batches: Vec<Batch> = vec![];
+    // Batch still filled for each destination and delivery
+    let mut open: HashMap<(SocketAddr, DeliveryRequirement), usize> = HashMap::new();
+    for message in messages {
+        let frame = Bytes::from(wire::write_frames(iter::once(&message.payload[..])));
+        for &destination in &message.destination {
+            match open.get(&(destination, message.delivery)) {
+                Some(&index) if batches[index].len + frame.len() <= mtu => {
+                    let batch = &mut batches[index];
+                    batch.len += frame.len();
+                    batch.frames.push(frame.clone());
+                }
+                _ => {
+                    open.insert((destination, message.delivery), batches.len());
+                    batches.push(Batch {
+                        destination,
+                        delivery: message.delivery,
+                        frames: vec![frame.clone()],
+                        len: frame.len(),
+                    });
+                }
+            }
+        }
+    }
+    batches
+        .into_iter()
+        .map(|batch| {
+            let datagram = match &batch.frames[..] {
+                [frame] => frame.clone(),
+                frames => Bytes::from(frames.concat()),
+            };
+            (batch.destination, batch.delivery, datagram)
+        })
+        .collect()
+}
+
+/// Event received from a [`Transport`].
+#[derive(Debug, PartialEq)]
+pub enum TransportEvent {
+    Packet(SocketAddr, Vec<u8>),
+    Connect(SocketAddr),
+    Timeout(SocketAddr),
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// The other half of the transport is gone.
+    Disconnected,
+    Custom(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Disconnected => write!(f, "transport disconnected"),
+            TransportError::Custom(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// Addressed datagrams with delivery guarantees and connection events.
+///
+/// It is split in two when the network is initialized: the sender is used by the systems and
+/// the receiver is polled by a network thread.
+pub trait Transport {
+    type Sender: TransportSender;
+    type Receiver: TransportReceiver;
+
+    fn split(self) -> (Self::Sender, Self::Receiver);
+}
+
+pub trait TransportSender: Send + Sync + 'static {
+    fn send(&self, addr: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Result<(), TransportError>;
+
+    /// Drops what the transport keeps about `addr` once the network timed it out.
+    fn forget(&self, _addr: SocketAddr) {}
+}
+
+pub trait TransportReceiver: Send + 'static {
+    /// Blocks until the next event, fails once the transport is closed.
+    fn recv(&self) -> Result<TransportEvent, TransportError>;
+}
+
+/// Shared by the systems and the receive thread, which answers handshakes.
+///
+/// It keeps the traffic with the connected peers, for the heartbeats and the idle timeouts.
+#[derive(Clone)]
 pub struct NetworkSender {
-    sender: Sender<Packet>,
+    sender: Arc<dyn TransportSender>,
+    keepalive: Arc<Mutex<Keepalive>>,
 }
 
 impl NetworkSender {
-    pub fn new(sender: Sender<Packet>) -> Self {
-        Self { sender }
+    pub fn new<S: TransportSender>(sender: S) -> Self {
+        Self {
+            sender: Arc::new(sender),
+            keepalive: Arc::new(Mutex::new(Keepalive::default())),
+        }
+    }
+
+    pub fn keepalive(&self) -> MutexGuard<'_, Keepalive> {
+        self.keepalive.lock().unwrap()
+    }
+
+    /// Times the heartbeats and the idle timeouts with `clock`, see [`Keepalive::set_clock`].
+    pub fn set_clock<C: Clock>(&self, clock: C) {
+        self.keepalive().set_clock(clock);
+    }
+
+    fn send(&self, addr: SocketAddr, datagram: Bytes, delivery: DeliveryRequirement) {
+        match self.sender.send(addr, datagram, delivery) {
+            Ok(()) => self.keepalive().sent(addr),
+            Err(e) => println!("Send Error sending message: {}", e),
+        }
+    }
+
+    // The transport starts over with `addr`, after it timed out
+    fn forget(&self, addr: SocketAddr) {
+        self.sender.forget(addr);
+    }
+
+    // A datagram with only `payload`
+    fn send_frame(&self, addr: SocketAddr, payload: &[u8], delivery: DeliveryRequirement) {
+        self.send(addr, Bytes::from(wire::write_frames(iter::once(payload))), delivery);
     }
 }
 
+/// Connections of the server's clients, or of the client to its server.
+#[derive(Debug, PartialEq)]
 pub enum NetworkEvent {
     Message(SocketAddr, Bytes),
     Connect(SocketAddr),
     Disconnect(SocketAddr),
 }
 
+#[derive(Debug)]
+pub enum NetworkError {
+    Delta(DeltaError),
+    Serialization(bincode::Error),
+    Wire(WireError),
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkError::Delta(e) => write!(f, "failed to get delta: {}", e),
+            NetworkError::Serialization(e) => write!(f, "failed to serialize: {}", e),
+            NetworkError::Wire(e) => write!(f, "failed to read packet: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<DeltaError> for NetworkError {
+    fn from(e: DeltaError) -> Self {
+        NetworkError::Delta(e)
+    }
+}
+
+impl From<bincode::Error> for NetworkError {
+    fn from(e: bincode::Error) -> Self {
+        NetworkError::Serialization(e)
+    }
+}
+
+impl From<WireError> for NetworkError {
+    fn from(e: WireError) -> Self {
+        NetworkError::Wire(e)
+    }
+}
+
 #[derive(Default)]
 pub struct TransportResource {
     pub messages: VecDeque<Message>,
 }
 
+/// How many past states the server and the clients keep as delta baselines.
+pub const STATE_HISTORY_SIZE: usize = 32;
+
+/// How many commands the server buffers for each client.
+pub const INPUT_BUFFER_SIZE: usize = 32;
+
+/// Commands the server lets wait for each client, older ones are processed together to catch up.
+pub const INPUT_BUFFER_DEPTH: usize = 2;
+
 #[derive(Default)]
 pub struct ClientList {
     pub clients: Arc<Mutex<Vec<SocketAddr>>>,
 }
 
-pub struct EventList(pub Arc<Mutex<Vec<NetworkEvent>>>);
+pub struct ClientConfig {
+    /// How many received states are kept as delta baselines.
+    pub snapshot_history_size: usize,
+    /// How many deltas can wait for their baseline at the same time.
+    pub pending_deltas_size: usize,
+    /// Frames after which a waiting delta is dropped.
+    pub pending_delta_max_age: u32,
+    pub jitter_buffer: JitterBufferConfig,
+    /// How many of the newest unacknowledged input commands each input packet carries.
+    pub commands_per_packet: usize,
+    /// How many unacknowledged input commands are kept, older ones are dropped.
+    pub command_queue_size: usize,
+    /// Messages for the server share a datagram up to this many bytes.
+    pub mtu: usize,
+    /// Sent to servers that require one, see [`ServerAuth`].
+    pub connect_token: Option<ConnectToken>,
+    /// Sends the handshake again when the server times out, instead of staying disconnected.
+    pub reconnect: bool,
+    /// The handshake is sent again this often until the server answers, see [`client_keepalive`].
+    pub connect_interval: Duration,
+    pub keepalive: KeepaliveConfig,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            snapshot_history_size: STATE_HISTORY_SIZE,
+            pending_deltas_size: 32,
+            pending_delta_max_age: STATE_HISTORY_SIZE as u32,
+            jitter_buffer: JitterBufferConfig::default(),
+            commands_per_packet: 8,
+            command_queue_size: 64,
+            mtu: 1200,
+            connect_token: None,
+            reconnect: true,
+            connect_interval: Duration::from_millis(500),
+            keepalive: KeepaliveConfig::default(),
+        }
+    }
+}
+
+pub struct ServerConfig {
+    /// Snapshots bigger than this many bytes are split in chunks, see [`fragment`].
+    /// Messages for the same client share a datagram up to this size.
+    pub mtu: usize,
+    pub keepalive: KeepaliveConfig,
+}
 
-pub struct JitBuffer<T>(pub Arc<Mutex<Vec<T>>>);
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            mtu: 1200,
+            keepalive: KeepaliveConfig::default(),
+        }
+    }
+}
+
+/// Last frame acknowledged by each client, used as its delta baseline.
+#[derive(Default)]
+pub struct ClientAcks(pub Arc<Mutex<HashMap<SocketAddr, NetworkClientAck>>>);
+
+/// Input commands received from each client, see [`take_inputs`].
+#[derive(Default)]
+pub struct ClientInputs(pub Arc<Mutex<HashMap<SocketAddr, InputBuffer<Vec<u8>>>>>);
+
+/// User data the [`Authenticator`](super::auth::Authenticator) attached to each client, empty
+/// without a [`ServerAuth`].
+#[derive(Default)]
+pub struct ClientUserData(pub Arc<Mutex<HashMap<SocketAddr, Vec<u8>>>>);
+
+/// Network id of the entity each client controls, sent to the client for its prediction.
+#[derive(Default)]
+pub struct ClientEntities(pub HashMap<SocketAddr, u32>);
+
+/// States sent to each client, the baselines of its deltas.
+pub struct ClientHistories<T>(pub HashMap<SocketAddr, StateHistory<T>>);
+
+impl<T> Default for ClientHistories<T> {
+    fn default() -> Self {
+        ClientHistories(HashMap::new())
+    }
+}
+
+pub struct EventList(pub Arc<Mutex<Vec<NetworkEvent>>>);
 
 pub struct NetworkIdMapping(pub HashMap<u32, EntityId>);
 
 #[derive(Serialize, Deserialize)]
 pub struct NetworkClientState {
     ack: NetworkClientAck,
-    state: Vec<u8>,
+    message: ClientMessage,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum ClientMessage {
+    /// First message of the client, the server drops the others until it accepts it.
+    Connect(ConnectRequest),
+    /// Nonce of the server's challenge, sent back from the client's address.
+    ChallengeResponse(u64),
+    State(Vec<u8>),
+    /// The newest unacknowledged commands, serialized one by one.
+    Commands(Vec<InputCommand<Vec<u8>>>),
+    /// Keeps the connection alive while the client sends nothing else.
+    Heartbeat,
+}
+
+/// Version of the messages between the server and the clients, raised when they change.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// What one end sends and expects, the other end only talks to it if both match.
+#[derive(Serialize, Deserialize, NetSerialize, Clone, Copy, Debug, PartialEq)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    /// [`CarrierPacket::SCHEMA_HASH`] of the states.
+    pub schema_hash: u64,
+}
+
+impl Handshake {
+    pub fn new<T>() -> Self
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            schema_hash: T::SCHEMA_HASH,
+        }
+    }
+
+    /// Why this end can't talk to `other`, `None` if it can.
+    pub fn mismatch(&self, other: &Handshake) -> Option<String> {
+        if other.protocol_version != self.protocol_version {
+            Some(format!(
+                "protocol version {} instead of {}",
+                other.protocol_version, self.protocol_version
+            ))
+        } else if other.schema_hash != self.schema_hash {
+            Some(format!(
+                "packet schema {:016x} instead of {:016x}, the generate_packet! structs differ",
+                other.schema_hash, self.schema_hash
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConnectRequest {
+    pub handshake: Handshake,
+    /// Needed by servers with a [`ServerAuth`].
+    pub token: Option<ConnectToken>,
+}
+
+/// Answer of the server to the [`ConnectRequest`] of a client.
+#[derive(Serialize, Deserialize, NetSerialize, Clone, Debug, PartialEq)]
+pub struct HandshakeReply {
+    pub handshake: Handshake,
+    pub outcome: HandshakeOutcome,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum HandshakeOutcome {
+    Accepted,
+    /// The token is valid, the client has to send the nonce back.
+    Challenge(u64),
+    Rejected(String),
+}
+
+impl NetSerialize for HandshakeOutcome {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        match self {
+            HandshakeOutcome::Accepted => writer.write_bits(0, 2),
+            HandshakeOutcome::Challenge(nonce) => {
+                writer.write_bits(1, 2);
+                // Random, a varint would only make it longer
+                writer.write_bits(*nonce, 64);
+            }
+            HandshakeOutcome::Rejected(reason) => {
+                writer.write_bits(2, 2);
+                reason.net_serialize(writer, quantize);
+            }
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        match reader.read_bits(2)? {
+            0 => Ok(HandshakeOutcome::Accepted),
+            1 => Ok(HandshakeOutcome::Challenge(reader.read_bits(64)?)),
+            2 => Ok(HandshakeOutcome::Rejected(String::net_deserialize(reader, quantize)?)),
+            tag => Err(WireError::Invalid(format!("unknown handshake outcome {}", tag))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum DeliveryRequirement {
     Unreliable,
     UnreliableSequenced(Option<u8>),
@@ -82,10 +470,11 @@ pub enum DeliveryRequirement {
 }
 
 // TODO: review struct name and struct alias
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct NetworkClientAck {
-    last_frame: u32,
-    last_snapshot_frame: u32,
+    /// Newest state the client has, received as a snapshot or rebuilt from a delta.
+    last_frame: Option<u32>,
+    last_snapshot_frame: Option<u32>,
 }
 
 pub struct NetworkAck(pub Arc<Mutex<NetworkClientAck>>);
@@ -94,127 +483,335 @@ pub fn client_send_network_system(
     network: UniqueViewMut<NetworkSender>,
     mut transport: UniqueViewMut<TransportResource>,
     network_ack: UniqueViewMut<NetworkAck>,
+    config: UniqueView<ClientConfig>,
 ) {
     let ack = network_ack.0.lock().unwrap();
-    for message in &transport.messages {
-        for &destination in &message.destination {
+    // The ack is the same for every destination, each message is serialized once
+    let messages: Vec<_> = transport
+        .messages
+        .drain(..)
+        .map(|message| {
             let net_state = NetworkClientState {
                 ack: ack.clone(),
-                state: message.payload.to_vec(),
-            };
-            let payload = bincode::serialize(&net_state).unwrap();
-            let packet = match message.delivery {
-                DeliveryRequirement::Reliable => Packet::reliable_unordered(destination, payload),
-                DeliveryRequirement::Unreliable => Packet::unreliable(destination, payload),
-                DeliveryRequirement::UnreliableSequenced(stream_id) => {
-                    Packet::unreliable_sequenced(destination, payload, stream_id)
-                }
-                DeliveryRequirement::ReliableSequenced(stream_id) => {
-                    Packet::reliable_sequenced(destination, payload, stream_id)
-                }
-                DeliveryRequirement::ReliableOrdered(stream_id) => {
-                    Packet::reliable_ordered(destination, payload, stream_id)
-                }
+                message: ClientMessage::State(message.payload.to_vec()),
             };
-
-            if let Err(SendError(e)) = network.sender.send(packet) {
-                println!("Send Error sending message: {:?}", e);
-            }
-        }
-    }
-    transport.messages.clear();
+            Message::new(message.destination, bincode::serialize(&net_state).unwrap(), message.delivery)
+        })
+        .collect();
+    send_messages(&network, &messages, config.mtu);
 }
 
 pub fn server_send_network_system(
     network: UniqueViewMut<NetworkSender>,
     mut transport: UniqueViewMut<TransportResource>,
+    config: UniqueView<ServerConfig>,
 ) {
-    for message in &transport.messages {
-        for &destination in &message.destination {
-            println!("{}", message.payload.len());
-            let packet = Packet::reliable_unordered(destination, message.payload.to_vec());
-            if let Err(SendError(e)) = network.sender.send(packet) {
-                println!("Send Error sending message: {:?}", e);
-            }
-        }
-    }
+    send_messages(&network, &transport.messages, config.mtu);
     transport.messages.clear();
 }
 
-pub fn server_receive_network_system(
-    receiver: Receiver<SocketEvent>,
-    client_list: Arc<Mutex<Vec<SocketAddr>>>,
-) -> Receiver<NetworkEvent> {
-    let (sender, event_receiver) = crossbeam_channel::unbounded();
-    let _pool = thread::spawn(move || loop {
-        if let Ok(event) = receiver.recv() {
+fn send_messages<'m>(network: &NetworkSender, messages: impl IntoIterator<Item = &'m Message>, mtu: usize) {
+    for (destination, delivery, datagram) in batch_messages(messages, mtu) {
+        network.send(destination, datagram, delivery);
+    }
+}
+
+/// Receives from the clients in a new thread, until the transport is closed.
+///
+/// Addresses become clients once their [`Handshake`] matches the server's and, if the server has
+/// a [`ServerAuth`], once they answered the challenge of their connect token. The messages of the
+/// other addresses are dropped.
+pub fn server_receive_network_system<T, R>(receiver: R, mut server: ServerReceiver, events: Arc<Mutex<Vec<NetworkEvent>>>)
+where
+    T: CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+    R: TransportReceiver,
+{
+    let _pool = thread::spawn(move || {
+        while let Ok(event) = receiver.recv() {
             let event = match event {
-                SocketEvent::Packet(packet) => {
-                    if let Ok(net_client_state) =
-                        bincode::deserialize::<NetworkClientState>(&packet.payload())
-                    {
-                        // println!("Client {}, last_received frame: {}", packet.addr(), net_client_state.ack.last_frame);
-                        NetworkEvent::Message(
-                            packet.addr(),
-                            Bytes::copy_from_slice(&net_client_state.state),
-                        )
-                    } else {
-                        break;
+                TransportEvent::Packet(addr, payload) => {
+                    server.sender.keepalive().received(addr);
+                    let frames = match wire::read_frames(&payload) {
+                        Ok(frames) => frames,
+                        Err(e) => {
+                            println!("Error reading packet from {}: {}", addr, e);
+                            continue;
+                        }
+                    };
+                    let mut messages = vec![];
+                    for frame in frames {
+                        match bincode::deserialize::<NetworkClientState>(frame) {
+                            Ok(net_client_state) => messages.extend(server.receive::<T>(addr, net_client_state)),
+                            Err(e) => println!("Error reading packet from {}: {}", addr, e),
+                        }
                     }
+                    events.lock().unwrap().extend(messages);
+                    continue;
                 }
-                SocketEvent::Connect(addr) => {
-                    let mut clients = client_list.lock().unwrap();
-                    println!("Client {} connected!", addr);
-                    if !clients.contains(&addr) {
-                        clients.push(addr);
+                // Clients connect with their handshake
+                TransportEvent::Connect(_) => continue,
+                TransportEvent::Timeout(addr) => {
+                    server.sender.keepalive().forget(addr);
+                    let removed = remove_client(
+                        addr,
+                        &server.client_list,
+                        &server.client_acks,
+                        &server.client_inputs,
+                        &server.client_user_data,
+                    );
+                    if !removed {
+                        continue;
                     }
-                    NetworkEvent::Connect(addr)
-                }
-                SocketEvent::Timeout(addr) => {
-                    println!("Client {} disconnected!", addr);
-                    client_list.lock().unwrap().retain(|&x| x != addr);
                     NetworkEvent::Disconnect(addr)
                 }
             };
-            sender.send(event).unwrap();
+            events.lock().unwrap().push(event);
         }
     });
-    event_receiver
 }
 
+// Forgets a client that timed out, true if it was connected
+fn remove_client(
+    addr: SocketAddr,
+    client_list: &Mutex<Vec<SocketAddr>>,
+    client_acks: &Mutex<HashMap<SocketAddr, NetworkClientAck>>,
+    client_inputs: &Mutex<HashMap<SocketAddr, InputBuffer<Vec<u8>>>>,
+    client_user_data: &Mutex<HashMap<SocketAddr, Vec<u8>>>,
+) -> bool {
+    client_acks.lock().unwrap().remove(&addr);
+    client_inputs.lock().unwrap().remove(&addr);
+    client_user_data.lock().unwrap().remove(&addr);
+    let mut clients = client_list.lock().unwrap();
+    if !clients.contains(&addr) {
+        return false;
+    }
+    println!("Client {} disconnected!", addr);
+    clients.retain(|&x| x != addr);
+    true
+}
+
+/// What the server receive thread shares with the server systems.
+pub struct ServerReceiver {
+    /// Answers the handshakes.
+    pub sender: NetworkSender,
+    /// Connect tokens the clients need, any client with a matching handshake joins without it.
+    pub auth: Option<ServerAuth>,
+    pub client_list: Arc<Mutex<Vec<SocketAddr>>>,
+    pub client_acks: Arc<Mutex<HashMap<SocketAddr, NetworkClientAck>>>,
+    pub client_inputs: Arc<Mutex<HashMap<SocketAddr, InputBuffer<Vec<u8>>>>>,
+    pub client_user_data: Arc<Mutex<HashMap<SocketAddr, Vec<u8>>>>,
+}
+
+impl ServerReceiver {
+    // Keeps the ack and the commands of a client, its state is a message for the game
+    fn receive<T>(&mut self, addr: SocketAddr, net_client_state: NetworkClientState) -> Option<NetworkEvent>
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        match net_client_state.message {
+            ClientMessage::Connect(request) => return self.connect::<T>(addr, request),
+            ClientMessage::ChallengeResponse(nonce) => return self.respond::<T>(addr, nonce),
+            _ => {}
+        }
+        if !self.client_list.lock().unwrap().contains(&addr) {
+            return None;
+        }
+        let mut client_acks = self.client_acks.lock().unwrap();
+        let ack = client_acks.entry(addr).or_default();
+        // Packets can arrive out of order, keep the newest ack
+        if net_client_state.ack.last_frame > ack.last_frame {
+            *ack = net_client_state.ack.clone();
+        }
+        match net_client_state.message {
+            ClientMessage::State(state) => Some(NetworkEvent::Message(addr, Bytes::from(state))),
+            ClientMessage::Commands(commands) => {
+                let mut client_inputs = self.client_inputs.lock().unwrap();
+                let inputs = client_inputs
+                    .entry(addr)
+                    .or_insert_with(|| InputBuffer::new(INPUT_BUFFER_SIZE, INPUT_BUFFER_DEPTH));
+                // Duplicated and stale commands are dropped
+                for command in commands {
+                    inputs.receive(command);
+                }
+                None
+            }
+            ClientMessage::Connect(_) | ClientMessage::ChallengeResponse(_) | ClientMessage::Heartbeat => None,
+        }
+    }
+
+    // Answers the handshake, a client that sends it again is answered again
+    fn connect<T>(&mut self, addr: SocketAddr, request: ConnectRequest) -> Option<NetworkEvent>
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        if let Some(reason) = Handshake::new::<T>().mismatch(&request.handshake) {
+            return self.reject::<T>(addr, reason);
+        }
+        if self.client_list.lock().unwrap().contains(&addr) {
+            return self.accept::<T>(addr, vec![]);
+        }
+        let auth = match &mut self.auth {
+            Some(auth) => auth,
+            None => return self.accept::<T>(addr, vec![]),
+        };
+        let challenge = match &request.token {
+            Some(token) => auth.challenge(addr, token, SystemTime::now()).map_err(|e| e.to_string()),
+            None => Err("the server requires a connect token".to_string()),
+        };
+        match challenge {
+            Ok(nonce) => {
+                self.reply::<T>(addr, HandshakeOutcome::Challenge(nonce));
+                None
+            }
+            Err(reason) => self.reject::<T>(addr, reason),
+        }
+    }
+
+    fn respond<T>(&mut self, addr: SocketAddr, nonce: u64) -> Option<NetworkEvent>
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        // Stale or forged answers are dropped
+        match self.auth.as_mut()?.respond(addr, nonce)? {
+            Ok(user_data) => self.accept::<T>(addr, user_data),
+            Err(reason) => self.reject::<T>(addr, reason),
+        }
+    }
+
+    fn accept<T>(&self, addr: SocketAddr, user_data: Vec<u8>) -> Option<NetworkEvent>
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        self.reply::<T>(addr, HandshakeOutcome::Accepted);
+        let mut clients = self.client_list.lock().unwrap();
+        if clients.contains(&addr) {
+            return None;
+        }
+        println!("Client {} connected!", addr);
+        clients.push(addr);
+        self.sender.keepalive().track(addr);
+        self.client_user_data.lock().unwrap().insert(addr, user_data);
+        Some(NetworkEvent::Connect(addr))
+    }
+
+    fn reject<T>(&self, addr: SocketAddr, reason: String) -> Option<NetworkEvent>
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        println!("Client {} rejected: {}", addr, reason);
+        self.reply::<T>(addr, HandshakeOutcome::Rejected(reason));
+        None
+    }
+
+    fn reply<T>(&self, addr: SocketAddr, outcome: HandshakeOutcome)
+    where
+        T: CarrierPacket,
+        T::DeltaType: CarrierDeltaPacket,
+    {
+        let reply = HandshakeReply {
+            handshake: Handshake::new::<T>(),
+            outcome,
+        };
+        let packet = ServerPacket {
+            input_ack: InputAck::default(),
+            message: ServerMessage::<T>::Handshake(reply),
+        };
+        self.sender.send_frame(addr, &wire::to_bytes(&packet), DeliveryRequirement::Reliable);
+    }
+}
+
+#[cfg(feature = "laminar")]
 pub fn init_network<T>(world: &mut World, server: &str) -> Result<(), ErrorKind>
 where
     T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
     T::DeltaType: CarrierDeltaPacket + Debug,
 {
-    let mut socket = Socket::bind(server)?;
-    let sender = socket.get_packet_sender();
-    let receiver = socket.get_event_receiver();
-    let _thread = thread::spawn(move || socket.start_polling());
+    init_network_with_config::<T>(world, server, ServerConfig::default())
+}
+
+/// Binds a laminar socket on `server`, timing out the clients after the keepalive idle timeout,
+/// see [`init_server_transport_with_config`].
+#[cfg(feature = "laminar")]
+pub fn init_network_with_config<T>(world: &mut World, server: &str, config: ServerConfig) -> Result<(), ErrorKind>
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug,
+{
+    let socket = Socket::bind_with_config(server, laminar::config(&config.keepalive))?;
+    init_server_transport_with_config::<T, _>(world, socket, config);
+    Ok(())
+}
+
+pub fn init_server_transport<T, N>(world: &mut World, transport: N)
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug,
+    N: Transport,
+{
+    init_server_transport_with_config::<T, N>(world, transport, ServerConfig::default());
+}
+
+pub fn init_server_transport_with_config<T, N>(world: &mut World, transport: N, config: ServerConfig)
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug,
+    N: Transport,
+{
+    init_server::<T, N>(world, transport, None, config);
+}
+
+/// Server that only accepts the clients with a connect token `auth` accepts.
+pub fn init_server_transport_with_auth<T, N>(world: &mut World, transport: N, auth: ServerAuth)
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug,
+    N: Transport,
+{
+    init_server::<T, N>(world, transport, Some(auth), ServerConfig::default());
+}
+
+fn init_server<T, N>(world: &mut World, transport: N, auth: Option<ServerAuth>, config: ServerConfig)
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug,
+    N: Transport,
+{
+    let (sender, receiver) = transport.split();
     let client_list = ClientList::default();
+    let client_acks = ClientAcks::default();
+    let client_inputs = ClientInputs::default();
+    let client_user_data = ClientUserData::default();
     let network_controller = NetworkController::new(10);
 
-    // TODO: review event receiver logic, seems we could simplify it a bit
-    let events: Arc<Mutex<Vec<NetworkEvent>>> = Arc::new(Mutex::new(vec![]));
-    let snapshot = GameSnapshot(Arc::new(Mutex::new(T::new(&world, 0))));
+    let event_list = EventList(Arc::new(Mutex::new(vec![])));
 
-    let events_clone = events.clone();
-    let event_list = EventList(events);
-    let event_receiver = server_receive_network_system(receiver, client_list.clients.clone());
-    thread::spawn(move || loop {
-        if let Ok(event) = event_receiver.recv() {
-            let mut e = events_clone.lock().unwrap();
-            e.push(event);
-        }
-    });
     let network_sender = NetworkSender::new(sender);
+    let server = ServerReceiver {
+        sender: network_sender.clone(),
+        auth,
+        client_list: client_list.clients.clone(),
+        client_acks: client_acks.0.clone(),
+        client_inputs: client_inputs.0.clone(),
+        client_user_data: client_user_data.0.clone(),
+    };
+    server_receive_network_system::<T, _>(receiver, server, event_list.0.clone());
     world.add_unique(network_sender);
-    world.add_unique(snapshot);
+    world.add_unique(ClientHistories::<T>::default());
+    world.add_unique(config);
     world.add_unique(network_controller);
     world.add_unique(client_list);
+    world.add_unique(client_acks);
+    world.add_unique(client_inputs);
+    world.add_unique(client_user_data);
+    world.add_unique(ClientEntities::default());
     world.add_unique(event_list);
     world.add_unique(TransportResource::default());
-    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -224,145 +821,696 @@ where
 {
     Snapshot(T),
     Delta(T::DeltaType),
+    /// Part of a snapshot too big for a single packet.
+    Chunk(SnapshotChunk<T>),
+    Handshake(HandshakeReply),
+    /// Keeps the connection alive while the client is sent nothing else.
+    Heartbeat,
+}
+
+impl<T> NetSerialize for ServerMessage<T>
+where
+    T: Delta + NetSerialize,
+    T::DeltaType: NetSerialize,
+{
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        match self {
+            ServerMessage::Snapshot(snapshot) => {
+                writer.write_bits(0, 3);
+                snapshot.net_serialize(writer, quantize);
+            }
+            ServerMessage::Delta(delta) => {
+                writer.write_bits(1, 3);
+                delta.net_serialize(writer, quantize);
+            }
+            ServerMessage::Chunk(chunk) => {
+                writer.write_bits(2, 3);
+                chunk.net_serialize(writer, quantize);
+            }
+            ServerMessage::Handshake(reply) => {
+                writer.write_bits(3, 3);
+                reply.net_serialize(writer, quantize);
+            }
+            ServerMessage::Heartbeat => writer.write_bits(4, 3),
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        match reader.read_bits(3)? {
+            0 => Ok(ServerMessage::Snapshot(T::net_deserialize(reader, quantize)?)),
+            1 => Ok(ServerMessage::Delta(T::DeltaType::net_deserialize(reader, quantize)?)),
+            2 => Ok(ServerMessage::Chunk(SnapshotChunk::net_deserialize(reader, quantize)?)),
+            3 => Ok(ServerMessage::Handshake(HandshakeReply::net_deserialize(reader, quantize)?)),
+            4 => Ok(ServerMessage::Heartbeat),
+            tag => Err(WireError::Invalid(format!("unknown server message {}", tag))),
+        }
+    }
+}
+
+/// What the server knew about the client when it built a state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, NetSerialize)]
+pub struct InputAck {
+    /// Newest input command processed by the game, see [`take_inputs`].
+    pub input_tick: Option<u32>,
+    /// Network id of the entity the client controls.
+    pub entity: Option<u32>,
+}
+
+/// Server message with the client's [`InputAck`], `M` is a [`ServerMessage`].
+///
+/// The message starts on a new byte, so clients getting the same one share its bytes, see
+/// [`ServerPacket::encode`].
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerPacket<M> {
+    pub input_ack: InputAck,
+    pub message: M,
+}
+
+impl<M> ServerPacket<M> {
+    /// Same bytes as the packet, with `message` already serialized.
+    pub fn encode(input_ack: InputAck, message: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        input_ack.net_serialize(&mut writer, None);
+        writer.write_bytes(message);
+        writer.finish()
+    }
+}
+
+impl<M: NetSerialize> NetSerialize for ServerPacket<M> {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        self.input_ack.net_serialize(writer, quantize);
+        writer.align();
+        self.message.net_serialize(writer, quantize);
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        let input_ack = InputAck::net_deserialize(reader, quantize)?;
+        reader.align();
+        Ok(ServerPacket {
+            input_ack,
+            message: M::net_deserialize(reader, quantize)?,
+        })
+    }
+}
+
+/// What the client receive thread fills, shared with the client systems.
+pub struct ClientReceiver<T: Delta> {
+    pub jit_buffer: Arc<Mutex<JitterBuffer<T>>>,
+    pub snapshots: Arc<Mutex<StateHistory<T>>>,
+    pub pending_deltas: Arc<Mutex<PendingDeltas<T::DeltaType>>>,
+    pub network_client_ack: Arc<Mutex<NetworkClientAck>>,
+    pub input_ack: Arc<Mutex<Option<(u32, InputAck)>>>,
+    pub connection: Arc<Mutex<ConnectionState>>,
+    pub events: Arc<Mutex<Vec<NetworkEvent>>>,
+    /// Answers the challenge of the server and sends the handshake again to reconnect.
+    pub sender: NetworkSender,
+    /// Sent again until the server answers it.
+    pub connect: ConnectRequest,
+    /// See [`ClientConfig::reconnect`].
+    pub reconnect: bool,
+    /// When the handshake was last sent, on the keepalive clock.
+    pub connect_sent: Arc<Mutex<Instant>>,
+}
+
+impl<T: Delta> Clone for ClientReceiver<T> {
+    fn clone(&self) -> Self {
+        ClientReceiver {
+            jit_buffer: self.jit_buffer.clone(),
+            snapshots: self.snapshots.clone(),
+            pending_deltas: self.pending_deltas.clone(),
+            network_client_ack: self.network_client_ack.clone(),
+            input_ack: self.input_ack.clone(),
+            connection: self.connection.clone(),
+            events: self.events.clone(),
+            sender: self.sender.clone(),
+            connect: self.connect.clone(),
+            reconnect: self.reconnect,
+            connect_sent: self.connect_sent.clone(),
+        }
+    }
+}
+
+impl<T> ClientReceiver<T>
+where
+    T: CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    // The states received before are no baselines for the next connection
+    fn time_out(&self, server: SocketAddr) {
+        self.jit_buffer.lock().unwrap().clear();
+        self.snapshots.lock().unwrap().clear();
+        self.pending_deltas.lock().unwrap().clear();
+        *self.network_client_ack.lock().unwrap() = NetworkClientAck::default();
+        *self.input_ack.lock().unwrap() = None;
+
+        self.sender.forget(server);
+
+        let mut connection = self.connection.lock().unwrap();
+        if *connection == ConnectionState::Connected {
+            println!("Connection to {} timed out", server);
+            self.events.lock().unwrap().push(NetworkEvent::Disconnect(server));
+        }
+        // A rejected client stays rejected
+        if let ConnectionState::Disconnected(DisconnectReason::Rejected(_)) = *connection {
+            return;
+        }
+        *connection = if self.reconnect {
+            self.sender.keepalive().track(server);
+            self.send_connect(server);
+            ConnectionState::Reconnecting
+        } else {
+            self.sender.keepalive().forget(server);
+            ConnectionState::Disconnected(DisconnectReason::TimedOut)
+        };
+    }
+
+    fn send_connect(&self, server: SocketAddr) {
+        let connect = NetworkClientState {
+            ack: NetworkClientAck::default(),
+            message: ClientMessage::Connect(self.connect.clone()),
+        };
+        *self.connect_sent.lock().unwrap() = self.sender.keepalive().now();
+        self.sender
+            .send_frame(server, &bincode::serialize(&connect).unwrap(), DeliveryRequirement::Reliable);
+    }
 }
 
-pub fn client_receive_network_system<T>(
-    receiver: Receiver<SocketEvent>,
-    jit_buffer: Arc<Mutex<Vec<T>>>,
-    snapshots: Arc<Mutex<Vec<T>>>,
-    network_client_ack: Arc<Mutex<NetworkClientAck>>,
-    server: SocketAddr,
-) where
+pub fn client_receive_network_system<T, R>(receiver: R, shared: ClientReceiver<T>, server: SocketAddr)
+where
     T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Clone + Debug,
-    T::DeltaType: CarrierDeltaPacket + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug + Send,
+    R: TransportReceiver,
 {
-    thread::spawn(move || loop {
-        if let Ok(event) = receiver.recv() {
+    let timeouts = shared.clone();
+    let ClientReceiver {
+        jit_buffer,
+        snapshots,
+        pending_deltas,
+        network_client_ack,
+        input_ack,
+        connection,
+        events,
+        sender,
+        ..
+    } = shared;
+    let handshake = Handshake::new::<T>();
+    thread::spawn(move || {
+        let mut assembler = ChunkAssembler::<T>::new();
+        while let Ok(event) = receiver.recv() {
             match event {
-                // TODO: match every socket event type
-                SocketEvent::Packet(packet) if packet.addr() == server => {
-                    if let Ok(net_state) =
-                        bincode::deserialize::<ServerMessage<T>>(&packet.payload())
-                    {
+                TransportEvent::Packet(addr, payload) if addr == server => {
+                    sender.keepalive().received(server);
+                    // Messages batched in the datagram are handled in order
+                    for frame in wire::read_frames(&payload).unwrap_or_default() {
+                        let server_packet = match wire::from_bytes::<ServerPacket<ServerMessage<T>>>(frame) {
+                            Ok(server_packet) => server_packet,
+                            Err(e) => {
+                                println!("Error reading packet from {}: {}", server, e);
+                                continue;
+                            }
+                        };
                         let mut ack = network_client_ack.lock().unwrap();
                         let mut jit_buffer = jit_buffer.lock().unwrap();
-                        match net_state {
+                        let mut snapshots = snapshots.lock().unwrap();
+                        let mut pending_deltas = pending_deltas.lock().unwrap();
+                        let mut states = vec![];
+                        match server_packet.message {
                             ServerMessage::Snapshot(snapshot) => {
-                                ack.last_snapshot_frame = snapshot.frame();
-                                ack.last_frame = snapshot.frame();
-
-                                let mut snapshots = snapshots.lock().unwrap();
-                                jit_buffer.push(snapshot.clone());
-                                if snapshots.len() == 2 {
-                                    //TODO: meh it works for now, fixed 2 length
-                                    if snapshots[0].frame() < snapshots[1].frame() {
-                                        snapshots[0] = snapshot
+                                ack.last_snapshot_frame = ack.last_snapshot_frame.max(Some(snapshot.frame()));
+                                states.push(snapshot);
+                            }
+                            ServerMessage::Delta(delta) => match snapshots.get(delta.snapshot_frame()) {
+                                Some(snapshot) => match snapshot.validate_delta(&delta) {
+                                    Ok(()) => states.push(snapshot.apply(&delta)),
+                                    Err(e) => println!("Invalid delta from {}: {}", server, e),
+                                },
+                                None => pending_deltas.push(delta),
+                            },
+                            // Only complete snapshots are delta baselines, incomplete ones are
+                            // just shown
+                            ServerMessage::Chunk(chunk) => {
+                                for assembled in assembler.push(chunk, snapshots.latest()) {
+                                    if assembled.complete {
+                                        ack.last_snapshot_frame =
+                                            ack.last_snapshot_frame.max(Some(assembled.state.frame()));
+                                        states.push(assembled.state);
                                     } else {
-                                        snapshots[1] = snapshot
+                                        jit_buffer.push(assembled.state, Instant::now());
                                     }
-                                } else {
-                                    snapshots.push(snapshot);
                                 }
                             }
-                            ServerMessage::Delta(delta) => {
-                                let snapshots = snapshots.lock().unwrap();
-                                ack.last_frame = delta.frame();
-                                //TODO: if we don't find the snapshot we should the save the delta to apply when we get the snapshot
-                                if let Some(snapshot) = snapshots
-                                    .iter()
-                                    .find(|s| s.frame() == delta.snapshot_frame())
-                                {
-                                    jit_buffer.push(snapshot.apply(&delta));
+                            ServerMessage::Handshake(reply) => {
+                                let outcome = match handshake.mismatch(&reply.handshake) {
+                                    Some(reason) => HandshakeOutcome::Rejected(reason),
+                                    None => reply.outcome,
+                                };
+                                match outcome {
+                                    HandshakeOutcome::Accepted => {
+                                        let mut connection = connection.lock().unwrap();
+                                        if *connection != ConnectionState::Connected {
+                                            // Chunks of the previous connection can't be completed
+                                            if *connection == ConnectionState::Reconnecting {
+                                                assembler = ChunkAssembler::new();
+                                            }
+                                            *connection = ConnectionState::Connected;
+                                            events.lock().unwrap().push(NetworkEvent::Connect(server));
+                                        }
+                                    }
+                                    HandshakeOutcome::Challenge(nonce) => {
+                                        let response = NetworkClientState {
+                                            ack: NetworkClientAck::default(),
+                                            message: ClientMessage::ChallengeResponse(nonce),
+                                        };
+                                        let response = bincode::serialize(&response).unwrap();
+                                        sender.send_frame(server, &response, DeliveryRequirement::Reliable);
+                                    }
+                                    HandshakeOutcome::Rejected(reason) => {
+                                        println!("Connection to {} rejected: {}", server, reason);
+                                        sender.keepalive().forget(server);
+                                        let rejected = ConnectionState::Disconnected(DisconnectReason::Rejected(reason));
+                                        let previous = std::mem::replace(&mut *connection.lock().unwrap(), rejected);
+                                        if previous == ConnectionState::Connected {
+                                            events.lock().unwrap().push(NetworkEvent::Disconnect(server));
+                                        }
+                                    }
+                                }
+                            }
+                            ServerMessage::Heartbeat => {}
+                        };
+                        // Deltas that waited for their baseline don't carry an input ack
+                        if let Some(state) = states.first() {
+                            let mut input_ack = input_ack.lock().unwrap();
+                            if !matches!(*input_ack, Some((frame, _)) if frame >= state.frame()) {
+                                *input_ack = Some((state.frame(), server_packet.input_ack));
+                            }
+                        }
+                        // Every received state can be the baseline of the next deltas,
+                        // including the ones that were waiting for it
+                        while let Some(state) = states.pop() {
+                            for delta in pending_deltas.take(state.frame()) {
+                                match state.validate_delta(&delta) {
+                                    Ok(()) => states.push(state.apply(&delta)),
+                                    Err(e) => println!("Invalid delta from {}: {}", server, e),
                                 }
                             }
+                            ack.last_frame = ack.last_frame.max(Some(state.frame()));
+                            jit_buffer.push(state.clone(), Instant::now());
+                            snapshots.push(state);
+                        }
+                        if let Some(last_frame) = ack.last_frame {
+                            pending_deltas.expire(last_frame);
                         }
-                        jit_buffer.sort_by(|a, b| a.frame().cmp(&b.frame()));
                     }
                 }
-                _ => {}
+                TransportEvent::Timeout(addr) if addr == server => {
+                    assembler = ChunkAssembler::new();
+                    timeouts.time_out(server);
+                }
+                // The client is connected once the server accepts its handshake
+                TransportEvent::Connect(_) | TransportEvent::Packet(..) | TransportEvent::Timeout(_) => {}
             };
         }
     });
 }
 
 //TODO: pass types to Packet
+#[cfg(feature = "laminar")]
 pub fn init_client_network<T>(world: &mut World, addr: &str, server: &str) -> Result<(), ErrorKind>
 where
     T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
-    T::DeltaType: CarrierDeltaPacket + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug + Send + Sync,
+{
+    init_client_network_with_config::<T>(world, addr, server, ClientConfig::default())
+}
+
+/// Binds a laminar socket on `addr`, timing out the server after the keepalive idle timeout, see
+/// [`init_client_transport`].
+#[cfg(feature = "laminar")]
+pub fn init_client_network_with_config<T>(
+    world: &mut World,
+    addr: &str,
+    server: &str,
+    config: ClientConfig,
+) -> Result<(), ErrorKind>
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug + Send + Sync,
 {
-    let mut socket = Socket::bind(addr)?;
-    let server = server.parse().unwrap();
+    let socket = Socket::bind_with_config(addr, laminar::config(&config.keepalive))?;
+    init_client_transport::<T, _>(world, socket, server.parse().unwrap(), config);
+    Ok(())
+}
+
+pub fn init_client_transport<T, N>(world: &mut World, transport: N, server: SocketAddr, config: ClientConfig)
+where
+    T: 'static + DeserializeOwned + CarrierPacket + Sync + Send + Delta + Serialize + Clone + Debug,
+    T::DeltaType: CarrierDeltaPacket + Debug + Send + Sync,
+    N: Transport,
+{
+    let (sender, receiver) = transport.split();
     let net_id_mapping = NetworkIdMapping(HashMap::new());
-    let sender = socket.get_packet_sender();
-    let receiver = socket.get_event_receiver();
-    let _thread = thread::spawn(move || socket.start_polling());
-    let buffer: Arc<Mutex<Vec<T>>> = Arc::new(Mutex::new(vec![]));
-    let network_client_ack = Arc::new(Mutex::new(NetworkClientAck {
-        last_frame: 0,
-        last_snapshot_frame: 0,
-    }));
+    let buffer = Arc::new(Mutex::new(JitterBuffer::new(config.jitter_buffer.clone())));
+    let network_client_ack = Arc::new(Mutex::new(NetworkClientAck::default()));
     let network_ack = NetworkAck(network_client_ack);
-    let jit_buffer = JitBuffer(buffer);
-    let snapshots = ClientGameSnapshots(Arc::new(Mutex::new(vec![T::new(&world, 0)])));
-
-    client_receive_network_system::<T>(
-        receiver,
-        jit_buffer.0.clone(),
-        snapshots.0.clone(),
-        network_ack.0.clone(),
-        server,
-    );
+    let jit_buffer = ClientJitterBuffer(buffer);
+    let snapshots = ClientGameSnapshots(Arc::new(Mutex::new(StateHistory::new(
+        config.snapshot_history_size,
+    ))));
+    let input_ack = ClientInputAck(Arc::new(Mutex::new(None)));
+    let pending_deltas = ClientPendingDeltas::<T>(Arc::new(Mutex::new(PendingDeltas::new(
+        config.pending_deltas_size,
+        config.pending_delta_max_age,
+    ))));
+    let connection = ClientConnection(Arc::new(Mutex::new(ConnectionState::Connecting)));
+    let event_list = EventList(Arc::new(Mutex::new(vec![])));
     let network_sender = NetworkSender::new(sender);
+    let connect = ConnectRequest {
+        handshake: Handshake::new::<T>(),
+        token: config.connect_token.clone(),
+    };
+
+    let shared = ClientReceiver {
+        jit_buffer: jit_buffer.0.clone(),
+        snapshots: snapshots.0.clone(),
+        pending_deltas: pending_deltas.0.clone(),
+        network_client_ack: network_ack.0.clone(),
+        input_ack: input_ack.0.clone(),
+        connection: connection.0.clone(),
+        events: event_list.0.clone(),
+        sender: network_sender.clone(),
+        connect,
+        reconnect: config.reconnect,
+        connect_sent: Arc::new(Mutex::new(network_sender.keepalive().now())),
+    };
+    client_receive_network_system::<T, _>(receiver, shared.clone(), server);
+    // The server only sends states once it accepted the handshake
+    network_sender.keepalive().track(server);
+    shared.send_connect(server);
+    world.add_unique(shared);
     world.add_unique(net_id_mapping);
+    world.add_unique(connection);
+    world.add_unique(event_list);
     world.add_unique(snapshots);
+    world.add_unique(pending_deltas);
     world.add_unique(network_ack);
+    world.add_unique(input_ack);
+    world.add_unique(ClientCommands(CommandQueue::new(config.command_queue_size)));
     world.add_unique(network_sender);
     world.add_unique(jit_buffer);
+    world.add_unique(ClientInterpolation::<T>(None));
     world.add_unique(TransportResource::default());
-    Ok(())
+    world.add_unique(config);
 }
 
-pub struct GameSnapshot<T>(pub Arc<Mutex<T>>) 
-where T: 'static + Sync + Send + CarrierPacket + Serialize, T::DeltaType: CarrierDeltaPacket;
 pub struct ClientGameSnapshots<T>(
-    pub Arc<Mutex<Vec<T>>>,
+    pub Arc<Mutex<StateHistory<T>>>,
+) where T: 'static + Sync + Send + CarrierPacket + Serialize, T::DeltaType: CarrierDeltaPacket;
+pub struct ClientJitterBuffer<T>(
+    pub Arc<Mutex<JitterBuffer<T>>>,
 ) where T: 'static + Sync + Send + CarrierPacket + Serialize, T::DeltaType: CarrierDeltaPacket;
+/// Connection of the client to its server, the changes are also in its [`EventList`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// Waiting for the server to accept the handshake.
+    Connecting,
+    Connected,
+    Disconnected(DisconnectReason),
+    /// Timed out, the handshake was sent again.
+    Reconnecting,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    /// The server refused the handshake, see [`Handshake`] and [`ServerAuth`].
+    Rejected(String),
+    TimedOut,
+}
+
+pub struct ClientConnection(pub Arc<Mutex<ConnectionState>>);
+/// Newest received state built directly from a server message, with its input ack.
+pub struct ClientInputAck(pub Arc<Mutex<Option<(u32, InputAck)>>>);
+pub struct ClientPrediction<P: Predict>(pub Prediction<P>);
+/// Input commands sent and not yet processed by the server.
+pub struct ClientCommands(pub CommandQueue<Vec<u8>>);
+/// Last state released by the jitter buffer, the start of the interpolation.
+pub struct ClientInterpolation<T>(pub Option<T>);
+pub struct ClientPendingDeltas<T>(
+    pub Arc<Mutex<PendingDeltas<T::DeltaType>>>,
+) where T: 'static + Sync + Send + CarrierPacket + Serialize, T::DeltaType: CarrierDeltaPacket;
+
+/// Client a state is selected for, see [`NetworkSerializable`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetworkClient {
+    pub addr: SocketAddr,
+    /// Network id of the entity the client controls, see [`ClientEntities`].
+    pub entity: Option<u32>,
+}
+
+/// Chooses what each client receives: the world is captured once per tick, then a packet is
+/// selected from the capture for every client. Entities left out of a client's packet are
+/// removed on that client.
+pub trait NetworkSerializable {
+    type Capture;
+    /// A [`CarrierPacket`], sent as a delta against the client's previous one.
+    type Packet;
+
+    fn serialize(&mut self, world: &World, frame: u32) -> Self::Capture;
+    fn select_for_client(&mut self, client: &NetworkClient, capture: &Self::Capture) -> Self::Packet;
+}
+
+/// Sends the whole world to every client.
+pub struct WholeWorld<T>(PhantomData<T>);
+
+impl<T> Default for WholeWorld<T> {
+    fn default() -> Self {
+        WholeWorld(PhantomData)
+    }
+}
+
+impl<T> NetworkSerializable for WholeWorld<T>
+where
+    T: CarrierPacket + Clone,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    type Capture = T;
+    type Packet = T;
+
+    fn serialize(&mut self, world: &World, frame: u32) -> T {
+        T::new(world, frame)
+    }
+
+    fn select_for_client(&mut self, _client: &NetworkClient, capture: &T) -> T {
+        capture.clone()
+    }
+}
 
-pub fn update_server<T>(world: &mut World, frame: u32,) 
-where T: 'static + Sync + Send + CarrierPacket + Serialize + Clone, T::DeltaType: CarrierDeltaPacket {
-    let net_state = T::new(&world, frame);
+pub fn update_server<T>(world: &mut World, frame: u32,) -> Result<(), NetworkError>
+where T: 'static + Sync + Send + CarrierPacket + Serialize + Clone + PartialEq, T::DeltaType: CarrierDeltaPacket {
+    update_server_with(world, frame, &mut WholeWorld::<T>::default())
+}
+
+/// Sends each client the state `serializable` selects for it, as a delta against the newest
+/// state it acked.
+pub fn update_server_with<S>(world: &mut World, frame: u32, serializable: &mut S) -> Result<(), NetworkError>
+where
+    S: NetworkSerializable,
+    S::Packet: 'static + Sync + Send + CarrierPacket + Clone + PartialEq,
+    <S::Packet as Delta>::DeltaType: CarrierDeltaPacket,
+{
+    let capture = serializable.serialize(world, frame);
     world.run(
         |client_list: UniqueView<ClientList>,
+         client_acks: UniqueView<ClientAcks>,
+         client_inputs: UniqueView<ClientInputs>,
+         client_entities: UniqueView<ClientEntities>,
          mut transport: UniqueViewMut<TransportResource>,
-         mut snapshot: UniqueViewMut<GameSnapshot<T>>,
-         mut network_controller: UniqueViewMut<NetworkController>| {
+         mut histories: UniqueViewMut<ClientHistories<S::Packet>>,
+         config: UniqueView<ServerConfig>,
+         mut network_controller: UniqueViewMut<NetworkController>|
+         -> Result<(), NetworkError> {
             network_controller.tick();
-            if network_controller.is_snapshot_frame() {
-                *snapshot.0.lock().unwrap() = net_state.clone();
-                let server_message = ServerMessage::<T>::Snapshot(net_state);
-                let payload = bincode::serialize(&server_message).unwrap();
-                println!("Netpacket len: {:?}", payload.len());
-                transport.messages.push_back(Message::new(
-                    client_list.clients.lock().unwrap().clone(),
-                    &payload[..],
-                    DeliveryRequirement::Unreliable,
-                ));
-            } else {
-                let snapshot = snapshot.0.lock().unwrap();
-                let delta_packet = net_state.from(&snapshot).unwrap();
-                let server_message = ServerMessage::<T>::Delta(delta_packet);
-                let payload = bincode::serialize(&server_message).unwrap();
-                println!("Netpacket len: {:?}", payload.len());
-                transport.messages.push_back(Message::new(
-                    client_list.clients.lock().unwrap().clone(),
-                    &payload[..],
-                    DeliveryRequirement::ReliableSequenced(Some(1)),
-                ));
+            let clients = client_list.clients.lock().unwrap().clone();
+            histories.0.retain(|addr, _| clients.contains(addr));
+            network_controller.retain_clients(&clients);
+            let client_acks = client_acks.0.lock().unwrap();
+            let client_inputs = client_inputs.0.lock().unwrap();
+            // Room left for the biggest input ack and the length of the frame
+            let input_ack_len = wire::to_bytes(&InputAck {
+                input_tick: Some(u32::MAX),
+                entity: Some(u32::MAX),
+            })
+            .len();
+            let message_mtu = config.mtu.saturating_sub(input_ack_len + wire::frame_prefix_len(config.mtu));
+            // Clients getting the same state from the same baseline share its serialization
+            let mut shared: Vec<SharedMessage<S::Packet>> = vec![];
+            // Serializations by entities and baseline frame, only these are compared with a state
+            let mut shared_keys: HashMap<(u64, Option<u32>), Vec<usize>> = HashMap::new();
+            for addr in clients {
+                let input_ack = InputAck {
+                    input_tick: client_inputs.get(&addr).and_then(|inputs| inputs.last_processed()),
+                    entity: client_entities.0.get(&addr).copied(),
+                };
+                let client = NetworkClient {
+                    addr,
+                    entity: input_ack.entity,
+                };
+                let net_state = serializable.select_for_client(&client, &capture);
+                let history = histories
+                    .0
+                    .entry(addr)
+                    .or_insert_with(|| StateHistory::new(STATE_HISTORY_SIZE));
+                let baseline = client_acks
+                    .get(&addr)
+                    .and_then(|ack| ack.last_frame)
+                    .and_then(|frame| history.get(frame));
+                let mut hasher = DefaultHasher::new();
+                net_state.entities_id().hash(&mut hasher);
+                let candidates = shared_keys
+                    .entry((hasher.finish(), baseline.map(|baseline| baseline.frame())))
+                    .or_default();
+                let index = candidates.iter().copied().find(|&index| {
+                    shared[index].state == net_state && shared[index].baseline.as_ref() == baseline
+                });
+                let index = match index {
+                    Some(index) => index,
+                    None => {
+                        shared.push(serialize_message(&net_state, baseline, message_mtu)?);
+                        candidates.push(shared.len() - 1);
+                        shared.len() - 1
+                    }
+                };
+                let message = &shared[index];
+                let acked = client_acks.get(&addr).and_then(|ack| ack.last_frame);
+                if message.snapshot && !network_controller.send_snapshot(addr, frame, acked) {
+                    continue;
+                }
+                for bytes in &message.messages {
+                    let payload = ServerPacket::<ServerMessage<S::Packet>>::encode(input_ack, bytes);
+                    transport.messages.push_back(Message::new(vec![addr], payload, message.delivery));
+                }
+                // The next deltas start from what the client rebuilds, not from the exact state
+                if let Some(held) = &message.held {
+                    history.push(held.clone());
+                }
             }
+            Ok(())
         },
-    );
+    )?;
+    server_keepalive::<S::Packet>(world);
     world.run(server_send_network_system);
+    Ok(())
+}
+
+/// Disconnects the clients that sent nothing for the idle timeout, and queues a heartbeat for
+/// the ones that are sent nothing for the heartbeat interval.
+pub fn server_keepalive<T>(world: &World)
+where
+    T: 'static + Sync + Send + CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    world.run(
+        |network: UniqueView<NetworkSender>,
+         client_list: UniqueView<ClientList>,
+         client_acks: UniqueView<ClientAcks>,
+         client_inputs: UniqueView<ClientInputs>,
+         client_user_data: UniqueView<ClientUserData>,
+         events: UniqueView<EventList>,
+         config: UniqueView<ServerConfig>,
+         mut transport: UniqueViewMut<TransportResource>| {
+            let timed_out = network.keepalive().take_timed_out(&config.keepalive);
+            for addr in timed_out {
+                network.forget(addr);
+                if remove_client(addr, &client_list.clients, &client_acks.0, &client_inputs.0, &client_user_data.0) {
+                    events.0.lock().unwrap().push(NetworkEvent::Disconnect(addr));
+                }
+            }
+
+            let heartbeats = network.keepalive().heartbeats_due(&config.keepalive);
+            let heartbeat = wire::to_bytes(&ServerPacket {
+                input_ack: InputAck::default(),
+                message: ServerMessage::<T>::Heartbeat,
+            });
+            for addr in heartbeats {
+                // Any message sent this tick keeps the connection alive
+                if transport.messages.iter().all(|message| !message.destination.contains(&addr)) {
+                    transport
+                        .messages
+                        .push_back(Message::new(vec![addr], heartbeat.clone(), DeliveryRequirement::Unreliable));
+                }
+            }
+        },
+    );
+}
+
+/// Serialized messages sending `state` to the clients that have `baseline`.
+struct SharedMessage<T> {
+    state: T,
+    baseline: Option<T>,
+    /// State the client holds once it received the messages, quantized like the client sees it.
+    /// `None` when nothing is sent.
+    held: Option<T>,
+    /// Serialized [`ServerMessage`]s, several when the snapshot is split in chunks.
+    messages: Vec<Vec<u8>>,
+    delivery: DeliveryRequirement,
+    /// The messages are a full snapshot, see [`NetworkController::send_snapshot`].
+    snapshot: bool,
+}
+
+fn serialize_message<T>(state: &T, baseline: Option<&T>, mtu: usize) -> Result<SharedMessage<T>, NetworkError>
+where
+    T: CarrierPacket + Clone,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    let message = match baseline {
+        Some(baseline) => match state.from(baseline)? {
+            DeltaOutcome::Delta(delta_packet) => Some(ServerMessage::<T>::Delta(delta_packet)),
+            DeltaOutcome::Full => Some(ServerMessage::Snapshot(state.clone())),
+            DeltaOutcome::Unchanged => None,
+        },
+        None => Some(ServerMessage::Snapshot(state.clone())),
+    };
+    let delivery = match message {
+        Some(ServerMessage::Delta(_)) => DeliveryRequirement::ReliableSequenced(Some(1)),
+        _ => DeliveryRequirement::Unreliable,
+    };
+    let snapshot = matches!(message, Some(ServerMessage::Snapshot(_)));
+    let mut held = None;
+    let messages = match message {
+        Some(message) => {
+            let bytes = wire::to_bytes(&message);
+            held = match (&message, baseline) {
+                (ServerMessage::Delta(delta), Some(baseline)) => Some(baseline.apply(delta)),
+                // Read back from its bytes, with the wire quantization
+                _ => match wire::from_bytes::<ServerMessage<T>>(&bytes)? {
+                    ServerMessage::Snapshot(snapshot) => Some(snapshot),
+                    _ => None,
+                },
+            };
+            println!("Netpacket len: {:?}", bytes.len());
+            match message {
+                // Each chunk can be applied on its own, a lost one only delays its entities
+                ServerMessage::Snapshot(snapshot) if bytes.len() > mtu => {
+                    let chunks = fragment::split_snapshot(&snapshot, mtu, |chunk| {
+                        wire::to_bytes(&ServerMessage::<T>::Chunk(chunk.clone())).len()
+                    });
+                    chunks
+                        .into_iter()
+                        .map(|chunk| wire::to_bytes(&ServerMessage::<T>::Chunk(chunk)))
+                        .collect()
+                }
+                _ => vec![bytes],
+            }
+        }
+        None => vec![],
+    };
+    Ok(SharedMessage {
+        state: state.clone(),
+        baseline: baseline.cloned(),
+        held,
+        messages,
+        delivery,
+        snapshot,
+    })
 }
 
 pub fn update_client<T: Serialize>(world: &mut World, client_state: T, server: SocketAddr) {
@@ -370,9 +1518,231 @@ pub fn update_client<T: Serialize>(world: &mut World, client_state: T, server: S
     world.run(|mut transport: UniqueViewMut<TransportResource>| {
         transport.messages.push_back(Message::new(
             vec![server],
-            &encoded_client,
+            encoded_client,
             DeliveryRequirement::Unreliable,
         ));
     });
     world.run(client_send_network_system);
 }
+
+/// Sends the server a heartbeat when it was sent nothing for the heartbeat interval, and times
+/// the connection out when the server sent nothing for the idle timeout. Until the server
+/// answers, the handshake is sent again every [`ClientConfig::connect_interval`], in case it or
+/// its reply was lost. Call it every frame.
+pub fn client_keepalive<T>(world: &World, server: SocketAddr)
+where
+    T: 'static + Sync + Send + CarrierPacket,
+    T::DeltaType: CarrierDeltaPacket + Send,
+{
+    world.run(|shared: UniqueView<ClientReceiver<T>>, config: UniqueView<ClientConfig>| {
+        let timed_out = shared.sender.keepalive().take_timed_out(&config.keepalive).contains(&server);
+        if timed_out {
+            shared.time_out(server);
+            return;
+        }
+        let connecting = matches!(
+            *shared.connection.lock().unwrap(),
+            ConnectionState::Connecting | ConnectionState::Reconnecting
+        );
+        let since_connect = shared
+            .sender
+            .keepalive()
+            .now()
+            .saturating_duration_since(*shared.connect_sent.lock().unwrap());
+        if connecting && since_connect >= config.connect_interval {
+            shared.send_connect(server);
+        }
+        if shared.sender.keepalive().heartbeats_due(&config.keepalive).contains(&server) {
+            let heartbeat = NetworkClientState {
+                ack: shared.network_client_ack.lock().unwrap().clone(),
+                message: ClientMessage::Heartbeat,
+            };
+            shared
+                .sender
+                .send_frame(server, &bincode::serialize(&heartbeat).unwrap(), DeliveryRequirement::Unreliable);
+        }
+    });
+}
+
+/// Applies to the world every state the jitter buffer releases now, in frame order.
+/// Returns the frame of the last applied state.
+pub fn apply_client_state<T>(world: &World) -> Option<u32>
+where
+    T: 'static + Sync + Send + CarrierPacket + Serialize,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    let now = Instant::now();
+    let mut last_frame = None;
+    loop {
+        let state = {
+            let jit_buffer = world.borrow::<UniqueView<ClientJitterBuffer<T>>>();
+            let mut jit_buffer = jit_buffer.0.lock().unwrap();
+            jit_buffer.pop(now)
+        };
+        match state {
+            Some(state) => {
+                state.apply_state(world);
+                last_frame = Some(state.frame());
+            }
+            None => return last_frame,
+        }
+    }
+}
+
+/// Applies to the world the last state the jitter buffer released, interpolated towards the next
+/// buffered state at the current render time. Without a next state the last one is applied as is.
+/// Returns the frame of the interpolation start.
+pub fn interpolate_client_state<T>(world: &World) -> Option<u32>
+where
+    T: 'static + Sync + Send + CarrierPacket + Serialize,
+    T::DeltaType: CarrierDeltaPacket,
+{
+    let now = Instant::now();
+    let (from, interpolated) = world.run(
+        |jit_buffer: UniqueView<ClientJitterBuffer<T>>, mut interpolation: UniqueViewMut<ClientInterpolation<T>>| {
+            let mut jit_buffer = jit_buffer.0.lock().unwrap();
+            while let Some(state) = jit_buffer.pop(now) {
+                interpolation.0 = Some(state);
+            }
+            // Taken out while the world is updated, the views can't be borrowed by apply_state
+            let from = interpolation.0.take()?;
+            let interpolated = match (jit_buffer.peek(), jit_buffer.playout_frame(now)) {
+                (Some(next), Some(playout_frame)) if next.frame() > from.frame() => {
+                    let ticks = (next.frame() - from.frame()) as f64;
+                    let t = ((playout_frame - from.frame() as f64) / ticks).clamp(0.0, 1.0);
+                    Some(from.interpolate_state(next, t as f32))
+                }
+                _ => None,
+            };
+            Some((from, interpolated))
+        },
+    )?;
+
+    interpolated.as_ref().unwrap_or(&from).apply_state(world);
+    let frame = from.frame();
+    world.run(|mut interpolation: UniqueViewMut<ClientInterpolation<T>>| interpolation.0 = Some(from));
+    Some(frame)
+}
+
+/// Sends `input` as the command of the next tick, returns that tick.
+pub fn send_input<I: Serialize>(world: &World, input: &I, server: SocketAddr) -> u32 {
+    let tick = world.run(|commands: UniqueView<ClientCommands>, input_ack: UniqueView<ClientInputAck>| {
+        let acked = (*input_ack.0.lock().unwrap()).and_then(|(_, input_ack)| input_ack.input_tick);
+        commands.0.newest_tick().max(acked).map_or(1, |tick| tick + 1)
+    });
+    send_command(world, tick, input, server);
+    tick
+}
+
+/// Queues the command of `tick` and sends the newest commands the server didn't process yet.
+pub fn send_command<I: Serialize>(world: &World, tick: u32, input: &I, server: SocketAddr) {
+    let input = match bincode::serialize(input) {
+        Ok(input) => input,
+        Err(e) => {
+            println!("Error serializing input: {}", e);
+            return;
+        }
+    };
+    world.run(
+        |network: UniqueView<NetworkSender>,
+         network_ack: UniqueView<NetworkAck>,
+         input_ack: UniqueView<ClientInputAck>,
+         config: UniqueView<ClientConfig>,
+         mut commands: UniqueViewMut<ClientCommands>| {
+            if let Some((_, InputAck { input_tick: Some(processed), .. })) = *input_ack.0.lock().unwrap() {
+                commands.0.ack(processed);
+            }
+            commands.0.push(tick, input);
+            let net_state = NetworkClientState {
+                ack: network_ack.0.lock().unwrap().clone(),
+                message: ClientMessage::Commands(commands.0.unacked(config.commands_per_packet)),
+            };
+            // Lost packets are covered by the next ones, which resend the same commands
+            network.send_frame(server, &bincode::serialize(&net_state).unwrap(), DeliveryRequirement::Unreliable);
+        },
+    );
+}
+
+/// Commands of each client to process this tick, in tick order. One per client, more for the
+/// clients with more commands waiting than [`INPUT_BUFFER_DEPTH`].
+pub fn take_inputs<I: DeserializeOwned>(world: &World) -> Vec<(SocketAddr, InputCommand<I>)> {
+    world.run(|client_inputs: UniqueView<ClientInputs>| {
+        let mut client_inputs = client_inputs.0.lock().unwrap();
+        client_inputs
+            .iter_mut()
+            .flat_map(|(&addr, inputs)| inputs.take_due().into_iter().map(move |command| (addr, command)))
+            .filter_map(|(addr, command)| match bincode::deserialize(&command.input) {
+                Ok(input) => Some((addr, InputCommand { tick: command.tick, input })),
+                Err(e) => {
+                    println!("Error deserializing input from {}: {}", addr, e);
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+pub fn init_client_prediction<P>(world: &mut World, capacity: usize)
+where
+    P: 'static + Predict + Send + Sync,
+    P::Input: Send + Sync,
+{
+    world.add_unique(ClientPrediction(Prediction::<P>::new(capacity)));
+}
+
+/// Reconciles with the newest server state, predicts the next client tick with `input`
+/// and sends the input to the server. Returns the tick of the input.
+pub fn predict_client<T, P>(world: &mut World, input: P::Input, server: SocketAddr) -> u32
+where
+    T: 'static + Sync + Send + CarrierPacket + Serialize,
+    T::DeltaType: CarrierDeltaPacket,
+    P: 'static + Predict<State = T> + Send + Sync,
+    P::Input: Serialize + Send + Sync,
+{
+    let tick = world.run(
+        |mut prediction: UniqueViewMut<ClientPrediction<P>>,
+         input_ack: UniqueView<ClientInputAck>,
+         snapshots: UniqueView<ClientGameSnapshots<T>>| {
+            if let Some((frame, input_ack)) = *input_ack.0.lock().unwrap() {
+                let snapshots = snapshots.0.lock().unwrap();
+                let server_state = match (input_ack.entity, snapshots.get(frame)) {
+                    (Some(net_id), Some(state)) if prediction.0.reconciled_frame() < Some(frame) => {
+                        P::from_state(state, net_id)
+                    }
+                    _ => None,
+                };
+                if let Some(server_state) = server_state {
+                    prediction.0.reconcile(frame, server_state, input_ack.input_tick);
+                }
+            }
+            prediction.0.push_input(input.clone())
+        },
+    );
+    send_command(world, tick, &input, server);
+    tick
+}
+
+/// Writes the predicted state to the local entity, it has to run after the server states
+/// are applied. Returns false if nothing is predicted yet or the entity isn't spawned.
+pub fn apply_prediction<P>(world: &World) -> bool
+where
+    P: 'static + Predict + Send + Sync,
+    P::Input: Send + Sync,
+{
+    let predicted = world.run(
+        |prediction: UniqueView<ClientPrediction<P>>,
+         input_ack: UniqueView<ClientInputAck>,
+         net_id_mapping: UniqueView<NetworkIdMapping>| {
+            let (_, input_ack) = (*input_ack.0.lock().unwrap())?;
+            let entity = *net_id_mapping.0.get(&input_ack.entity?)?;
+            Some((prediction.0.predicted()?.clone(), entity))
+        },
+    );
+    match predicted {
+        Some((predicted, entity)) => {
+            predicted.apply(world, entity);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,550 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::{DeliveryRequirement, Transport, TransportError, TransportEvent, TransportReceiver, TransportSender};
+
+// Packets that would wait longer than this for the bandwidth are dropped
+const MAX_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// Simulated network between the transport and the other peers.
+///
+/// Latency and jitter apply in both directions, the rest only to sent packets: received ones
+/// already went through the wrapped transport's reliability, losing them there would never be
+/// recovered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkProfile {
+    pub latency: Duration,
+    /// Random delay added to the latency, up to this value.
+    pub jitter: Duration,
+    /// Probability of losing a packet and starting a loss burst.
+    pub loss: f64,
+    /// Probability of losing the next packet too while in a burst.
+    pub loss_burst: f64,
+    pub duplication: f64,
+    /// Probability of holding a packet for `reorder_delay` more, so newer ones overtake it.
+    pub reordering: f64,
+    pub reorder_delay: Duration,
+    /// Bytes per second sent, packets queue up above it.
+    pub bandwidth: Option<u32>,
+    pub seed: u64,
+}
+
+impl LinkProfile {
+    /// No latency and no loss, the packets go straight through.
+    pub fn perfect() -> Self {
+        LinkProfile {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            loss: 0.0,
+            loss_burst: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_millis(0),
+            bandwidth: None,
+            seed: 0,
+        }
+    }
+
+    pub fn good() -> Self {
+        LinkProfile {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(5),
+            loss: 0.005,
+            ..LinkProfile::perfect()
+        }
+    }
+
+    pub fn average() -> Self {
+        LinkProfile {
+            latency: Duration::from_millis(60),
+            jitter: Duration::from_millis(20),
+            loss: 0.02,
+            loss_burst: 0.2,
+            duplication: 0.005,
+            reordering: 0.01,
+            reorder_delay: Duration::from_millis(30),
+            ..LinkProfile::perfect()
+        }
+    }
+
+    pub fn bad() -> Self {
+        LinkProfile {
+            latency: Duration::from_millis(150),
+            jitter: Duration::from_millis(60),
+            loss: 0.08,
+            loss_burst: 0.5,
+            duplication: 0.02,
+            reordering: 0.05,
+            reorder_delay: Duration::from_millis(50),
+            bandwidth: Some(32 * 1024),
+            ..LinkProfile::perfect()
+        }
+    }
+}
+
+impl Default for LinkProfile {
+    fn default() -> Self {
+        LinkProfile::perfect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStats {
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// Packets dropped because the bandwidth queue was full.
+    pub congested: u64,
+}
+
+struct Link {
+    profile: LinkProfile,
+    rng: StdRng,
+    stats: LinkStats,
+    in_burst: bool,
+    // When the bandwidth is free for the next packet
+    free_at: Option<Instant>,
+    // Arrival of the last received packet, the next ones can't overtake it
+    last_incoming: Option<Instant>,
+}
+
+impl Link {
+    fn new(profile: LinkProfile) -> Self {
+        Link {
+            rng: StdRng::seed_from_u64(profile.seed),
+            profile,
+            stats: LinkStats::default(),
+            in_burst: false,
+            free_at: None,
+            last_incoming: None,
+        }
+    }
+
+    // When each copy of the sent packet arrives, none if it is lost
+    fn condition(&mut self, size: usize, lossy: bool, unordered: bool, now: Instant) -> Vec<Instant> {
+        let profile = &self.profile;
+
+        let lose_probability = if self.in_burst { profile.loss_burst } else { profile.loss };
+        if lossy {
+            self.in_burst = self.rng.gen_bool(lose_probability);
+            if self.in_burst {
+                self.stats.lost += 1;
+                return vec![];
+            }
+        }
+
+        let mut departure = now;
+        if let Some(bandwidth) = profile.bandwidth {
+            departure = self.free_at.map_or(now, |free_at| free_at.max(now));
+            if departure - now > MAX_QUEUE_DELAY {
+                self.stats.congested += 1;
+                return vec![];
+            }
+            self.free_at = Some(departure + Duration::from_secs_f64(size as f64 / bandwidth as f64));
+        }
+
+        let copies = if lossy && self.rng.gen_bool(profile.duplication) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let rng = &mut self.rng;
+        let stats = &mut self.stats;
+        (0..copies)
+            .map(|_| {
+                let mut delay = profile.latency + profile.jitter.mul_f64(rng.gen::<f64>());
+                if unordered && rng.gen_bool(profile.reordering) {
+                    stats.reordered += 1;
+                    delay += profile.reorder_delay;
+                }
+                departure + delay
+            })
+            .collect()
+    }
+
+    // When the received packet is handed over, only delayed and in the order it came in
+    fn delay_incoming(&mut self, now: Instant) -> Instant {
+        let delay = self.profile.latency + self.profile.jitter.mul_f64(self.rng.gen::<f64>());
+        let at = self.last_incoming.map_or(now + delay, |last| (now + delay).max(last));
+        self.last_incoming = Some(at);
+        at
+    }
+}
+
+/// Profile of a [`LinkConditioner`], add it to the world as a unique to switch it at runtime.
+#[derive(Clone)]
+pub struct LinkConditions(Arc<Mutex<Link>>);
+
+impl LinkConditions {
+    /// Switches to `profile`, its seed restarts the random sequence.
+    pub fn set_profile(&self, profile: LinkProfile) {
+        let mut link = self.0.lock().unwrap();
+        let stats = link.stats.clone();
+        *link = Link::new(profile);
+        link.stats = stats;
+    }
+
+    pub fn profile(&self) -> LinkProfile {
+        self.0.lock().unwrap().profile.clone()
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.0.lock().unwrap().stats.clone()
+    }
+}
+
+struct Scheduled<T> {
+    at: Instant,
+    order: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Scheduled<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at && self.order == other.order
+    }
+}
+
+impl<T> Eq for Scheduled<T> {}
+
+impl<T> PartialOrd for Scheduled<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed so the heap pops the earliest packet first
+impl<T> Ord for Scheduled<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.order).cmp(&(self.at, self.order))
+    }
+}
+
+struct Queue<T> {
+    scheduled: BinaryHeap<Scheduled<T>>,
+    order: u64,
+    closed: bool,
+}
+
+struct SchedulerShared<T> {
+    queue: Mutex<Queue<T>>,
+    wake: Condvar,
+}
+
+// Hands each item to `deliver` at its time, from its own thread. The thread stops once the
+// scheduler is dropped and the items left are delivered, or when `deliver` returns false.
+struct Scheduler<T>(Arc<SchedulerShared<T>>);
+
+impl<T: Send + 'static> Scheduler<T> {
+    fn start<F: Fn(T) -> bool + Send + 'static>(deliver: F) -> Arc<Self> {
+        let shared = Arc::new(SchedulerShared {
+            queue: Mutex::new(Queue {
+                scheduled: BinaryHeap::new(),
+                order: 0,
+                closed: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::spawn(move || {
+            let shared = thread_shared;
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                let now = Instant::now();
+                queue = match queue.scheduled.peek().map(|scheduled| scheduled.at) {
+                    Some(at) if at <= now => {
+                        let scheduled = queue.scheduled.pop().unwrap();
+                        drop(queue);
+                        let delivered = deliver(scheduled.item);
+                        let mut queue = shared.queue.lock().unwrap();
+                        if !delivered {
+                            queue.closed = true;
+                            queue.scheduled.clear();
+                            return;
+                        }
+                        queue
+                    }
+                    Some(at) => shared.wake.wait_timeout(queue, at - now).unwrap().0,
+                    None if queue.closed => return,
+                    None => shared.wake.wait(queue).unwrap(),
+                };
+            }
+        });
+        Arc::new(Scheduler(shared))
+    }
+
+    // False once the thread stopped
+    fn schedule(&self, at: Instant, item: T) -> bool {
+        let mut queue = self.0.queue.lock().unwrap();
+        if queue.closed {
+            return false;
+        }
+        let order = queue.order;
+        queue.order += 1;
+        queue.scheduled.push(Scheduled { at, order, item });
+        self.0.wake.notify_one();
+        true
+    }
+}
+
+impl<T> Drop for Scheduler<T> {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().closed = true;
+        self.0.wake.notify_one();
+    }
+}
+
+/// Wraps a transport to simulate a bad network with the given [`LinkProfile`].
+///
+/// Outgoing reliable packets are only delayed, the wrapped transport doesn't know about the
+/// losses. Incoming packets are only delayed, in order.
+pub struct LinkConditioner<N> {
+    transport: N,
+    conditions: LinkConditions,
+}
+
+impl<N: Transport> LinkConditioner<N> {
+    pub fn new(transport: N, profile: LinkProfile) -> Self {
+        LinkConditioner {
+            transport,
+            conditions: LinkConditions(Arc::new(Mutex::new(Link::new(profile)))),
+        }
+    }
+
+    pub fn conditions(&self) -> LinkConditions {
+        self.conditions.clone()
+    }
+}
+
+impl<N: Transport> Transport for LinkConditioner<N> {
+    type Sender = ConditionedSender;
+    type Receiver = ConditionedReceiver;
+
+    fn split(self) -> (ConditionedSender, ConditionedReceiver) {
+        let (sender, receiver) = self.transport.split();
+        let sender = Arc::new(sender);
+        let delivering = sender.clone();
+        let outgoing = Scheduler::start(move |(addr, payload, delivery)| {
+            if let Err(e) = delivering.send(addr, payload, delivery) {
+                println!("Send Error sending message: {}", e);
+            }
+            true
+        });
+
+        let (events, events_receiver) = crossbeam_channel::unbounded();
+        let incoming_events = events.clone();
+        let incoming = Scheduler::start(move |event| incoming_events.send(event).is_ok());
+        let conditions = self.conditions.clone();
+        thread::spawn(move || pump(receiver, conditions, incoming, events));
+
+        (
+            ConditionedSender {
+                conditions: self.conditions,
+                outgoing,
+                sender,
+            },
+            ConditionedReceiver(events_receiver),
+        )
+    }
+}
+
+// Delays the received packets, the connection events go through as they are
+fn pump<R: TransportReceiver>(
+    receiver: R,
+    conditions: LinkConditions,
+    incoming: Arc<Scheduler<TransportEvent>>,
+    events: Sender<TransportEvent>,
+) {
+    while let Ok(event) = receiver.recv() {
+        match event {
+            TransportEvent::Packet(addr, payload) => {
+                let at = conditions.0.lock().unwrap().delay_incoming(Instant::now());
+                if !incoming.schedule(at, TransportEvent::Packet(addr, payload)) {
+                    return;
+                }
+            }
+            event => {
+                if events.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+pub struct ConditionedSender {
+    conditions: LinkConditions,
+    outgoing: Arc<Scheduler<(SocketAddr, Bytes, DeliveryRequirement)>>,
+    sender: Arc<dyn TransportSender>,
+}
+
+impl TransportSender for ConditionedSender {
+    fn send(&self, addr: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Result<(), TransportError> {
+        let lossy = matches!(
+            delivery,
+            DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_)
+        );
+        let unordered = matches!(delivery, DeliveryRequirement::Unreliable | DeliveryRequirement::Reliable);
+        let arrivals = self
+            .conditions
+            .0
+            .lock()
+            .unwrap()
+            .condition(payload.len(), lossy, unordered, Instant::now());
+        for at in arrivals {
+            if !self.outgoing.schedule(at, (addr, payload.clone(), delivery)) {
+                return Err(TransportError::Disconnected);
+            }
+        }
+        Ok(())
+    }
+
+    fn forget(&self, addr: SocketAddr) {
+        self.sender.forget(addr);
+    }
+}
+
+pub struct ConditionedReceiver(Receiver<TransportEvent>);
+
+impl TransportReceiver for ConditionedReceiver {
+    fn recv(&self) -> Result<TransportEvent, TransportError> {
+        self.0.recv().map_err(|_| TransportError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback::LoopbackNetwork;
+
+    fn arrivals(link: &mut Link, packets: usize, now: Instant) -> Vec<Vec<Instant>> {
+        (0..packets).map(|_| link.condition(100, true, true, now)).collect()
+    }
+
+    #[test]
+    fn same_seed_same_network() {
+        let now = Instant::now();
+        let mut first = Link::new(LinkProfile::bad());
+        let mut second = Link::new(LinkProfile::bad());
+        assert_eq!(arrivals(&mut first, 200, now), arrivals(&mut second, 200, now));
+        assert_eq!(first.stats, second.stats);
+        assert!(first.stats.lost > 0);
+    }
+
+    #[test]
+    fn losses_come_in_bursts() {
+        let mut link = Link::new(LinkProfile {
+            loss: 0.05,
+            loss_burst: 0.9,
+            seed: 3,
+            ..LinkProfile::perfect()
+        });
+        let lost: Vec<bool> = arrivals(&mut link, 1000, Instant::now()).iter().map(|copies| copies.is_empty()).collect();
+        let bursts = lost.windows(2).filter(|pair| !pair[0] && pair[1]).count();
+        let lost = lost.iter().filter(|&&lost| lost).count();
+        assert!(lost > bursts * 3, "{} lost in {} bursts", lost, bursts);
+    }
+
+    #[test]
+    fn reliable_packets_are_not_lost() {
+        let mut link = Link::new(LinkProfile {
+            loss: 1.0,
+            duplication: 1.0,
+            ..LinkProfile::perfect()
+        });
+        assert_eq!(link.condition(100, false, true, Instant::now()).len(), 1);
+        assert!(link.condition(100, true, true, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn incoming_packets_only_delayed() {
+        let now = Instant::now();
+        let mut link = Link::new(LinkProfile {
+            jitter: Duration::from_millis(50),
+            ..LinkProfile::bad()
+        });
+        let arrivals: Vec<Instant> = (0..200).map(|_| link.delay_incoming(now)).collect();
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(arrivals.iter().all(|&at| at >= now + Duration::from_millis(150)));
+        assert_eq!(link.stats, LinkStats::default());
+    }
+
+    #[test]
+    fn bandwidth_spaces_packets() {
+        let now = Instant::now();
+        let mut link = Link::new(LinkProfile {
+            bandwidth: Some(1000),
+            ..LinkProfile::perfect()
+        });
+        let arrivals = arrivals(&mut link, 3, now);
+        assert_eq!(arrivals[1][0] - now, Duration::from_millis(100));
+        assert_eq!(arrivals[2][0] - now, Duration::from_millis(200));
+
+        let mut congested = 0;
+        for _ in 0..20 {
+            if link.condition(100, true, true, now).is_empty() {
+                congested += 1;
+            }
+        }
+        assert_eq!(congested, 12);
+        assert_eq!(link.stats.congested, 12);
+    }
+
+    #[test]
+    fn scheduler_threads_stop() {
+        // The thread drops `deliver` and its channel end when it stops
+        let (delivered, received) = crossbeam_channel::unbounded();
+        let scheduler = Scheduler::start(move |item| delivered.send(item).is_ok());
+        assert!(scheduler.schedule(Instant::now() + Duration::from_millis(20), 1));
+        drop(scheduler);
+        assert_eq!(received.recv(), Ok(1));
+        assert!(received.recv().is_err());
+
+        // Stops at the first item it fails to deliver
+        let (delivered, received) = crossbeam_channel::unbounded();
+        let scheduler = Scheduler::start(move |item| {
+            delivered.send(item).unwrap();
+            item != 0
+        });
+        assert!(scheduler.schedule(Instant::now(), 0));
+        assert_eq!(received.recv(), Ok(0));
+        assert!(received.recv().is_err());
+        assert!(!scheduler.schedule(Instant::now(), 1));
+    }
+
+    #[test]
+    fn delays_packets_through_transport() {
+        let network = LoopbackNetwork::new();
+        let server = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let (_, receiver) = network.bind(server).split();
+        let client = LinkConditioner::new(
+            network.bind(SocketAddr::from(([127, 0, 0, 1], 4001))),
+            LinkProfile {
+                latency: Duration::from_millis(30),
+                ..LinkProfile::perfect()
+            },
+        );
+        let conditions = client.conditions();
+        let (sender, _) = client.split();
+
+        let sent = Instant::now();
+        sender.send(server, Bytes::from(vec![1]), DeliveryRequirement::Reliable).unwrap();
+        assert!(matches!(receiver.recv(), Ok(TransportEvent::Connect(_))));
+        assert!(matches!(receiver.recv(), Ok(TransportEvent::Packet(_, _))));
+        assert!(sent.elapsed() >= Duration::from_millis(30));
+
+        conditions.set_profile(LinkProfile::perfect());
+        sender.send(server, Bytes::from(vec![2]), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(receiver.recv().unwrap(), TransportEvent::Packet(SocketAddr::from(([127, 0, 0, 1], 4001)), vec![2]));
+    }
+}
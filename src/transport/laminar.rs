@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+use std::thread;
+
+use ::laminar::{Config, Packet, Socket, SocketEvent};
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, SendError, Sender};
+
+use super::super::keepalive::KeepaliveConfig;
+use super::{DeliveryRequirement, Transport, TransportError, TransportEvent, TransportReceiver, TransportSender};
+
+/// laminar settings matching `keepalive`: laminar times the peers out after the same idle timeout,
+/// the heartbeats are sent by [`client_keepalive`](super::client_keepalive) and
+/// [`server_keepalive`](super::server_keepalive).
+pub fn config(keepalive: &KeepaliveConfig) -> Config {
+    Config {
+        idle_connection_timeout: keepalive.idle_timeout,
+        ..Config::default()
+    }
+}
+
+pub struct LaminarSender(Sender<Packet>);
+
+pub struct LaminarReceiver(Receiver<SocketEvent>);
+
+/// laminar over UDP, the socket is polled in its own thread.
+impl Transport for Socket {
+    type Sender = LaminarSender;
+    type Receiver = LaminarReceiver;
+
+    fn split(mut self) -> (LaminarSender, LaminarReceiver) {
+        let sender = self.get_packet_sender();
+        let receiver = self.get_event_receiver();
+        let _thread = thread::spawn(move || self.start_polling());
+        (LaminarSender(sender), LaminarReceiver(receiver))
+    }
+}
+
+impl TransportSender for LaminarSender {
+    fn send(&self, addr: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Result<(), TransportError> {
+        // laminar packets own their payload
+        let payload = payload.to_vec();
+        let packet = match delivery {
+            DeliveryRequirement::Reliable => Packet::reliable_unordered(addr, payload),
+            DeliveryRequirement::Unreliable => Packet::unreliable(addr, payload),
+            DeliveryRequirement::UnreliableSequenced(stream_id) => {
+                Packet::unreliable_sequenced(addr, payload, stream_id)
+            }
+            DeliveryRequirement::ReliableSequenced(stream_id) => {
+                Packet::reliable_sequenced(addr, payload, stream_id)
+            }
+            DeliveryRequirement::ReliableOrdered(stream_id) => Packet::reliable_ordered(addr, payload, stream_id),
+        };
+        self.0.send(packet).map_err(|SendError(_)| TransportError::Disconnected)
+    }
+}
+
+impl TransportReceiver for LaminarReceiver {
+    fn recv(&self) -> Result<TransportEvent, TransportError> {
+        let event = self.0.recv().map_err(|_| TransportError::Disconnected)?;
+        Ok(match event {
+            SocketEvent::Packet(packet) => TransportEvent::Packet(packet.addr(), packet.payload().to_vec()),
+            SocketEvent::Connect(addr) => TransportEvent::Connect(addr),
+            SocketEvent::Timeout(addr) => TransportEvent::Timeout(addr),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn times_out_after_idle_timeout() {
+        let keepalive = KeepaliveConfig {
+            heartbeat_interval: Duration::from_secs(60),
+            idle_timeout: Duration::from_millis(200),
+        };
+        let bind = || Socket::bind_with_config("127.0.0.1:0", config(&keepalive)).unwrap();
+        let (first, second) = (bind(), bind());
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let (first_sender, first_receiver) = first.split();
+        let (second_sender, second_receiver) = second.split();
+
+        // laminar keeps the connection once both ends sent something
+        let start = Instant::now();
+        let ping = Bytes::from_static(b"ping");
+        first_sender.send(second_addr, ping.clone(), DeliveryRequirement::Unreliable).unwrap();
+        while second_receiver.recv().unwrap() != TransportEvent::Packet(first_addr, ping.to_vec()) {}
+        second_sender.send(first_addr, ping, DeliveryRequirement::Unreliable).unwrap();
+        while first_receiver.recv().unwrap() != TransportEvent::Timeout(second_addr) {}
+        // Not after laminar's default of 5 seconds
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,288 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::{DeliveryRequirement, Transport, TransportError, TransportEvent, TransportReceiver, TransportSender};
+
+/// Link simulated between every pair of endpoints, applied to each packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopbackConditions {
+    /// Steps before a packet is delivered, 0 delivers it when sent.
+    pub latency: u32,
+    /// Probability of losing an unreliable packet, reliable ones are only delayed.
+    pub loss: f64,
+    /// Probability of delivering an unreliable packet twice.
+    pub duplication: f64,
+    /// Probability of delaying an unordered packet one more step, so newer ones overtake it.
+    pub reordering: f64,
+    pub seed: u64,
+}
+
+impl Default for LoopbackConditions {
+    fn default() -> Self {
+        LoopbackConditions {
+            latency: 0,
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    payload: Bytes,
+}
+
+struct Endpoint {
+    sender: Sender<TransportEvent>,
+    // Endpoints this one received packets from
+    peers: HashSet<SocketAddr>,
+}
+
+struct Hub {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    // Ordered by deliver_at, then by send order
+    queue: Vec<InFlight>,
+    now: u64,
+    conditions: LoopbackConditions,
+    rng: StdRng,
+    // Events delivered to an endpoint and not yet processed by its receiver
+    unprocessed: usize,
+}
+
+impl Hub {
+    fn deliver(&mut self, packet: InFlight) {
+        let endpoint = match self.endpoints.get_mut(&packet.to) {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        let mut events = vec![];
+        if endpoint.peers.insert(packet.from) {
+            events.push(TransportEvent::Connect(packet.from));
+        }
+        events.push(TransportEvent::Packet(packet.from, packet.payload.to_vec()));
+        for event in events {
+            if endpoint.sender.send(event).is_ok() {
+                self.unprocessed += 1;
+            }
+        }
+    }
+
+    fn enqueue(&mut self, packet: InFlight) {
+        if packet.deliver_at <= self.now {
+            self.deliver(packet);
+        } else {
+            let index = self.queue.iter().position(|queued| queued.deliver_at > packet.deliver_at);
+            let index = index.unwrap_or(self.queue.len());
+            self.queue.insert(index, packet);
+        }
+    }
+}
+
+/// In-process network where endpoints exchange packets through channels.
+///
+/// Time only moves with [`step`](LoopbackNetwork::step), so tests can run servers and
+/// clients tick by tick and [`wait_idle`](LoopbackNetwork::wait_idle) for their receive threads.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    hub: Arc<(Mutex<Hub>, Condvar)>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        LoopbackNetwork::with_conditions(LoopbackConditions::default())
+    }
+
+    pub fn with_conditions(conditions: LoopbackConditions) -> Self {
+        let hub = Hub {
+            endpoints: HashMap::new(),
+            queue: vec![],
+            now: 0,
+            rng: StdRng::seed_from_u64(conditions.seed),
+            conditions,
+            unprocessed: 0,
+        };
+        LoopbackNetwork {
+            hub: Arc::new((Mutex::new(hub), Condvar::new())),
+        }
+    }
+
+    /// New endpoint on `addr`, replacing the previous one with the same address.
+    pub fn bind(&self, addr: SocketAddr) -> LoopbackTransport {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let mut hub = self.hub.0.lock().unwrap();
+        hub.endpoints.insert(
+            addr,
+            Endpoint {
+                sender,
+                peers: HashSet::new(),
+            },
+        );
+        LoopbackTransport {
+            addr,
+            hub: self.hub.clone(),
+            receiver,
+        }
+    }
+
+    /// Removes the endpoint, its peers get a timeout and its packets in flight are lost.
+    pub fn disconnect(&self, addr: SocketAddr) {
+        let mut hub = self.hub.0.lock().unwrap();
+        hub.endpoints.remove(&addr);
+        hub.queue.retain(|packet| packet.from != addr && packet.to != addr);
+        let mut timeouts = 0;
+        for endpoint in hub.endpoints.values_mut() {
+            if endpoint.peers.remove(&addr) && endpoint.sender.send(TransportEvent::Timeout(addr)).is_ok() {
+                timeouts += 1;
+            }
+        }
+        hub.unprocessed += timeouts;
+    }
+
+    pub fn set_conditions(&self, conditions: LoopbackConditions) {
+        let mut hub = self.hub.0.lock().unwrap();
+        hub.rng = StdRng::seed_from_u64(conditions.seed);
+        hub.conditions = conditions;
+    }
+
+    /// Advances one step and delivers the packets that are due.
+    pub fn step(&self) {
+        let mut hub = self.hub.0.lock().unwrap();
+        hub.now += 1;
+        let now = hub.now;
+        let due = hub.queue.iter().take_while(|packet| packet.deliver_at <= now).count();
+        for packet in hub.queue.drain(..due).collect::<Vec<_>>() {
+            hub.deliver(packet);
+        }
+    }
+
+    /// Packets sent and not delivered yet.
+    pub fn in_flight(&self) -> usize {
+        self.hub.0.lock().unwrap().queue.len()
+    }
+
+    /// Waits until every receiver processed its delivered events, false on timeout.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let (hub, idle) = &*self.hub;
+        let deadline = Instant::now() + timeout;
+        let mut hub = hub.lock().unwrap();
+        while hub.unprocessed > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            hub = idle.wait_timeout(hub, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+impl Default for LoopbackNetwork {
+    fn default() -> Self {
+        LoopbackNetwork::new()
+    }
+}
+
+pub struct LoopbackTransport {
+    addr: SocketAddr,
+    hub: Arc<(Mutex<Hub>, Condvar)>,
+    receiver: Receiver<TransportEvent>,
+}
+
+impl Transport for LoopbackTransport {
+    type Sender = LoopbackSender;
+    type Receiver = LoopbackReceiver;
+
+    fn split(self) -> (LoopbackSender, LoopbackReceiver) {
+        let sender = LoopbackSender {
+            addr: self.addr,
+            hub: self.hub.clone(),
+        };
+        let receiver = LoopbackReceiver {
+            hub: self.hub,
+            receiver: self.receiver,
+            processing: Cell::new(false),
+        };
+        (sender, receiver)
+    }
+}
+
+pub struct LoopbackSender {
+    addr: SocketAddr,
+    hub: Arc<(Mutex<Hub>, Condvar)>,
+}
+
+impl TransportSender for LoopbackSender {
+    fn send(&self, addr: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Result<(), TransportError> {
+        let mut hub = self.hub.0.lock().unwrap();
+        let conditions = hub.conditions.clone();
+        let unreliable = matches!(
+            delivery,
+            DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_)
+        );
+        let unordered = matches!(delivery, DeliveryRequirement::Unreliable | DeliveryRequirement::Reliable);
+
+        if unreliable && hub.rng.gen_bool(conditions.loss) {
+            return Ok(());
+        }
+        let copies = if unreliable && hub.rng.gen_bool(conditions.duplication) { 2 } else { 1 };
+        for _ in 0..copies {
+            let reordered = unordered && hub.rng.gen_bool(conditions.reordering);
+            let deliver_at = hub.now + conditions.latency as u64 + reordered as u64;
+            hub.enqueue(InFlight {
+                deliver_at,
+                from: self.addr,
+                to: addr,
+                payload: payload.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+pub struct LoopbackReceiver {
+    hub: Arc<(Mutex<Hub>, Condvar)>,
+    receiver: Receiver<TransportEvent>,
+    // An event was returned, it is processed once recv is called again
+    processing: Cell<bool>,
+}
+
+impl LoopbackReceiver {
+    fn processed(&self) {
+        if self.processing.replace(false) {
+            let (hub, idle) = &*self.hub;
+            hub.lock().unwrap().unprocessed -= 1;
+            idle.notify_all();
+        }
+    }
+}
+
+impl TransportReceiver for LoopbackReceiver {
+    fn recv(&self) -> Result<TransportEvent, TransportError> {
+        self.processed();
+        let event = self.receiver.recv().map_err(|_| TransportError::Disconnected)?;
+        self.processing.set(true);
+        Ok(event)
+    }
+}
+
+impl Drop for LoopbackReceiver {
+    fn drop(&mut self) {
+        self.processed();
+        // Events left in the channel will never be processed
+        let left = self.receiver.try_iter().count();
+        self.hub.0.lock().unwrap().unprocessed -= left;
+        self.hub.1.notify_all();
+    }
+}
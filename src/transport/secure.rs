@@ -0,0 +1,598 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::iter;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{DeliveryRequirement, Transport, TransportError, TransportEvent, TransportReceiver, TransportSender};
+
+const HELLO: u8 = 0;
+const DATA: u8 = 1;
+// Answers a hello, it never starts a session
+const HELLO_REPLY: u8 = 2;
+// Kind and sequence, authenticated with the payload
+const HEADER_LEN: usize = 9;
+const TAG_LEN: usize = 16;
+/// Sequences this far behind the newest received one are dropped as replays.
+pub const REPLAY_WINDOW: u64 = 64;
+/// Payloads waiting for the answer to a hello, the oldest are dropped beyond this.
+pub const QUEUE_SIZE: usize = 64;
+/// Sessions kept besides the current one until the peer authenticates one of them.
+pub const MAX_CANDIDATES: usize = 4;
+
+// Sequences received from a peer, the newest and a bit for each one before it
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_new(&self, sequence: u64) -> bool {
+        match self.newest {
+            None => true,
+            Some(newest) if sequence > newest => true,
+            Some(newest) => {
+                let behind = newest - sequence;
+                behind < REPLAY_WINDOW && self.seen & (1 << behind) == 0
+            }
+        }
+    }
+
+    // Only called once the datagram is authenticated, forged sequences don't move the window
+    fn mark(&mut self, sequence: u64) {
+        match self.newest {
+            Some(newest) if sequence <= newest => {
+                if newest - sequence < REPLAY_WINDOW {
+                    self.seen |= 1 << (newest - sequence);
+                }
+            }
+            Some(newest) => {
+                let ahead = sequence - newest;
+                self.seen = if ahead < REPLAY_WINDOW { self.seen << ahead | 1 } else { 1 };
+                self.newest = Some(sequence);
+            }
+            None => {
+                self.seen = 1;
+                self.newest = Some(sequence);
+            }
+        }
+    }
+}
+
+struct Session {
+    // Its key in the exchange, a copy of the same hello doesn't start a new session
+    peer_public: [u8; 32],
+    sending: ChaCha20Poly1305,
+    receiving: ChaCha20Poly1305,
+    next_sequence: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    fn new(secret: &StaticSecret, public: &PublicKey, peer_public: &PublicKey) -> Self {
+        let shared = secret.diffie_hellman(peer_public);
+        // Both peers order the keys the same way, each direction gets its own key
+        let own_first = public.as_bytes() < peer_public.as_bytes();
+        let (first, second) = if own_first { (public, peer_public) } else { (peer_public, public) };
+        let salt = [&first.as_bytes()[..], &second.as_bytes()[..]].concat();
+        let mut keys = [0; 64];
+        Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+            .expand(b"netcarrier session keys", &mut keys)
+            .expect("64 bytes is a valid HKDF output");
+        let first_key: [u8; 32] = keys[..32].try_into().unwrap();
+        let second_key: [u8; 32] = keys[32..].try_into().unwrap();
+        let (sending, receiving) = if own_first { (first_key, second_key) } else { (second_key, first_key) };
+        Session {
+            peer_public: *peer_public.as_bytes(),
+            sending: ChaCha20Poly1305::new(&Key::from(sending)),
+            receiving: ChaCha20Poly1305::new(&Key::from(receiving)),
+            next_sequence: 0,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    fn seal(&mut self, payload: &[u8]) -> Bytes {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len() + TAG_LEN);
+        datagram.push(DATA);
+        datagram.extend_from_slice(&sequence.to_le_bytes());
+        let sealed = self
+            .sending
+            .encrypt(&nonce(sequence), Payload { msg: payload, aad: &datagram })
+            .expect("ChaCha20Poly1305 encrypts any payload");
+        datagram.extend_from_slice(&sealed);
+        Bytes::from(datagram)
+    }
+
+    // The payload, if the datagram is authentic and not a replay
+    fn open(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() < HEADER_LEN + TAG_LEN {
+            return None;
+        }
+        let (header, sealed) = datagram.split_at(HEADER_LEN);
+        let sequence = u64::from_le_bytes(header[1..].try_into().unwrap());
+        if !self.replay.is_new(sequence) {
+            return None;
+        }
+        let payload = self
+            .receiving
+            .decrypt(&nonce(sequence), Payload { msg: sealed, aad: header })
+            .ok()?;
+        self.replay.mark(sequence);
+        Some(payload)
+    }
+}
+
+// Unique for each datagram of a direction, the sequences never repeat under a key
+fn nonce(sequence: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+    Nonce::from(nonce)
+}
+
+// Our side of an exchange we started
+struct Hello {
+    secret: StaticSecret,
+    public: PublicKey,
+    // Sent again to each new answer, the first one could come from anyone
+    queued: VecDeque<(Bytes, DeliveryRequirement)>,
+}
+
+impl Hello {
+    fn session(&self, peer_public: &PublicKey) -> (Session, Vec<(Bytes, DeliveryRequirement)>) {
+        let mut session = Session::new(&self.secret, &self.public, peer_public);
+        let datagrams = self
+            .queued
+            .iter()
+            .map(|(payload, delivery)| (session.seal(payload), *delivery))
+            .collect();
+        (session, datagrams)
+    }
+}
+
+enum Peer {
+    // Our hello was sent, the payloads wait for the peer's
+    Pending(Hello),
+    Established {
+        session: Session,
+        // Answers to new hellos from the peer, or other answers to our hello: one replaces the
+        // session once a datagram opens with it, an unauthenticated hello could come from anyone
+        candidates: Vec<Session>,
+        // A datagram opened with the session, until then the payloads are sealed for the
+        // candidates too
+        confirmed: bool,
+        // Kept until confirmed so a later answer to our hello becomes a candidate
+        ours: Option<Hello>,
+    },
+}
+
+impl Peer {
+    fn established(session: Session, ours: Option<Hello>) -> Self {
+        Peer::Established {
+            session,
+            candidates: vec![],
+            confirmed: false,
+            ours,
+        }
+    }
+}
+
+fn hello(kind: u8, public: &PublicKey) -> Bytes {
+    let mut datagram = vec![kind];
+    datagram.extend_from_slice(public.as_bytes());
+    Bytes::from(datagram)
+}
+
+fn key_pair() -> (StaticSecret, PublicKey) {
+    let secret = StaticSecret::new(OsRng);
+    let public = PublicKey::from(&secret);
+    (secret, public)
+}
+
+struct Shared<S> {
+    sender: S,
+    peers: Mutex<HashMap<SocketAddr, Peer>>,
+}
+
+impl<S: TransportSender> Shared<S> {
+    fn send_all(&self, addr: SocketAddr, datagrams: Vec<(Bytes, DeliveryRequirement)>) -> Result<(), TransportError> {
+        for (datagram, delivery) in datagrams {
+            self.sender.send(addr, datagram, delivery)?;
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a transport to encrypt and authenticate every datagram with ChaCha20-Poly1305.
+///
+/// The first datagram to a peer is a hello with an ephemeral X25519 key, the peer answers with
+/// its own and both derive a key for each direction. Payloads sent before the answer wait for it.
+/// Other answers, spoofed or not, and new hellos from a peer that restarted give candidate
+/// sessions: the payloads are sealed for each of them until the peer sends a datagram
+/// authenticated with one, which becomes the session.
+/// Each datagram carries a sequence, the receiver drops forged, tampered and replayed ones before
+/// they reach the network systems. The keys aren't signed, this protects against eavesdropping
+/// and injection but not against a man in the middle of the exchange.
+pub struct SecureTransport<N> {
+    transport: N,
+}
+
+impl<N: Transport> SecureTransport<N> {
+    pub fn new(transport: N) -> Self {
+        SecureTransport { transport }
+    }
+}
+
+impl<N: Transport> Transport for SecureTransport<N> {
+    type Sender = SecureSender<N::Sender>;
+    type Receiver = SecureReceiver<N::Receiver, N::Sender>;
+
+    fn split(self) -> (Self::Sender, Self::Receiver) {
+        let (sender, receiver) = self.transport.split();
+        let shared = Arc::new(Shared {
+            sender,
+            peers: Mutex::new(HashMap::new()),
+        });
+        (SecureSender(shared.clone()), SecureReceiver { receiver, shared })
+    }
+}
+
+pub struct SecureSender<S>(Arc<Shared<S>>);
+
+impl<S: TransportSender> TransportSender for SecureSender<S> {
+    fn send(&self, addr: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Result<(), TransportError> {
+        let datagram = {
+            let mut peers = self.0.peers.lock().unwrap();
+            match peers.get_mut(&addr) {
+                Some(Peer::Established {
+                    session,
+                    candidates,
+                    confirmed,
+                    ..
+                }) => {
+                    let mut datagrams = vec![(session.seal(&payload), delivery)];
+                    if !*confirmed {
+                        datagrams.extend(candidates.iter_mut().map(|candidate| (candidate.seal(&payload), delivery)));
+                    }
+                    datagrams
+                }
+                Some(Peer::Pending(Hello { queued, .. })) => {
+                    if queued.len() >= QUEUE_SIZE {
+                        queued.pop_front();
+                    }
+                    queued.push_back((payload, delivery));
+                    return Ok(());
+                }
+                None => {
+                    let (secret, public) = key_pair();
+                    peers.insert(
+                        addr,
+                        Peer::Pending(Hello {
+                            secret,
+                            public,
+                            queued: iter::once((payload, delivery)).collect(),
+                        }),
+                    );
+                    vec![(hello(HELLO, &public), DeliveryRequirement::Reliable)]
+                }
+            }
+        };
+        self.0.send_all(addr, datagram)
+    }
+
+    // The next payloads start a new exchange
+    fn forget(&self, addr: SocketAddr) {
+        self.0.peers.lock().unwrap().remove(&addr);
+        self.0.sender.forget(addr);
+    }
+}
+
+pub struct SecureReceiver<R, S> {
+    receiver: R,
+    shared: Arc<Shared<S>>,
+}
+
+impl<R: TransportReceiver, S: TransportSender> SecureReceiver<R, S> {
+    // Starts the session with `addr` from its hello, answering it unless it answers ours
+    fn exchange(&self, addr: SocketAddr, kind: u8, peer_public: &[u8]) -> Result<(), TransportError> {
+        let peer_public: [u8; 32] = match peer_public.try_into() {
+            Ok(key) => key,
+            Err(_) => return Ok(()),
+        };
+        let peer_public = PublicKey::from(peer_public);
+        let mut peers = self.shared.peers.lock().unwrap();
+        let datagrams = match peers.remove(&addr) {
+            // An answer, or the peer's hello sent at the same time as ours. It could be spoofed,
+            // the next answers are candidates until the peer authenticates one
+            Some(Peer::Pending(ours)) => {
+                let (session, datagrams) = ours.session(&peer_public);
+                peers.insert(addr, Peer::established(session, Some(ours)));
+                datagrams
+            }
+            Some(Peer::Established {
+                session,
+                mut candidates,
+                confirmed,
+                ours,
+            }) => {
+                let known = |session: &Session| session.peer_public == *peer_public.as_bytes();
+                let datagrams = if known(&session) || candidates.iter().any(known) {
+                    // Copies of a hello or of an answer
+                    vec![]
+                } else if kind == HELLO_REPLY {
+                    // Without our hello, the peer already authenticated a session: the answer is stray
+                    match &ours {
+                        Some(ours) if candidates.len() < MAX_CANDIDATES => {
+                            let (candidate, datagrams) = ours.session(&peer_public);
+                            candidates.push(candidate);
+                            datagrams
+                        }
+                        _ => vec![],
+                    }
+                } else {
+                    let (secret, public) = key_pair();
+                    if candidates.len() >= MAX_CANDIDATES {
+                        candidates.remove(0);
+                    }
+                    candidates.push(Session::new(&secret, &public, &peer_public));
+                    vec![(hello(HELLO_REPLY, &public), DeliveryRequirement::Reliable)]
+                };
+                peers.insert(
+                    addr,
+                    Peer::Established {
+                        session,
+                        candidates,
+                        confirmed,
+                        ours,
+                    },
+                );
+                datagrams
+            }
+            None if kind == HELLO => {
+                let (secret, public) = key_pair();
+                let session = Session::new(&secret, &public, &peer_public);
+                peers.insert(addr, Peer::established(session, None));
+                vec![(hello(HELLO_REPLY, &public), DeliveryRequirement::Reliable)]
+            }
+            None => vec![],
+        };
+        drop(peers);
+        self.shared.send_all(addr, datagrams)
+    }
+
+    fn open(&self, addr: SocketAddr, datagram: &[u8]) -> Option<Vec<u8>> {
+        match self.shared.peers.lock().unwrap().get_mut(&addr) {
+            Some(Peer::Established {
+                session,
+                candidates,
+                confirmed,
+                ours,
+            }) => {
+                let payload = match session.open(datagram) {
+                    Some(payload) if *confirmed => return Some(payload),
+                    Some(payload) => payload,
+                    None => {
+                        let (index, payload) = candidates
+                            .iter_mut()
+                            .enumerate()
+                            .find_map(|(index, candidate)| Some((index, candidate.open(datagram)?)))?;
+                        // The peer restarted or the first answer was spoofed, the old session is gone
+                        *session = candidates.swap_remove(index);
+                        payload
+                    }
+                };
+                // The peer holds the keys of this session, the other ones can't take it over
+                *confirmed = true;
+                candidates.clear();
+                *ours = None;
+                Some(payload)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<R: TransportReceiver, S: TransportSender> TransportReceiver for SecureReceiver<R, S> {
+    fn recv(&self) -> Result<TransportEvent, TransportError> {
+        loop {
+            match self.receiver.recv()? {
+                TransportEvent::Packet(addr, datagram) => match datagram.first() {
+                    Some(&kind) if kind == HELLO || kind == HELLO_REPLY => self.exchange(addr, kind, &datagram[1..])?,
+                    Some(&DATA) => {
+                        if let Some(payload) = self.open(addr, &datagram) {
+                            return Ok(TransportEvent::Packet(addr, payload));
+                        }
+                    }
+                    _ => {}
+                },
+                TransportEvent::Timeout(addr) => {
+                    self.shared.peers.lock().unwrap().remove(&addr);
+                    return Ok(TransportEvent::Timeout(addr));
+                }
+                event => return Ok(event),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback::LoopbackNetwork;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        for sequence in &[5, 3, 70, 69] {
+            assert!(window.is_new(*sequence));
+            window.mark(*sequence);
+            assert!(!window.is_new(*sequence));
+        }
+        assert!(window.is_new(10));
+        // Too old to tell
+        assert!(!window.is_new(6));
+    }
+
+    #[test]
+    fn sessions_agree_on_keys() {
+        let (first_secret, first_public) = key_pair();
+        let (second_secret, second_public) = key_pair();
+        let mut first = Session::new(&first_secret, &first_public, &second_public);
+        let mut second = Session::new(&second_secret, &second_public, &first_public);
+
+        let datagram = first.seal(b"state");
+        assert_eq!(second.open(&datagram), Some(b"state".to_vec()));
+        assert_eq!(second.open(&datagram), None);
+        // Each direction has its own key
+        assert_eq!(first.open(&second.seal(b"input")), Some(b"input".to_vec()));
+        let own = first.seal(b"input");
+        assert_eq!(first.open(&own), None);
+
+        let datagram = first.seal(b"state");
+        let mut tampered = datagram.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(second.open(&tampered), None);
+        // The forged datagram didn't use up its sequence
+        assert_eq!(second.open(&datagram), Some(b"state".to_vec()));
+    }
+
+    #[test]
+    fn encrypts_through_transport() {
+        let network = LoopbackNetwork::new();
+        let (server_sender, server_receiver) = SecureTransport::new(network.bind(addr(4000))).split();
+        let (client_sender, client_receiver) = SecureTransport::new(network.bind(addr(4001))).split();
+
+        client_sender.send(addr(4000), Bytes::from_static(b"connect"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Connect(addr(4001)));
+        // The hello is answered from the receive thread, then the payload is sent
+        let client = std::thread::spawn(move || {
+            assert_eq!(client_receiver.recv().unwrap(), TransportEvent::Connect(addr(4000)));
+            client_receiver.recv().unwrap()
+        });
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"connect".to_vec()));
+        server_sender.send(addr(4001), Bytes::from_static(b"state"), DeliveryRequirement::Unreliable).unwrap();
+        assert_eq!(client.join().unwrap(), TransportEvent::Packet(addr(4000), b"state".to_vec()));
+
+        // Injected datagrams never reach the server
+        let (injector, _) = network.bind(addr(4002)).split();
+        let mut forged = vec![DATA];
+        forged.extend_from_slice(&[0; HEADER_LEN + TAG_LEN + 4]);
+        injector.send(addr(4000), Bytes::from(forged), DeliveryRequirement::Reliable).unwrap();
+        client_sender.send(addr(4000), Bytes::from_static(b"input"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Connect(addr(4002)));
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"input".to_vec()));
+    }
+
+    #[test]
+    fn spoofed_hello_keeps_session() {
+        let network = LoopbackNetwork::new();
+        let (server_sender, server_receiver) = SecureTransport::new(network.bind(addr(4000))).split();
+        let (client_sender, client_receiver) = SecureTransport::new(network.bind(addr(4001))).split();
+        let (client_events, client_received) = crossbeam_channel::unbounded();
+        std::thread::spawn(move || {
+            while let Ok(event) = client_receiver.recv() {
+                let _ = client_events.send(event);
+            }
+        });
+
+        client_sender.send(addr(4000), Bytes::from_static(b"connect"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Connect(addr(4001)));
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"connect".to_vec()));
+        assert_eq!(client_received.recv().unwrap(), TransportEvent::Connect(addr(4000)));
+
+        // A hello with the client's address and another key, its answer goes to the client
+        let (_, spoofed) = key_pair();
+        server_receiver.exchange(addr(4001), HELLO, spoofed.as_bytes()).unwrap();
+        client_sender.send(addr(4000), Bytes::from_static(b"input"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"input".to_vec()));
+        server_sender.send(addr(4001), Bytes::from_static(b"state"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(client_received.recv().unwrap(), TransportEvent::Packet(addr(4000), b"state".to_vec()));
+
+        // A client that restarted on the same address gets a new session
+        let (client_sender, client_receiver) = SecureTransport::new(network.bind(addr(4001))).split();
+        client_sender.send(addr(4000), Bytes::from_static(b"connect"), DeliveryRequirement::Reliable).unwrap();
+        let client = std::thread::spawn(move || {
+            assert_eq!(client_receiver.recv().unwrap(), TransportEvent::Connect(addr(4000)));
+            client_receiver.recv().unwrap()
+        });
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"connect".to_vec()));
+        server_sender.send(addr(4001), Bytes::from_static(b"state"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(client.join().unwrap(), TransportEvent::Packet(addr(4000), b"state".to_vec()));
+    }
+
+    #[test]
+    fn spoofed_reply_before_real_one() {
+        let network = LoopbackNetwork::new();
+        let (server_sender, server_receiver) = SecureTransport::new(network.bind(addr(4000))).split();
+        let (client_sender, client_receiver) = SecureTransport::new(network.bind(addr(4001))).split();
+
+        client_sender.send(addr(4000), Bytes::from_static(b"connect"), DeliveryRequirement::Reliable).unwrap();
+        // An answer with the server's address and another key, before the server's
+        let (_, spoofed) = key_pair();
+        client_receiver.exchange(addr(4000), HELLO_REPLY, spoofed.as_bytes()).unwrap();
+        let client = std::thread::spawn(move || {
+            assert_eq!(client_receiver.recv().unwrap(), TransportEvent::Connect(addr(4000)));
+            client_receiver.recv().unwrap()
+        });
+
+        // The payload sealed for the spoofed key is dropped, the one for the server's goes through
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Connect(addr(4001)));
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"connect".to_vec()));
+        server_sender.send(addr(4001), Bytes::from_static(b"state"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(client.join().unwrap(), TransportEvent::Packet(addr(4000), b"state".to_vec()));
+        match client_sender.0.peers.lock().unwrap().get(&addr(4000)) {
+            Some(Peer::Established {
+                session,
+                candidates,
+                confirmed,
+                ours,
+            }) => {
+                assert_ne!(session.peer_public, *spoofed.as_bytes());
+                assert!(candidates.is_empty());
+                assert!(*confirmed);
+                assert!(ours.is_none());
+            }
+            _ => panic!("no session with the server"),
+        }
+
+        client_sender.send(addr(4000), Bytes::from_static(b"input"), DeliveryRequirement::Reliable).unwrap();
+        assert_eq!(server_receiver.recv().unwrap(), TransportEvent::Packet(addr(4001), b"input".to_vec()));
+    }
+
+    #[test]
+    fn unanswered_hello_queue() {
+        let network = LoopbackNetwork::new();
+        // Never answers
+        let _server = network.bind(addr(4000));
+        let (client_sender, _) = SecureTransport::new(network.bind(addr(4001))).split();
+
+        for sequence in 0..QUEUE_SIZE as u8 + 1 {
+            client_sender.send(addr(4000), Bytes::from(vec![sequence]), DeliveryRequirement::Reliable).unwrap();
+        }
+        match client_sender.0.peers.lock().unwrap().get(&addr(4000)) {
+            Some(Peer::Pending(ours)) => {
+                assert_eq!(ours.queued.len(), QUEUE_SIZE);
+                // The oldest payload was dropped
+                assert_eq!(ours.queued.front().unwrap().0, Bytes::from(vec![1]));
+            }
+            _ => panic!("no hello to the server"),
+        }
+
+        client_sender.forget(addr(4000));
+        assert!(client_sender.0.peers.lock().unwrap().is_empty());
+    }
+}
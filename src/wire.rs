@@ -0,0 +1,757 @@
+use std::fmt;
+
+use bit_vec::BitVec;
+
+use super::NetworkBitmask;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WireError {
+    /// The packet ended before the value was read.
+    UnexpectedEnd,
+    Invalid(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::UnexpectedEnd => write!(f, "unexpected end of packet"),
+            WireError::Invalid(message) => write!(f, "invalid packet: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u64,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter::default()
+    }
+
+    /// Writes the `count` lowest bits of `value`, up to 64.
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        for i in 0..count {
+            self.write_bool((value >> i) & 1 == 1);
+        }
+    }
+
+    pub fn write_bool(&mut self, bit: bool) {
+        let offset = (self.bits % 8) as u8;
+        if offset == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 1 << offset;
+        }
+        self.bits += 1;
+    }
+
+    /// Groups of 7 bits, each followed by whether another one comes.
+    pub fn write_varint(&mut self, mut value: u64) {
+        loop {
+            self.write_bits(value & 0x7f, 7);
+            value >>= 7;
+            self.write_bool(value != 0);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Pads the last byte with zeros, the next bits start a new byte.
+    pub fn align(&mut self) {
+        self.bits = self.bytes.len() as u64 * 8;
+    }
+
+    /// Appends bytes serialized on their own, starting on a new byte.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.align();
+        self.bytes.extend_from_slice(bytes);
+        self.bits += bytes.len() as u64 * 8;
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    /// The written bytes, the last one padded with zeros.
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    bit: u64,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, bit: 0 }
+    }
+
+    pub fn read_bits(&mut self, count: u32) -> Result<u64, WireError> {
+        let mut value = 0;
+        for i in 0..count {
+            if self.read_bool()? {
+                value |= 1 << i;
+            }
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, WireError> {
+        let byte = self.bytes.get((self.bit / 8) as usize).ok_or(WireError::UnexpectedEnd)?;
+        let bit = (byte >> (self.bit % 8)) & 1 == 1;
+        self.bit += 1;
+        Ok(bit)
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, WireError> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            if shift >= 64 {
+                return Err(WireError::Invalid("varint longer than 64 bits".to_string()));
+            }
+            value |= self.read_bits(7)? << shift;
+            shift += 7;
+            if !self.read_bool()? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Skips the padding of the current byte.
+    pub fn align(&mut self) {
+        self.bit = self.bit.div_ceil(8) * 8;
+    }
+
+    /// The next `len` bytes, starting on a new byte.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        self.align();
+        let start = (self.bit / 8) as usize;
+        let bytes = self.bytes.get(start..start + len).ok_or(WireError::UnexpectedEnd)?;
+        self.bit += len as u64 * 8;
+        Ok(bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bit >= self.bytes.len() as u64 * 8
+    }
+}
+
+/// Range and precision of floats, sent as the number of `precision` steps above `min`.
+/// Values out of the range are clamped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantize {
+    pub min: f64,
+    pub max: f64,
+    pub precision: f64,
+}
+
+impl Quantize {
+    pub fn bits(&self) -> u32 {
+        let steps = ((self.max - self.min) / self.precision).ceil() as u64;
+        64 - steps.leading_zeros()
+    }
+
+    pub fn write(&self, value: f64, writer: &mut BitWriter) {
+        let steps = ((self.max - self.min) / self.precision).ceil();
+        let step = ((value - self.min) / self.precision).round().max(0.0).min(steps);
+        writer.write_bits(step as u64, self.bits());
+    }
+
+    pub fn read(&self, reader: &mut BitReader) -> Result<f64, WireError> {
+        let step = reader.read_bits(self.bits())?;
+        Ok((self.min + step as f64 * self.precision).min(self.max))
+    }
+}
+
+/// Bit-packed encoding of a value.
+///
+/// `quantize` is the range of the floats inside the value, they are sent in full without it.
+/// It can be derived with `#[derive(NetSerialize)]`, fields marked
+/// `#[net(min = .., max = .., precision = ..)]` use their own range.
+pub trait NetSerialize: Sized {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>);
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError>;
+}
+
+pub fn to_bytes<T: NetSerialize>(value: &T) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    value.net_serialize(&mut writer, None);
+    writer.finish()
+}
+
+pub fn from_bytes<T: NetSerialize>(bytes: &[u8]) -> Result<T, WireError> {
+    T::net_deserialize(&mut BitReader::new(bytes), None)
+}
+
+pub fn serialized_bits<T: NetSerialize>(value: &T, quantize: Option<Quantize>) -> u64 {
+    let mut writer = BitWriter::new();
+    value.net_serialize(&mut writer, quantize);
+    writer.bits()
+}
+
+/// Payloads sharing a datagram, each after its length in bytes.
+pub fn write_frames<'b>(payloads: impl IntoIterator<Item = &'b [u8]>) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    for payload in payloads {
+        writer.write_varint(payload.len() as u64);
+        writer.write_bytes(payload);
+    }
+    writer.finish()
+}
+
+/// Bytes [`write_frames`] adds before a frame of `len` bytes.
+pub fn frame_prefix_len(len: usize) -> usize {
+    let mut writer = BitWriter::new();
+    writer.write_varint(len as u64);
+    writer.finish().len()
+}
+
+pub fn read_frames(datagram: &[u8]) -> Result<Vec<&[u8]>, WireError> {
+    let mut reader = BitReader::new(datagram);
+    let mut frames = vec![];
+    while !reader.is_empty() {
+        let len = reader.read_varint()?;
+        if len > datagram.len() as u64 {
+            return Err(WireError::Invalid(format!("frame of {} bytes in a datagram of {}", len, datagram.len())));
+        }
+        frames.push(reader.read_bytes(len as usize)?);
+    }
+    Ok(frames)
+}
+
+/// Network ids as the difference with the previous one, small when they are mostly increasing.
+pub fn write_ids(writer: &mut BitWriter, ids: &[u32]) {
+    writer.write_varint(ids.len() as u64);
+    let mut previous = 0;
+    for &id in ids {
+        writer.write_varint(zigzag(id as i64 - previous as i64));
+        previous = id;
+    }
+}
+
+pub fn read_ids(reader: &mut BitReader) -> Result<Vec<u32>, WireError> {
+    let len = reader.read_varint()?;
+    let mut ids = vec![];
+    let mut previous: i64 = 0;
+    for _ in 0..len {
+        let id = previous + unzigzag(reader.read_varint()?);
+        if id < 0 || id > u32::MAX as i64 {
+            return Err(WireError::Invalid(format!("network id {} out of range", id)));
+        }
+        ids.push(id as u32);
+        previous = id;
+    }
+    Ok(ids)
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+impl NetSerialize for bool {
+    fn net_serialize(&self, writer: &mut BitWriter, _quantize: Option<Quantize>) {
+        writer.write_bool(*self);
+    }
+
+    fn net_deserialize(reader: &mut BitReader, _quantize: Option<Quantize>) -> Result<Self, WireError> {
+        reader.read_bool()
+    }
+}
+
+macro_rules! impl_fixed {
+    ($($ty:ty: $unsigned:ty),*) => {
+        $(
+            impl NetSerialize for $ty {
+                fn net_serialize(&self, writer: &mut BitWriter, _quantize: Option<Quantize>) {
+                    writer.write_bits(*self as $unsigned as u64, <$ty>::BITS);
+                }
+
+                fn net_deserialize(reader: &mut BitReader, _quantize: Option<Quantize>) -> Result<Self, WireError> {
+                    Ok(reader.read_bits(<$ty>::BITS)? as $unsigned as $ty)
+                }
+            }
+        )*
+    };
+}
+
+impl_fixed!(u8: u8, u16: u16, i8: u8, i16: u16);
+
+// Usually small, sent as varints
+macro_rules! impl_varint {
+    ($($ty:ty),*) => {
+        $(
+            impl NetSerialize for $ty {
+                fn net_serialize(&self, writer: &mut BitWriter, _quantize: Option<Quantize>) {
+                    writer.write_varint(*self as u64);
+                }
+
+                fn net_deserialize(reader: &mut BitReader, _quantize: Option<Quantize>) -> Result<Self, WireError> {
+                    let value = reader.read_varint()?;
+                    if value > <$ty>::MAX as u64 {
+                        return Err(WireError::Invalid(format!("{} out of range", value)));
+                    }
+                    Ok(value as $ty)
+                }
+            }
+        )*
+    };
+}
+
+impl_varint!(u32, u64, usize);
+
+macro_rules! impl_zigzag {
+    ($($ty:ty),*) => {
+        $(
+            impl NetSerialize for $ty {
+                fn net_serialize(&self, writer: &mut BitWriter, _quantize: Option<Quantize>) {
+                    writer.write_varint(zigzag(*self as i64));
+                }
+
+                fn net_deserialize(reader: &mut BitReader, _quantize: Option<Quantize>) -> Result<Self, WireError> {
+                    let value = unzigzag(reader.read_varint()?);
+                    if value < <$ty>::MIN as i64 || value > <$ty>::MAX as i64 {
+                        return Err(WireError::Invalid(format!("{} out of range", value)));
+                    }
+                    Ok(value as $ty)
+                }
+            }
+        )*
+    };
+}
+
+impl_zigzag!(i32, i64);
+
+impl NetSerialize for f32 {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        match quantize {
+            Some(quantize) => quantize.write(*self as f64, writer),
+            None => writer.write_bits(self.to_bits() as u64, 32),
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        match quantize {
+            Some(quantize) => Ok(quantize.read(reader)? as f32),
+            None => Ok(f32::from_bits(reader.read_bits(32)? as u32)),
+        }
+    }
+}
+
+impl NetSerialize for f64 {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        match quantize {
+            Some(quantize) => quantize.write(*self, writer),
+            None => writer.write_bits(self.to_bits(), 64),
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        match quantize {
+            Some(quantize) => quantize.read(reader),
+            None => Ok(f64::from_bits(reader.read_bits(64)?)),
+        }
+    }
+}
+
+impl NetSerialize for String {
+    fn net_serialize(&self, writer: &mut BitWriter, _quantize: Option<Quantize>) {
+        writer.write_varint(self.len() as u64);
+        for byte in self.bytes() {
+            writer.write_bits(byte as u64, 8);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, _quantize: Option<Quantize>) -> Result<Self, WireError> {
+        let bytes = Vec::<u8>::net_deserialize(reader, None)?;
+        String::from_utf8(bytes).map_err(|e| WireError::Invalid(e.to_string()))
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for Option<T> {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        writer.write_bool(self.is_some());
+        if let Some(value) = self {
+            value.net_serialize(writer, quantize);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        if reader.read_bool()? {
+            Ok(Some(T::net_deserialize(reader, quantize)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for Vec<T> {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        writer.write_varint(self.len() as u64);
+        for value in self {
+            value.net_serialize(writer, quantize);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        let len = reader.read_varint()?;
+        // Every value takes at least a bit, a bigger length is a corrupted packet
+        if len > (reader.bytes.len() as u64 * 8).saturating_sub(reader.bit) {
+            return Err(WireError::UnexpectedEnd);
+        }
+        (0..len).map(|_| T::net_deserialize(reader, quantize)).collect()
+    }
+}
+
+macro_rules! impl_array {
+    ($($len:expr),*) => {
+        $(
+            impl<T: NetSerialize + Copy + Default> NetSerialize for [T; $len] {
+                fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+                    for value in self {
+                        value.net_serialize(writer, quantize);
+                    }
+                }
+
+                fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+                    let mut array = [T::default(); $len];
+                    for value in array.iter_mut() {
+                        *value = T::net_deserialize(reader, quantize)?;
+                    }
+                    Ok(array)
+                }
+            }
+        )*
+    };
+}
+
+impl_array!(2, 3, 4);
+
+/// How the bits of a bitmask are written, each mask is sent with the smallest one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaskEncoding {
+    /// One bit per entity.
+    Dense,
+    /// Positions of the set bits, for sparse masks.
+    Indices,
+    /// Lengths of the runs of equal bits, for masks with long runs.
+    Runs,
+}
+
+impl MaskEncoding {
+    const ALL: [MaskEncoding; 3] = [MaskEncoding::Dense, MaskEncoding::Indices, MaskEncoding::Runs];
+
+    /// Smallest encoding of `mask`, dense on ties.
+    pub fn smallest(mask: &BitVec<u32>) -> MaskEncoding {
+        *MaskEncoding::ALL.iter().min_by_key(|encoding| encoding.bits(mask)).unwrap()
+    }
+
+    /// Bits the mask takes with this encoding, without its length and encoding.
+    pub fn bits(self, mask: &BitVec<u32>) -> u64 {
+        match self {
+            MaskEncoding::Dense => mask.len() as u64,
+            MaskEncoding::Indices => {
+                let gaps = index_gaps(mask);
+                varint_bits(gaps.len() as u64) + gaps.into_iter().map(varint_bits).sum::<u64>()
+            }
+            MaskEncoding::Runs => match runs(mask) {
+                runs if runs.is_empty() => 0,
+                runs => 1 + runs.into_iter().map(varint_bits).sum::<u64>(),
+            },
+        }
+    }
+}
+
+fn varint_bits(value: u64) -> u64 {
+    let groups = (64 - value.leading_zeros() as u64).div_ceil(7);
+    8 * groups.max(1)
+}
+
+// Distance of each set bit to the previous one
+fn index_gaps(mask: &BitVec<u32>) -> Vec<u64> {
+    let mut next = 0;
+    let mut gaps = vec![];
+    for (i, bit) in mask.iter().enumerate() {
+        if bit {
+            gaps.push((i - next) as u64);
+            next = i + 1;
+        }
+    }
+    gaps
+}
+
+fn runs(mask: &BitVec<u32>) -> Vec<u64> {
+    let mut runs: Vec<u64> = vec![];
+    let mut previous = None;
+    for bit in mask.iter() {
+        if previous == Some(bit) {
+            *runs.last_mut().unwrap() += 1;
+        } else {
+            runs.push(1);
+        }
+        previous = Some(bit);
+    }
+    runs
+}
+
+/// Writes the mask's length and its bits with the smallest [`MaskEncoding`].
+pub fn write_mask(writer: &mut BitWriter, mask: &BitVec<u32>) {
+    writer.write_varint(mask.len() as u64);
+    let encoding = MaskEncoding::smallest(mask);
+    writer.write_bits(encoding as u64, 2);
+    match encoding {
+        MaskEncoding::Dense => {
+            for bit in mask.iter() {
+                writer.write_bool(bit);
+            }
+        }
+        MaskEncoding::Indices => {
+            let gaps = index_gaps(mask);
+            writer.write_varint(gaps.len() as u64);
+            for gap in gaps {
+                writer.write_varint(gap);
+            }
+        }
+        MaskEncoding::Runs => {
+            if let Some(first) = mask.get(0) {
+                writer.write_bool(first);
+            }
+            for run in runs(mask) {
+                writer.write_varint(run);
+            }
+        }
+    }
+}
+
+/// Most entities a mask read without a known length can have, the sparse and run encodings take
+/// a few bits for any length.
+pub const MAX_MASK_ENTITIES: usize = 1 << 20;
+
+/// Reads a mask written by [`write_mask`], `entities` is the length it must have if known.
+pub fn read_mask(reader: &mut BitReader, entities: Option<usize>) -> Result<BitVec<u32>, WireError> {
+    let len = reader.read_varint()?;
+    match entities {
+        Some(entities) if len != entities as u64 => {
+            return Err(WireError::Invalid(format!("bitmask has {} entities, expected {}", len, entities)))
+        }
+        None if len > MAX_MASK_ENTITIES as u64 => {
+            return Err(WireError::Invalid(format!("bitmask of {} entities", len)))
+        }
+        _ => {}
+    }
+    let len = len as usize;
+    let mut mask: BitVec<u32> = BitVec::new();
+    match reader.read_bits(2)? {
+        0 => {
+            for _ in 0..len {
+                mask.push(reader.read_bool()?);
+            }
+        }
+        1 => {
+            let count = reader.read_varint()?;
+            if count > len as u64 {
+                return Err(WireError::Invalid(format!("{} indices in a bitmask of {}", count, len)));
+            }
+            mask = BitVec::from_elem(len, false);
+            let mut next = 0u64;
+            for _ in 0..count {
+                let index = next.saturating_add(reader.read_varint()?);
+                if index >= len as u64 {
+                    return Err(WireError::Invalid(format!("index {} in a bitmask of {}", index, len)));
+                }
+                mask.set(index as usize, true);
+                next = index + 1;
+            }
+        }
+        2 if len == 0 => {}
+        2 => {
+            let mut bit = reader.read_bool()?;
+            while mask.len() < len {
+                let run = reader.read_varint()?;
+                if run == 0 || run > (len - mask.len()) as u64 {
+                    return Err(WireError::Invalid(format!("run of {} in a bitmask of {}", run, len)));
+                }
+                mask.grow(run as usize, bit);
+                bit = !bit;
+            }
+        }
+        encoding => return Err(WireError::Invalid(format!("unknown bitmask encoding {}", encoding))),
+    }
+    Ok(mask)
+}
+
+impl<T: NetSerialize> NetworkBitmask<T> {
+    /// Reads a bitmask of a packet with `entities` entities, whose mask must be that long.
+    pub fn net_deserialize_for(reader: &mut BitReader, entities: Option<usize>, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        let entities_mask = read_mask(reader, entities)?;
+        let values = (0..entities_mask.iter().filter(|&bit| bit).count())
+            .map(|_| T::net_deserialize(reader, quantize))
+            .collect::<Result<_, _>>()?;
+        Ok(NetworkBitmask { entities_mask, values })
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for NetworkBitmask<T> {
+    fn net_serialize(&self, writer: &mut BitWriter, quantize: Option<Quantize>) {
+        write_mask(writer, &self.entities_mask);
+        for value in &self.values {
+            value.net_serialize(writer, quantize);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader, quantize: Option<Quantize>) -> Result<Self, WireError> {
+        NetworkBitmask::net_deserialize_for(reader, None, quantize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: NetSerialize + PartialEq + fmt::Debug>(value: T, quantize: Option<Quantize>) -> u64 {
+        let mut writer = BitWriter::new();
+        value.net_serialize(&mut writer, quantize);
+        let bits = writer.bits();
+        let bytes = writer.finish();
+        assert_eq!(T::net_deserialize(&mut BitReader::new(&bytes), quantize), Ok(value));
+        bits
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        assert_eq!(round_trip(true, None), 1);
+        assert_eq!(round_trip(-3i8, None), 8);
+        assert_eq!(round_trip(5u32, None), 8);
+        assert_eq!(round_trip(u32::MAX, None), 40);
+        assert_eq!(round_trip(-70i32, None), 16);
+        assert_eq!(round_trip(1.5f32, None), 32);
+        assert_eq!(round_trip(Some([1u8, 2]), None), 17);
+        assert_eq!(round_trip(vec![1u16; 3], None), 56);
+        round_trip("name".to_string(), None);
+    }
+
+    #[test]
+    fn quantized_floats() {
+        let quantize = Quantize {
+            min: -10.0,
+            max: 10.0,
+            precision: 0.5,
+        };
+        assert_eq!(quantize.bits(), 6);
+        assert_eq!(round_trip(2.5f32, Some(quantize)), 6);
+
+        let mut writer = BitWriter::new();
+        2.7f32.net_serialize(&mut writer, Some(quantize));
+        50f32.net_serialize(&mut writer, Some(quantize));
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(f32::net_deserialize(&mut reader, Some(quantize)), Ok(2.5));
+        assert_eq!(f32::net_deserialize(&mut reader, Some(quantize)), Ok(10.0));
+    }
+
+    #[test]
+    fn ids_and_truncated_packets() {
+        let mut writer = BitWriter::new();
+        write_ids(&mut writer, &[100, 101, 103, 2]);
+        let bytes = writer.finish();
+        assert_eq!(bytes.len(), 7);
+        assert_eq!(read_ids(&mut BitReader::new(&bytes)), Ok(vec![100, 101, 103, 2]));
+
+        let bytes = to_bytes(&vec![1u32, 2, 3]);
+        assert_eq!(from_bytes::<Vec<u32>>(&bytes[..2]), Err(WireError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn frames_and_aligned_bytes() {
+        let long = [7u8; 200];
+        let datagram = write_frames(vec![&[1u8, 2][..], &[], &long[..]]);
+        assert_eq!(datagram.len(), 1 + 2 + 1 + 2 + 200);
+        assert_eq!(read_frames(&datagram), Ok(vec![&[1u8, 2][..], &[], &long[..]]));
+        assert_eq!(read_frames(&datagram[..datagram.len() - 1]), Err(WireError::UnexpectedEnd));
+        assert_eq!(frame_prefix_len(2), 1);
+        assert_eq!(frame_prefix_len(long.len()), 2);
+
+        let mut writer = BitWriter::new();
+        writer.write_bool(true);
+        writer.write_bytes(&to_bytes(&300u32));
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bool(), Ok(true));
+        reader.align();
+        assert_eq!(u32::net_deserialize(&mut reader, None), Ok(300));
+        assert!(reader.is_empty());
+    }
+
+    fn mask(bits: &[bool]) -> BitVec<u32> {
+        let mut mask = BitVec::new();
+        bits.iter().for_each(|&bit| mask.push(bit));
+        mask
+    }
+
+    fn mask_round_trip(mask: &BitVec<u32>) -> usize {
+        let mut writer = BitWriter::new();
+        write_mask(&mut writer, mask);
+        let bytes = writer.finish();
+        assert_eq!(read_mask(&mut BitReader::new(&bytes), Some(mask.len())).as_ref(), Ok(mask));
+        bytes.len()
+    }
+
+    #[test]
+    fn smallest_mask_encoding() {
+        let mut sparse = BitVec::from_elem(1000, false);
+        sparse.set(3, true);
+        sparse.set(700, true);
+        assert_eq!(MaskEncoding::smallest(&sparse), MaskEncoding::Indices);
+        assert_eq!(mask_round_trip(&sparse), 7);
+
+        let mut runs = BitVec::from_elem(1000, true);
+        runs.set(500, false);
+        assert_eq!(MaskEncoding::smallest(&runs), MaskEncoding::Runs);
+        assert_eq!(mask_round_trip(&runs), 8);
+
+        let alternating = mask(&[true, false, true, true, false, false, true, false]);
+        assert_eq!(MaskEncoding::smallest(&alternating), MaskEncoding::Dense);
+        assert_eq!(mask_round_trip(&alternating), 3);
+
+        assert_eq!(mask_round_trip(&BitVec::new()), 2);
+    }
+
+    #[test]
+    fn mask_length_checked() {
+        let mut writer = BitWriter::new();
+        write_mask(&mut writer, &mask(&[true, false, true]));
+        let bytes = writer.finish();
+        assert!(read_mask(&mut BitReader::new(&bytes), Some(4)).is_err());
+
+        // A run past the end of the mask
+        let mut writer = BitWriter::new();
+        writer.write_varint(3);
+        writer.write_bits(MaskEncoding::Runs as u64, 2);
+        writer.write_bool(true);
+        writer.write_varint(4);
+        let bytes = writer.finish();
+        assert!(read_mask(&mut BitReader::new(&bytes), Some(3)).is_err());
+
+        // A huge mask in a few bytes, with no length to check against
+        let mut writer = BitWriter::new();
+        writer.write_varint(u32::MAX as u64);
+        writer.write_bits(MaskEncoding::Runs as u64, 2);
+        writer.write_bool(true);
+        writer.write_varint(u32::MAX as u64);
+        let bytes = writer.finish();
+        assert!(read_mask(&mut BitReader::new(&bytes), None).is_err());
+    }
+}